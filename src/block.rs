@@ -0,0 +1,326 @@
+//A block is a sorted list of entries followed by the offsets of its restart points:
+//  entry:   [shared varint][non shared varint][value length varint][key delta][value]
+//  trailer: [restart offset u32]...[number of restarts u32]
+//A key is stored as the length of the prefix it shares with the previous key plus the rest of
+//it. Every RESTART_INTERVAL entries a restart point stores the full key (shared is 0), so a
+//reader can binary search the restart points and start decoding there.
+use crate::data_type::{EntryT, KeyT, ValueT};
+use std::convert::{TryFrom, TryInto};
+
+pub static RESTART_INTERVAL: usize = 16;
+
+pub fn put_varint(dst: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        dst.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    dst.push(value as u8);
+}
+
+/// Decodes a varint at the start of `src`, returning the value and the number of bytes read.
+pub fn get_varint(src: &[u8]) -> Option<(u64, usize)> {
+    let mut value: u64 = 0;
+    for (i, byte) in src.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+pub fn get_u32(src: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(src[offset..offset + 4].try_into().unwrap())
+}
+
+pub struct BlockBuilder {
+    buffer: Vec<u8>,
+    restarts: Vec<u32>,
+    //entries added since the last restart point
+    counter: usize,
+    last_key: KeyT,
+}
+
+impl Default for BlockBuilder {
+    fn default() -> Self {
+        BlockBuilder::new()
+    }
+}
+
+impl BlockBuilder {
+    pub fn new() -> BlockBuilder {
+        BlockBuilder {
+            buffer: Vec::new(),
+            restarts: vec![0],
+            counter: 0,
+            last_key: KeyT::new(),
+        }
+    }
+
+    /// Appends an entry. Keys must be added in increasing order.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        assert!(self.is_empty() || key > self.last_key.as_slice());
+        if self.counter == RESTART_INTERVAL {
+            self.restarts.push(self.buffer.len() as u32);
+            self.counter = 0;
+        }
+        //the first entry of a restart interval stores its full key
+        let shared = if self.counter == 0 {
+            0
+        } else {
+            key.iter()
+                .zip(self.last_key.iter())
+                .take_while(|(a, b)| a == b)
+                .count()
+        };
+        put_varint(&mut self.buffer, shared as u64);
+        put_varint(&mut self.buffer, (key.len() - shared) as u64);
+        put_varint(&mut self.buffer, value.len() as u64);
+        self.buffer.extend_from_slice(&key[shared..]);
+        self.buffer.extend_from_slice(value);
+        self.last_key = key.to_vec();
+        self.counter += 1;
+    }
+
+    /// Size of the block if it was finished now.
+    pub fn size_estimate(&self) -> usize {
+        self.buffer.len() + 4 * (self.restarts.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn last_key(&self) -> &KeyT {
+        &self.last_key
+    }
+
+    /// Returns the encoded block and resets the builder for the next one.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buffer);
+        for restart in self.restarts.iter() {
+            block.extend_from_slice(&restart.to_le_bytes());
+        }
+        block.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        self.restarts = vec![0];
+        self.counter = 0;
+        self.last_key.clear();
+        block
+    }
+}
+
+pub struct Block {
+    data: Vec<u8>,
+    //where the restart array starts, which is also the end of the entries
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    /// Returns None if `data` is not a well-formed block. Every entry is checked here, so
+    /// reading the block later cannot fail.
+    pub fn new(data: Vec<u8>) -> Option<Block> {
+        if data.len() < 4 {
+            return None;
+        }
+        let num_restarts = get_u32(&data, data.len() - 4) as usize;
+        let trailer = num_restarts.checked_add(1)?.checked_mul(4)?;
+        let restarts_offset = data.len().checked_sub(trailer)?;
+        if num_restarts == 0 {
+            return None;
+        }
+        let block = Block {
+            data,
+            restarts_offset,
+            num_restarts,
+        };
+        //the restart points must be entries storing their full key, in order
+        let mut restarts = (0..num_restarts).map(|i| block.restart_point(i)).peekable();
+        let mut key = KeyT::new();
+        let mut offset = 0;
+        while offset < restarts_offset {
+            let (shared, next) = block.check_entry(offset, key.len())?;
+            if restarts.peek() == Some(&offset) {
+                if shared != 0 {
+                    return None;
+                }
+                restarts.next();
+            }
+            block.entry_at(offset, &mut key);
+            offset = next;
+        }
+        if restarts.next().is_some() {
+            return None;
+        }
+        Some(block)
+    }
+
+    //the shared length of the entry at offset and the offset of the next entry, or None if
+    //the entry does not fit in the block or shares more than the previous key of prev_len
+    fn check_entry(&self, offset: usize, prev_len: usize) -> Option<(usize, usize)> {
+        let entries = &self.data[..self.restarts_offset];
+        let (shared, n1) = get_varint(&entries[offset..])?;
+        let (non_shared, n2) = get_varint(&entries[offset + n1..])?;
+        let (value_len, n3) = get_varint(&entries[offset + n1 + n2..])?;
+        let next = (offset + n1 + n2 + n3)
+            .checked_add(usize::try_from(non_shared).ok()?)?
+            .checked_add(usize::try_from(value_len).ok()?)?;
+        if shared > prev_len as u64 || next > entries.len() {
+            return None;
+        }
+        Some((shared as usize, next))
+    }
+
+    /// Size of the decoded block in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn num_restarts(&self) -> usize {
+        self.num_restarts
+    }
+
+    pub fn restart_point(&self, index: usize) -> usize {
+        get_u32(&self.data, self.restarts_offset + 4 * index) as usize
+    }
+
+    //decodes the entry at offset on top of the previous key, which becomes the entry's key.
+    //returns the value and the offset of the next entry. new checked every entry, so
+    //decoding one cannot fail.
+    fn entry_at(&self, offset: usize, key: &mut KeyT) -> (&[u8], usize) {
+        let (shared, n1) = get_varint(&self.data[offset..]).unwrap();
+        let (non_shared, n2) = get_varint(&self.data[offset + n1..]).unwrap();
+        let (value_len, n3) = get_varint(&self.data[offset + n1 + n2..]).unwrap();
+        let delta_start = offset + n1 + n2 + n3;
+        let value_start = delta_start + non_shared as usize;
+        let next = value_start + value_len as usize;
+        key.truncate(shared as usize);
+        key.extend_from_slice(&self.data[delta_start..value_start]);
+        (&self.data[value_start..next], next)
+    }
+
+    pub fn entries(&self) -> Vec<EntryT> {
+        let mut res: Vec<EntryT> = Vec::new();
+        let mut key = KeyT::new();
+        let mut offset = 0;
+        while offset < self.restarts_offset {
+            let (value, next) = self.entry_at(offset, &mut key);
+            res.push(EntryT {
+                key: key.clone(),
+                value: value.to_vec(),
+            });
+            offset = next;
+        }
+        res
+    }
+
+    pub fn get(&self, target: &[u8]) -> Option<ValueT> {
+        //find the last restart point whose key is <= target, its interval is the only one
+        //that may hold target
+        let mut key = KeyT::new();
+        let mut left = 0;
+        let mut right = self.num_restarts;
+        while right - left > 1 {
+            let mid = (left + right) / 2;
+            key.clear();
+            self.entry_at(self.restart_point(mid), &mut key);
+            if key.as_slice() <= target {
+                left = mid;
+            } else {
+                right = mid;
+            }
+        }
+
+        key.clear();
+        let mut offset = self.restart_point(left);
+        let end = if left + 1 < self.num_restarts {
+            self.restart_point(left + 1)
+        } else {
+            self.restarts_offset
+        };
+        while offset < end {
+            let (value, next) = self.entry_at(offset, &mut key);
+            if key.as_slice() == target {
+                return Some(value.to_vec());
+            } else if key.as_slice() > target {
+                //entries are sorted, the key is not in this block
+                break;
+            }
+            offset = next;
+        }
+        None
+    }
+}
+
+#[test]
+fn test_varint() {
+    let mut buf: Vec<u8> = Vec::new();
+    for value in [0u64, 127, 128, 300, u32::MAX as u64, u64::MAX].iter() {
+        buf.clear();
+        put_varint(&mut buf, *value);
+        assert_eq!(Some((*value, buf.len())), get_varint(&buf));
+    }
+    assert_eq!(None, get_varint(&[0x80, 0x80]));
+}
+
+#[test]
+fn test_block() {
+    let mut builder = BlockBuilder::new();
+    for i in 0..100u32 {
+        builder.add(&i.to_be_bytes(), format!("value{}", i).as_bytes());
+    }
+    let block = Block::new(builder.finish()).unwrap();
+    assert!(builder.is_empty());
+    assert_eq!(100 / RESTART_INTERVAL + 1, block.num_restarts());
+    assert_eq!(100, block.entries().len());
+    assert_eq!(Some(b"value42".to_vec()), block.get(&42u32.to_be_bytes()));
+    assert_eq!(None, block.get(&100u32.to_be_bytes()));
+}
+
+#[test]
+fn test_prefix_compression() {
+    let key = |i: u32| format!("user:profile:{:08}", i).into_bytes();
+    let mut builder = BlockBuilder::new();
+    for i in 0..200 {
+        builder.add(&key(i * 2), b"v");
+    }
+    let data = builder.finish();
+    //keys are 21 bytes, but after a restart point only the differing suffix is stored
+    assert!(data.len() < 200 * 21);
+    let block = Block::new(data).unwrap();
+    let entries = block.entries();
+    for i in 0..200 {
+        assert_eq!(key(i * 2), entries[i as usize].key);
+        assert_eq!(Some(b"v".to_vec()), block.get(&key(i * 2)));
+        assert_eq!(None, block.get(&key(i * 2 + 1)));
+    }
+    assert_eq!(None, block.get(b"a"));
+    assert_eq!(None, block.get(b"z"));
+}
+
+#[test]
+fn test_malformed_block() {
+    let mut builder = BlockBuilder::new();
+    for i in 0..40u32 {
+        builder.add(&i.to_be_bytes(), b"value");
+    }
+    let data = builder.finish();
+    assert!(Block::new(data.clone()).is_some());
+    assert!(Block::new(vec![0; 3]).is_none());
+    //more restart points than the block can hold
+    let mut restarts = data.clone();
+    let len = restarts.len();
+    restarts[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(Block::new(restarts).is_none());
+    //a value running past the entries
+    let mut single = BlockBuilder::new();
+    single.add(b"k", b"v");
+    let mut value_len = single.finish();
+    value_len[2] = 0x7f;
+    assert!(Block::new(value_len).is_none());
+    //a restart point inside an entry
+    let mut restart = data;
+    let offset = restart.len() - 8;
+    restart[offset..offset + 4].copy_from_slice(&3u32.to_le_bytes());
+    assert!(Block::new(restart).is_none());
+}
@@ -1,16 +1,33 @@
+//Bloom filter of the runs. Keys are hashed to 64 bits with FNV-1a, and the bits of a key are
+//picked by double hashing: bit i is hash1(h) + i * hash3(h) modulo the size of the table.
+//A blocked filter first picks one 64-byte cache line with hash2(h) and sets all bits of the
+//key within it, so a probe costs one cache miss instead of one per hash, for a slightly
+//higher false positive rate at the same size.
 //extern crate rand;
 use crate::data_type;
-use bit_vec::BitVec;
 //use rand::prelude::*;
-use rand::thread_rng;
-use rand::Rng;
 //use std::ptr::hash;
-use bloomfilter::Bloom;
+use std::convert::TryInto;
 
 pub type IndexT = u64;
 
+//encoded filter: [number of hashes u64][number of bits u64][bits set u64][blocked u64]
+//followed by the cache lines, every word little endian
+static HEADER_SIZE: usize = 32;
+static LINE_BITS: IndexT = 512;
+
+//the bit table is stored in cache lines, aligned so a line never straddles two
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(align(64))]
+struct CacheLine([u64; 8]);
+
+fn lines_for(size: IndexT) -> usize {
+    size.div_ceil(LINE_BITS) as usize
+}
+
 #[test]
 fn test_Bloom() {
+    use bloomfilter::Bloom;
     let mut bloom: Bloom<Vec<u8>> = Bloom::new(100000, 1000);
     bloom.set(&vec![1, 2, 3, 4]);
     bloom.set(&"asd".as_bytes().to_vec());
@@ -24,17 +41,13 @@ pub struct BloomFilter {
     hashes: IndexT,
     size: IndexT,
     count: IndexT,
-    table: bit_vec::BitVec,
+    blocked: bool,
+    table: Vec<CacheLine>,
 }
 
 impl BloomFilter {
     pub fn new_init() -> BloomFilter {
-        BloomFilter {
-            hashes: data_type::HASHES,
-            size: data_type::BLOOM_SIZE,
-            count: 0,
-            table: BitVec::from_elem(data_type::BLOOM_SIZE as usize, false),
-        }
+        BloomFilter::new(data_type::HASHES, data_type::BLOOM_SIZE, 0)
     }
 
     pub fn new(hashes: IndexT, size: IndexT, count: IndexT) -> BloomFilter {
@@ -42,29 +55,26 @@ impl BloomFilter {
             hashes: hashes,
             size: size,
             count: count,
-            table: BitVec::from_elem(size as usize, false),
+            blocked: false,
+            table: vec![CacheLine::default(); lines_for(size)],
         }
     }
 
     pub fn new_with_size(size: IndexT) -> BloomFilter {
-        BloomFilter {
-            hashes: data_type::HASHES,
-            size: size,
-            count: 0,
-            table: BitVec::from_elem(size as usize, false),
-        }
+        BloomFilter::new(data_type::HASHES, size, 0)
     }
 
     pub fn set_bit(&mut self, i: IndexT) {
         assert!(i < self.size);
 
-        self.table.set(i as usize, true);
+        self.table[(i / LINE_BITS) as usize].0[(i % LINE_BITS / 64) as usize] |= 1 << (i % 64);
     }
 
     pub fn get_bit(&self, i: IndexT) -> Option<bool> {
         assert!(i < self.size);
 
-        self.table.get(i as usize)
+        let word = self.table[(i / LINE_BITS) as usize].0[(i % LINE_BITS / 64) as usize];
+        Some(word & (1 << (i % 64)) != 0)
     }
 
     pub fn hash1(&self, k: IndexT) -> IndexT {
@@ -81,11 +91,11 @@ impl BloomFilter {
 
     pub fn hash2(&self, k: IndexT) -> IndexT {
         let mut key = k;
-        key = (key + 0x7ed55d16) + (key << 12);
+        key = key.wrapping_add(0x7ed55d16).wrapping_add(key << 12);
         key = (key ^ 0xc761c23c) ^ (key >> 19);
-        key = (key + 0x165667b1) + (key << 5);
-        key = (key + 0xd3a2646c) ^ (key << 9);
-        key = (key + 0xfd7046c5) + (key << 3);
+        key = key.wrapping_add(0x165667b1).wrapping_add(key << 5);
+        key = key.wrapping_add(0xd3a2646c) ^ (key << 9);
+        key = key.wrapping_add(0xfd7046c5).wrapping_add(key << 3);
         key = (key ^ 0xb55a4f09) ^ (key >> 16);
         key
     }
@@ -93,16 +103,30 @@ impl BloomFilter {
     pub fn hash3(&self, k: IndexT) -> IndexT {
         let mut key = k;
         key = (key ^ 61) ^ (key >> 16);
-        key = key + (key << 3);
+        key = key.wrapping_add(key << 3);
         key = key ^ (key >> 4);
-        key = key * 0x27d4eb2d;
+        key = key.wrapping_mul(0x27d4eb2d);
         key = key ^ (key >> 15);
         key
     }
 
+    //the bits of k are start + (hash1 + i * hash3) % span
+    fn probe_range(&self, k: IndexT) -> (IndexT, IndexT) {
+        if self.blocked {
+            (
+                self.hash2(k) % self.table.len() as IndexT * LINE_BITS,
+                LINE_BITS,
+            )
+        } else {
+            (0, self.size)
+        }
+    }
+
     pub fn bloom_check(&self, k: IndexT) -> bool {
+        let (start, span) = self.probe_range(k);
+        let (h1, h3) = (self.hash1(k), self.hash3(k));
         for i in 0..self.hashes {
-            let hash = (self.hash1(k).wrapping_add(i.wrapping_mul(self.hash3(k)))) % self.size;
+            let hash = start + (h1.wrapping_add(i.wrapping_mul(h3))) % span;
             if self.get_bit(hash) == Some(false) {
                 return false;
             }
@@ -111,15 +135,174 @@ impl BloomFilter {
     }
 
     pub fn bloom_add(&mut self, k: IndexT) {
+        let (start, span) = self.probe_range(k);
+        let (h1, h3) = (self.hash1(k), self.hash3(k));
         for i in 0..self.hashes {
-            let hash: IndexT =
-                (self.hash1(k).wrapping_add(i.wrapping_mul(self.hash3(k)))) % self.size;
+            let hash: IndexT = start + (h1.wrapping_add(i.wrapping_mul(h3))) % span;
             if self.get_bit(hash) == Some(false) {
                 self.set_bit(hash);
-                self.count = self.count + 1;
+                self.count += 1;
+            }
+        }
+    }
+
+    /// Returns a filter for `expected_entries` keys with `bits_per_entry` bits per key and
+    /// the number of hashes that gives the lowest false positive rate for it, which is
+    /// `bits_per_entry * ln 2`.
+    pub fn with_bits_per_entry(expected_entries: u64, bits_per_entry: f32) -> BloomFilter {
+        let size = ((expected_entries as f64 * bits_per_entry as f64).ceil() as IndexT).max(1);
+        let hashes = (bits_per_entry as f64 * std::f64::consts::LN_2).round() as IndexT;
+        BloomFilter::new(hashes.clamp(1, 30), size, 0)
+    }
+
+    /// Like `with_bits_per_entry`, but all bits of a key are in one cache line. The size is
+    /// rounded up to whole cache lines.
+    pub fn blocked(expected_entries: u64, bits_per_entry: f32) -> BloomFilter {
+        let sized = BloomFilter::with_bits_per_entry(expected_entries, bits_per_entry);
+        let mut res =
+            BloomFilter::new(sized.hashes, lines_for(sized.size) as IndexT * LINE_BITS, 0);
+        res.blocked = true;
+        res
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked
+    }
+
+    /// 64-bit FNV-1a hash of a key, the input of the bit positions.
+    pub fn hash_key(key: &[u8]) -> IndexT {
+        let mut hash: IndexT = 0xcbf2_9ce4_8422_2325;
+        for byte in key {
+            hash ^= *byte as IndexT;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash
+    }
+
+    pub fn set(&mut self, key: &[u8]) {
+        self.bloom_add(BloomFilter::hash_key(key));
+    }
+
+    /// Returns false if `key` was never set, and true if it may have been.
+    pub fn check(&self, key: &[u8]) -> bool {
+        self.bloom_check(BloomFilter::hash_key(key))
+    }
+
+    pub fn num_bits(&self) -> IndexT {
+        self.size
+    }
+
+    pub fn num_hashes(&self) -> IndexT {
+        self.hashes
+    }
+
+    /// False positive rate expected from the fraction of bits set.
+    pub fn estimated_false_positive_rate(&self) -> f64 {
+        (self.count as f64 / self.size as f64).powi(self.hashes as i32)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::with_capacity(HEADER_SIZE + self.table.len() * 64);
+        res.extend_from_slice(&self.hashes.to_le_bytes());
+        res.extend_from_slice(&self.size.to_le_bytes());
+        res.extend_from_slice(&self.count.to_le_bytes());
+        res.extend_from_slice(&(self.blocked as u64).to_le_bytes());
+        for line in self.table.iter() {
+            for word in line.0.iter() {
+                res.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        res
+    }
+
+    /// Restores a filter written by `encode`, returns None if `src` is not one.
+    pub fn decode(src: &[u8]) -> Option<BloomFilter> {
+        if src.len() < HEADER_SIZE {
+            return None;
+        }
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(src[offset..offset + 8].try_into().unwrap());
+        let (hashes, size, count, blocked) = (u64_at(0), u64_at(8), u64_at(16), u64_at(24));
+        if hashes == 0
+            || size == 0
+            || blocked > 1
+            || src.len() - HEADER_SIZE != lines_for(size) * 64
+        {
+            return None;
+        }
+        let table = src[HEADER_SIZE..]
+            .chunks(64)
+            .map(|line| {
+                let mut words = [0u64; 8];
+                for (i, word) in words.iter_mut().enumerate() {
+                    *word = u64::from_le_bytes(line[i * 8..i * 8 + 8].try_into().unwrap());
+                }
+                CacheLine(words)
+            })
+            .collect();
+        Some(BloomFilter {
+            hashes,
+            size,
+            count,
+            blocked: blocked == 1,
+            table,
+        })
+    }
+}
+
+/// Monkey: splits `total_bits` of filter memory over levels holding `entries` keys each so
+/// that the sum of their false positive rates, the expected number of wasted reads of a
+/// lookup, is minimal. At the optimum the rate of every level is proportional to its number
+/// of entries, so larger, deeper levels get fewer bits per entry. Levels whose rate would
+/// reach 1 get no bits. Returns the bits per entry of every level.
+pub fn monkey_bits_per_entry(entries: &[u64], total_bits: u64) -> Vec<f32> {
+    let ln2_squared = std::f64::consts::LN_2 * std::f64::consts::LN_2;
+    let mut res: Vec<f32> = vec![0.0; entries.len()];
+    let mut levels: Vec<usize> = (0..entries.len()).filter(|i| entries[*i] > 0).collect();
+    while !levels.is_empty() {
+        //with rate_i = lambda * entries_i and bits_i = -ln(rate_i) / ln(2)^2, the bits of all
+        //levels add up to total_bits for this lambda
+        let n: f64 = levels.iter().map(|i| entries[*i] as f64).sum();
+        let n_ln_n: f64 = levels
+            .iter()
+            .map(|i| entries[*i] as f64 * (entries[*i] as f64).ln())
+            .sum();
+        let ln_lambda = -(total_bits as f64 * ln2_squared + n_ln_n) / n;
+        let ln_rate = |i: usize| ln_lambda + (entries[i] as f64).ln();
+        let (kept, dropped): (Vec<usize>, Vec<usize>) =
+            levels.iter().partition(|i| ln_rate(**i) < 0.0);
+        if dropped.is_empty() {
+            for i in kept {
+                res[i] = (-ln_rate(i) / ln2_squared) as f32;
             }
+            break;
         }
+        //the levels without a filter leave their share to the others
+        levels = kept;
     }
+    res
+}
+
+#[test]
+fn test_monkey() {
+    let entries = [0, 1000, 10000, 100000];
+    let total_bits = 5 * 111000;
+    let bits = monkey_bits_per_entry(&entries, total_bits);
+    assert_eq!(0.0, bits[0]);
+    assert!(bits[1] > bits[2] && bits[2] > bits[3] && bits[3] > 0.0);
+    let used: f64 = (1..4).map(|i| entries[i] as f64 * bits[i] as f64).sum();
+    assert!((used - total_bits as f64).abs() < 1.0);
+    //fewer wasted reads than the same memory spread evenly
+    let rate = |bits: f32| (-(bits as f64) * std::f64::consts::LN_2.powi(2)).exp();
+    let monkey: f64 = (1..4).map(|i| rate(bits[i])).sum();
+    assert!(monkey < 3.0 * rate(5.0));
+
+    //a small budget goes to the small levels only
+    let bits = monkey_bits_per_entry(&entries, 20000);
+    assert!(bits[1] > 0.0);
+    assert_eq!(0.0, bits[3]);
+    let used: f64 = (1..4).map(|i| entries[i] as f64 * bits[i] as f64).sum();
+    assert!((used - 20000.0).abs() < 1.0);
 }
 
 #[test]
@@ -165,8 +348,139 @@ fn test_bloom_occupancy() {
     }
 }
 
+#[test]
+fn test_bloom_keys() {
+    let key = |i: u32| format!("key{:08}", i).into_bytes();
+    let mut b = BloomFilter::with_bits_per_entry(10000, 10.0);
+    assert_eq!(7, b.num_hashes());
+    assert_eq!(100000, b.num_bits());
+    for i in 0..10000 {
+        b.set(&key(i));
+    }
+    assert!((0..10000).all(|i| b.check(&key(i))));
+    //10 bits per key give about 1% false positives
+    let false_positives = (10000..110000).filter(|i| b.check(&key(*i))).count();
+    assert!(
+        false_positives < 2000,
+        "{} false positives",
+        false_positives
+    );
+    assert!(b.estimated_false_positive_rate() < 0.02);
+
+    let decoded = BloomFilter::decode(&b.encode()).unwrap();
+    assert_eq!(b.num_bits(), decoded.num_bits());
+    assert!((0..10000).all(|i| decoded.check(&key(i))));
+    assert_eq!(
+        false_positives,
+        (10000..110000).filter(|i| decoded.check(&key(*i))).count()
+    );
+    assert!(BloomFilter::decode(&b.encode()[..100]).is_none());
+}
+
+#[test]
+fn test_blocked_bloom() {
+    let key = |i: u32| format!("key{:08}", i).into_bytes();
+    let mut b = BloomFilter::blocked(10000, 10.0);
+    assert!(b.is_blocked());
+    assert_eq!(0, b.num_bits() % 512);
+    for i in 0..10000 {
+        b.set(&key(i));
+    }
+    assert!((0..10000).all(|i| b.check(&key(i))));
+    let false_positives = (10000..110000).filter(|i| b.check(&key(*i))).count();
+    assert!(
+        false_positives < 3000,
+        "{} false positives",
+        false_positives
+    );
+
+    let decoded = BloomFilter::decode(&b.encode()).unwrap();
+    assert!(decoded.is_blocked());
+    assert_eq!(
+        false_positives,
+        (10000..110000).filter(|i| decoded.check(&key(*i))).count()
+    );
+}
+
+#[test]
+fn test_blocked_false_positive_rate() {
+    let num_keys = 100000;
+    let key = |i: u32| format!("key{:08}", i).into_bytes();
+    let mut standard = BloomFilter::with_bits_per_entry(num_keys as u64, 10.0);
+    let mut blocked = BloomFilter::blocked(num_keys as u64, 10.0);
+    for i in 0..num_keys {
+        standard.set(&key(i));
+        blocked.set(&key(i));
+    }
+    let rate = |filter: &BloomFilter| {
+        let hits = (num_keys..2 * num_keys)
+            .filter(|i| filter.check(&key(*i)))
+            .count();
+        hits as f64 / num_keys as f64
+    };
+    //blocking costs a little accuracy, not more than twice the misses
+    let (standard_rate, blocked_rate) = (rate(&standard), rate(&blocked));
+    assert!(standard_rate < 0.02 && blocked_rate < 2.0 * standard_rate + 0.005);
+}
+
+//run with cargo test --release -- --ignored bench_bloom_filters
+#[test]
+#[ignore]
+fn bench_bloom_filters() {
+    use bloomfilter::Bloom;
+    use std::time::{Duration, Instant};
+    //filters of 5 MB, larger than the caches of the core
+    let num_keys = 4_000_000;
+    let bits_per_entry = 10.0;
+    let key = |i: u32| format!("key{:08}", i).into_bytes();
+    let present: Vec<Vec<u8>> = (0..num_keys).map(key).collect();
+    let absent: Vec<Vec<u8>> = (num_keys..2 * num_keys).map(key).collect();
+
+    let mut standard = BloomFilter::with_bits_per_entry(num_keys as u64, bits_per_entry);
+    let mut blocked = BloomFilter::blocked(num_keys as u64, bits_per_entry);
+    //sized in bytes like Run used to
+    let mut crate_bloom: Bloom<Vec<u8>> = Bloom::new(
+        (num_keys as f32 * bits_per_entry / 8.0) as usize,
+        num_keys as usize,
+    );
+    for k in present.iter() {
+        standard.set(k);
+        blocked.set(k);
+        crate_bloom.set(k);
+    }
+
+    let mut durations: Vec<Duration> = Vec::new();
+    let probes: [(&str, &dyn Fn(&Vec<u8>) -> bool); 3] = [
+        ("bloom_filter::BloomFilter", &|k| standard.check(k)),
+        ("bloom_filter::BloomFilter blocked", &|k| blocked.check(k)),
+        ("bloomfilter::Bloom", &|k| crate_bloom.check(k)),
+    ];
+    for (name, check) in probes.iter() {
+        let start = Instant::now();
+        let hits = present
+            .iter()
+            .chain(absent.iter())
+            .filter(|k| check(k))
+            .count();
+        let duration = start.elapsed();
+        let rate = (hits - present.len()) as f64 / absent.len() as f64;
+        println!(
+            "{} {} probes time cost is {:?}, false positive rate is {:.4}",
+            name,
+            2 * num_keys,
+            duration,
+            rate
+        );
+        durations.push(duration);
+    }
+    //a blocked probe touches one cache line instead of one per hash
+    assert!(durations[1] < durations[0], "{:?}", durations);
+}
+
 #[test]
 fn test_bloom_false_positive() {
+    use rand::thread_rng;
+    use rand::Rng;
     let size_bits: IndexT = 1000000;
     let mut b: BloomFilter = BloomFilter::new(7, size_bits, 1);
     let rand_max: IndexT = 100000000;
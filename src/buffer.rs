@@ -1,10 +1,14 @@
 use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::memtable::{entry_charge, MemTable};
 use std::collections::BTreeSet;
 use std::ops::Bound::Included;
 
 pub struct Buffer {
+    //number of entries full checks against
     pub max_size: usize,
     pub entries: BTreeSet<EntryT>,
+    //approximate memory of the entries in bytes
+    size: usize,
 }
 
 impl Buffer {
@@ -12,10 +16,17 @@ impl Buffer {
         Buffer {
             max_size: size,
             entries: BTreeSet::new(),
+            size: 0,
         }
     }
 
-    pub fn get(&self, key: &KeyT) -> Option<ValueT> {
+    pub fn full(&self) -> bool {
+        self.entries.len() == self.max_size
+    }
+}
+
+impl MemTable for Buffer {
+    fn get(&self, key: &KeyT) -> Option<ValueT> {
         let search_entry = EntryT {
             key: key.clone(),
             value: ValueT::default(),
@@ -27,7 +38,39 @@ impl Buffer {
         }
     }
 
-    pub fn range(&self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
+    /// Looks up sorted `keys` in one pass over the entries between the first and the last key.
+    fn multi_get(&self, keys: &[KeyT]) -> Vec<Option<ValueT>> {
+        let mut res: Vec<Option<ValueT>> = vec![None; keys.len()];
+        if keys.is_empty() {
+            return res;
+        }
+        let lower_bound = EntryT {
+            key: keys[0].clone(),
+            value: ValueT::default(),
+        };
+        let upper_bound = EntryT {
+            key: keys[keys.len() - 1].clone(),
+            value: ValueT::default(),
+        };
+        let mut i = 0;
+        for elem in self
+            .entries
+            .range((Included(lower_bound), Included(upper_bound)))
+        {
+            while i < keys.len() && keys[i] < elem.key {
+                i += 1;
+            }
+            if i == keys.len() {
+                break;
+            }
+            if keys[i] == elem.key {
+                res[i] = Some(elem.value.clone());
+            }
+        }
+        res
+    }
+
+    fn range(&self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
         let lower_bound = EntryT {
             key: start.clone(),
             value: ValueT::default(),
@@ -46,24 +89,32 @@ impl Buffer {
         res
     }
 
-    pub fn put(&mut self, key: KeyT, value: ValueT) {
+    fn put(&mut self, key: KeyT, value: ValueT) {
         let entry = EntryT {
             key: key,
             value: value,
         };
-        self.entries.replace(entry);
+        self.size += entry_charge(&entry.key, &entry.value);
+        if let Some(old) = self.entries.replace(entry) {
+            self.size -= entry_charge(&old.key, &old.value);
+        }
     }
 
-    pub fn empty(&mut self) {
-        self.entries.clear();
+    fn iter(&self) -> Box<dyn Iterator<Item = EntryT> + '_> {
+        Box::new(self.entries.iter().cloned())
     }
 
-    pub fn full(&self) -> bool {
-        if self.entries.len() == self.max_size {
-            true
-        } else {
-            false
-        }
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.size
+    }
+
+    fn empty(&mut self) {
+        self.entries.clear();
+        self.size = 0;
     }
 }
 
@@ -78,6 +129,17 @@ fn test_size() {
     assert_eq!(2, buf.entries.len());
     assert_eq!(10, buf.max_size);
     assert!(false == buf.full());
+    assert_eq!(
+        entry_charge(b"helloworld", b"worldhello") + entry_charge(b"hello", b"world"),
+        buf.approximate_size()
+    );
+    buf.put("hello".as_bytes().to_vec(), "w".as_bytes().to_vec());
+    assert_eq!(
+        entry_charge(b"helloworld", b"worldhello") + entry_charge(b"hello", b"w"),
+        buf.approximate_size()
+    );
+    buf.empty();
+    assert_eq!(0, buf.approximate_size());
 }
 
 #[test]
@@ -91,6 +153,20 @@ fn test_put_get() {
     }
 }
 
+#[test]
+fn test_multi_get() {
+    let mut buf = Buffer::new(10);
+    for i in 0..10u8 {
+        buf.put(vec![i * 2], vec![i]);
+    }
+    let keys: Vec<Vec<u8>> = vec![vec![0], vec![3], vec![4], vec![18], vec![30]];
+    assert_eq!(
+        vec![Some(vec![0]), None, Some(vec![2]), Some(vec![9]), None],
+        buf.multi_get(&keys)
+    );
+    assert!(buf.multi_get(&[]).is_empty());
+}
+
 #[test]
 fn test_range() {
     let mut buf = Buffer::new(10);
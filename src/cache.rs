@@ -0,0 +1,309 @@
+//Caches shared by all runs of one or more trees.
+//BlockCache keeps blocks decoded (checksum verified and decompressed) and evicts them least
+//recently used first once the total size of the cached blocks exceeds the capacity.
+//TableCache keeps every run file open, mapped or as a file descriptor, and closes the least
+//recently used ones once more than max_open_files are open.
+use crate::block::Block;
+use crate::file_io::{IoMode, RunFile};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+pub static DEFAULT_NUM_SHARDS: usize = 16;
+pub static DEFAULT_MAX_OPEN_FILES: usize = 1000;
+
+/// Identifies a block by the cache id of its run and its offset in the run file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub run: u64,
+    pub offset: u64,
+}
+
+struct CacheEntry {
+    block: Arc<Block>,
+    charge: usize,
+    //position in the lru order
+    tick: u64,
+}
+
+#[derive(Default)]
+struct LruShard {
+    entries: HashMap<CacheKey, CacheEntry>,
+    //least recently used first
+    lru: BTreeMap<u64, CacheKey>,
+    next_tick: u64,
+    usage: usize,
+}
+
+impl LruShard {
+    fn touch(&mut self, key: &CacheKey) -> Option<Arc<Block>> {
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.tick);
+        entry.tick = self.next_tick;
+        self.next_tick += 1;
+        self.lru.insert(entry.tick, *key);
+        Some(entry.block.clone())
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.usage -= entry.charge;
+        }
+    }
+
+    fn insert(&mut self, key: CacheKey, block: Arc<Block>, capacity: usize) {
+        self.remove(&key);
+        let charge = block.size();
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                block,
+                charge,
+                tick,
+            },
+        );
+        self.lru.insert(tick, key);
+        self.usage += charge;
+        while self.usage > capacity {
+            //the new block itself goes if it alone is larger than the shard
+            let oldest = match self.lru.iter().next() {
+                Some((_, key)) => *key,
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+pub struct BlockCache {
+    shards: Vec<Mutex<LruShard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// Creates a cache holding up to `capacity` bytes of blocks, split evenly over
+    /// `num_shards` independently locked shards.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::cache::BlockCache;
+    /// use lsm_kv::lsm::LSMTree;
+    /// use std::sync::Arc;
+    /// let cache = Arc::new(BlockCache::new(64 * 1024 * 1024, 16));
+    /// let mut lsm = LSMTree::new(100, 5, 10, 0.5, 4, "cache_doc".to_string());
+    /// lsm.set_block_cache(cache.clone());
+    /// lsm.put("hello", "world");
+    /// assert_eq!(0, cache.misses());
+    /// ```
+    pub fn new(capacity: usize, num_shards: usize) -> BlockCache {
+        assert!(num_shards > 0);
+        BlockCache {
+            shards: (0..num_shards)
+                .map(|_| Mutex::new(LruShard::default()))
+                .collect(),
+            shard_capacity: capacity / num_shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &CacheKey) -> &Mutex<LruShard> {
+        let hash = (key.run.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ key.offset) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Arc<Block>> {
+        let res = self.shard(key).lock().unwrap().touch(key);
+        match res {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        res
+    }
+
+    pub fn insert(&self, key: CacheKey, block: Arc<Block>) {
+        self.shard(&key)
+            .lock()
+            .unwrap()
+            .insert(key, block, self.shard_capacity);
+    }
+
+    /// Drops every block of a run, e.g. after its file was removed by a compaction.
+    pub fn erase_run(&self, run: u64) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<CacheKey> = shard
+                .entries
+                .keys()
+                .filter(|key| key.run == run)
+                .cloned()
+                .collect();
+            for key in keys.iter() {
+                shard.remove(key);
+            }
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Total size of the cached blocks in bytes.
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().usage).sum()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shard_capacity * self.shards.len()
+    }
+}
+
+#[derive(Default)]
+struct OpenFiles {
+    //file of every open run and its position in the lru order
+    files: HashMap<u64, (Arc<RunFile>, u64)>,
+    lru: BTreeMap<u64, u64>,
+    next_tick: u64,
+}
+
+pub struct TableCache {
+    max_open_files: usize,
+    open_files: Mutex<OpenFiles>,
+    opens: AtomicU64,
+}
+
+impl TableCache {
+    pub fn new(max_open_files: usize) -> TableCache {
+        assert!(max_open_files > 0);
+        TableCache {
+            max_open_files,
+            open_files: Mutex::new(OpenFiles::default()),
+            opens: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the file of run `id`, opening `path` for `mode` if it is not open.
+    pub fn get_or_open(&self, id: u64, path: &Path, mode: IoMode) -> io::Result<Arc<RunFile>> {
+        let mut guard = self.open_files.lock().unwrap();
+        let open_files = &mut *guard;
+        let tick = open_files.next_tick;
+        open_files.next_tick += 1;
+        if let Some((file, old_tick)) = open_files.files.get_mut(&id) {
+            let file = file.clone();
+            let old_tick = std::mem::replace(old_tick, tick);
+            open_files.lru.remove(&old_tick);
+            open_files.lru.insert(tick, id);
+            return Ok(file);
+        }
+
+        let file = Arc::new(RunFile::open(path, mode)?);
+        self.opens.fetch_add(1, Ordering::Relaxed);
+        open_files.files.insert(id, (file.clone(), tick));
+        open_files.lru.insert(tick, id);
+        while open_files.files.len() > self.max_open_files {
+            let oldest = match open_files.lru.iter().next() {
+                Some((tick, id)) => (*tick, *id),
+                None => break,
+            };
+            open_files.lru.remove(&oldest.0);
+            open_files.files.remove(&oldest.1);
+        }
+        Ok(file)
+    }
+
+    /// Closes the file of run `id`, e.g. because the run was removed or its file moved.
+    pub fn evict(&self, id: u64) {
+        let mut open_files = self.open_files.lock().unwrap();
+        if let Some((_, tick)) = open_files.files.remove(&id) {
+            open_files.lru.remove(&tick);
+        }
+    }
+
+    pub fn num_open_files(&self) -> usize {
+        self.open_files.lock().unwrap().files.len()
+    }
+
+    /// Number of times a file was opened.
+    pub fn opens(&self) -> u64 {
+        self.opens.load(Ordering::Relaxed)
+    }
+}
+
+/// The table cache used by runs of trees without a table cache of their own.
+pub fn default_table_cache() -> Arc<TableCache> {
+    static DEFAULT: OnceLock<Arc<TableCache>> = OnceLock::new();
+    DEFAULT
+        .get_or_init(|| Arc::new(TableCache::new(DEFAULT_MAX_OPEN_FILES)))
+        .clone()
+}
+
+#[test]
+fn test_block_cache() {
+    use crate::block::BlockBuilder;
+    let block = |i: u32| {
+        let mut builder = BlockBuilder::new();
+        builder.add(&i.to_be_bytes(), &[0; 92]);
+        Arc::new(Block::new(builder.finish()).unwrap())
+    };
+    let size = block(0).size();
+    //a single shard makes the eviction order predictable
+    let cache = BlockCache::new(3 * size, 1);
+    let key = |offset: u64| CacheKey { run: 7, offset };
+    for i in 0..3 {
+        cache.insert(key(i), block(i as u32));
+    }
+    assert_eq!(3 * size, cache.usage());
+    assert!(cache.get(&key(0)).is_some());
+    //key 1 is now the least recently used
+    cache.insert(key(3), block(3));
+    assert!(cache.get(&key(1)).is_none());
+    assert!(cache.get(&key(0)).is_some());
+    assert!(cache.get(&key(3)).is_some());
+    assert_eq!(3, cache.hits());
+    assert_eq!(1, cache.misses());
+    assert!(cache.usage() <= cache.capacity());
+
+    cache.erase_run(7);
+    assert_eq!(0, cache.usage());
+    assert!(cache.get(&key(0)).is_none());
+}
+
+#[test]
+fn test_table_cache() {
+    use std::fs;
+    let _ = fs::create_dir_all("/tmp/table_cache_test");
+    let path = |i: u64| Path::new("/tmp/table_cache_test").join(format!("file-{}", i));
+    for i in 0..3 {
+        fs::write(path(i), vec![i as u8; 10]).unwrap();
+    }
+    let cache = TableCache::new(2);
+    let file = cache.get_or_open(0, &path(0), IoMode::Pread).unwrap();
+    assert_eq!(vec![0; 10], file.read(10, 0).unwrap());
+    cache.get_or_open(1, &path(1), IoMode::Mmap).unwrap();
+    //file 0 stays open, it is used again before file 2 is opened
+    cache.get_or_open(0, &path(0), IoMode::Mmap).unwrap();
+    cache.get_or_open(2, &path(2), IoMode::Mmap).unwrap();
+    assert_eq!(2, cache.num_open_files());
+    assert_eq!(3, cache.opens());
+    cache.get_or_open(0, &path(0), IoMode::Mmap).unwrap();
+    assert_eq!(3, cache.opens());
+    cache.get_or_open(1, &path(1), IoMode::Mmap).unwrap();
+    assert_eq!(4, cache.opens());
+
+    cache.evict(1);
+    assert_eq!(1, cache.num_open_files());
+    assert!(cache.get_or_open(3, &path(3), IoMode::Mmap).is_err());
+    fs::remove_dir_all("/tmp/table_cache_test").unwrap();
+}
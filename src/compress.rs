@@ -0,0 +1,235 @@
+//Block compression codecs. The id of the codec a block was written with is stored in the
+//block trailer, so runs written with different codecs can be read by the same tree.
+use crate::block::{get_varint, put_varint};
+use std::sync::Arc;
+
+pub static NO_COMPRESSION: u8 = 0;
+pub static LZ_COMPRESSION: u8 = 1;
+//largest block the codecs decompress, 256 data blocks of table::BLOCK_SIZE, so a damaged
+//length cannot ask for more memory. Larger blocks are written uncompressed.
+pub static MAX_BLOCK_SIZE: usize = 1 << 20;
+
+pub trait Compressor: Send + Sync {
+    /// Stored in the trailer of every block compressed by this codec.
+    fn id(&self) -> u8;
+    fn name(&self) -> &str;
+    fn compress(&self, input: &[u8]) -> Vec<u8>;
+    /// Returns None if `input` was not produced by `compress`.
+    fn decompress(&self, input: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Returns the built-in codec with the given id.
+pub fn by_id(id: u8) -> Option<Arc<dyn Compressor>> {
+    if id == NO_COMPRESSION {
+        Some(Arc::new(NoCompression))
+    } else if id == LZ_COMPRESSION {
+        Some(Arc::new(Lz::fast()))
+    } else {
+        None
+    }
+}
+
+pub struct NoCompression;
+
+impl Compressor for NoCompression {
+    fn id(&self) -> u8 {
+        NO_COMPRESSION
+    }
+
+    fn name(&self) -> &str {
+        "none"
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        input.to_vec()
+    }
+
+    fn decompress(&self, input: &[u8]) -> Option<Vec<u8>> {
+        Some(input.to_vec())
+    }
+}
+
+//shortest match worth encoding
+static MIN_MATCH: usize = 4;
+static HASH_BITS: u32 = 14;
+
+/// LZ77 codec. A compressed block is the uncompressed length followed by sequences of
+/// [literal length][literals][match length][match offset], all lengths as varints. The last
+/// sequence ends after its literals.
+///
+/// Both levels write the same format: `fast` only tries the latest position with the same
+/// hash, `high` walks a chain of up to `max_chain` earlier positions for the longest match.
+pub struct Lz {
+    max_chain: usize,
+}
+
+impl Lz {
+    pub fn fast() -> Lz {
+        Lz { max_chain: 1 }
+    }
+
+    pub fn high() -> Lz {
+        Lz { max_chain: 64 }
+    }
+}
+
+fn hash4(input: &[u8], pos: usize) -> usize {
+    let v = u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]]);
+    (v.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+fn match_len(input: &[u8], candidate: usize, pos: usize) -> usize {
+    input[pos..]
+        .iter()
+        .zip(input[candidate..].iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+impl Compressor for Lz {
+    fn id(&self) -> u8 {
+        LZ_COMPRESSION
+    }
+
+    fn name(&self) -> &str {
+        if self.max_chain > 1 {
+            "lz-high"
+        } else {
+            "lz"
+        }
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::with_capacity(input.len() / 2 + 16);
+        put_varint(&mut res, input.len() as u64);
+        //latest position of every hash, and the previous position with the same hash
+        let mut head: Vec<usize> = vec![usize::MAX; 1 << HASH_BITS];
+        let mut prev: Vec<usize> = vec![usize::MAX; input.len()];
+        let insert = |head: &mut Vec<usize>, prev: &mut Vec<usize>, pos: usize| {
+            let h = hash4(input, pos);
+            prev[pos] = head[h];
+            head[h] = pos;
+        };
+
+        let mut literal_start = 0;
+        let mut pos = 0;
+        while pos + MIN_MATCH <= input.len() {
+            let mut best_len = 0;
+            let mut best_pos = 0;
+            let mut candidate = head[hash4(input, pos)];
+            let mut chain = 0;
+            while candidate != usize::MAX && chain < self.max_chain {
+                let len = match_len(input, candidate, pos);
+                if len > best_len {
+                    best_len = len;
+                    best_pos = candidate;
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+            insert(&mut head, &mut prev, pos);
+            if best_len < MIN_MATCH {
+                pos += 1;
+                continue;
+            }
+            put_varint(&mut res, (pos - literal_start) as u64);
+            res.extend_from_slice(&input[literal_start..pos]);
+            put_varint(&mut res, best_len as u64);
+            put_varint(&mut res, (pos - best_pos) as u64);
+            for covered in pos + 1..(pos + best_len).min(input.len() + 1 - MIN_MATCH) {
+                insert(&mut head, &mut prev, covered);
+            }
+            pos += best_len;
+            literal_start = pos;
+        }
+        put_varint(&mut res, (input.len() - literal_start) as u64);
+        res.extend_from_slice(&input[literal_start..]);
+        res
+    }
+
+    fn decompress(&self, input: &[u8]) -> Option<Vec<u8>> {
+        let (len, mut pos) = get_varint(input)?;
+        if len > MAX_BLOCK_SIZE as u64 {
+            return None;
+        }
+        let len = len as usize;
+        let mut res: Vec<u8> = Vec::with_capacity(len);
+        let next_varint = |pos: &mut usize| -> Option<usize> {
+            let (value, n) = get_varint(&input[*pos..])?;
+            *pos += n;
+            Some(value as usize)
+        };
+        while res.len() < len || pos < input.len() {
+            let literals = next_varint(&mut pos)?;
+            if literals > input.len() - pos || res.len() + literals > len {
+                return None;
+            }
+            res.extend_from_slice(&input[pos..pos + literals]);
+            pos += literals;
+            if res.len() == len {
+                break;
+            }
+            let matched = next_varint(&mut pos)?;
+            let offset = next_varint(&mut pos)?;
+            if offset == 0 || offset > res.len() || res.len() + matched > len {
+                return None;
+            }
+            //the match may overlap the bytes it produces
+            let start = res.len() - offset;
+            for i in 0..matched {
+                res.push(res[start + i]);
+            }
+        }
+        if res.len() == len && pos == input.len() {
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_no_compression() {
+    let codec = NoCompression;
+    assert_eq!(
+        b"abc".to_vec(),
+        codec.decompress(&codec.compress(b"abc")).unwrap()
+    );
+    assert_eq!(NO_COMPRESSION, by_id(NO_COMPRESSION).unwrap().id());
+    assert!(by_id(42).is_none());
+}
+
+#[test]
+fn test_lz() {
+    let json: String = (0..200)
+        .map(|i| {
+            format!(
+                "{{\"id\":{},\"name\":\"user{}\",\"active\":true}}",
+                i,
+                i % 7
+            )
+        })
+        .collect();
+    let inputs: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        b"abc".to_vec(),
+        vec![b' '; 1000],
+        (0..=255u8).cycle().take(3000).collect(),
+        json.into_bytes(),
+    ];
+    for codec in [Lz::fast(), Lz::high()].iter() {
+        for input in inputs.iter() {
+            let compressed = codec.compress(input);
+            assert_eq!(Some(input.clone()), codec.decompress(&compressed));
+        }
+        assert!(codec.compress(&inputs[2]).len() < 20);
+        assert!(codec.compress(&inputs[4]).len() < inputs[4].len() / 3);
+    }
+    assert!(Lz::high().compress(&inputs[4]).len() <= Lz::fast().compress(&inputs[4]).len());
+    //garbage is rejected rather than decoded
+    assert_eq!(None, Lz::fast().decompress(&[10, 0, 3, 1]));
+    assert_eq!(None, Lz::fast().decompress(&[3, 5, b'a', b'b', b'c']));
+    let mut huge: Vec<u8> = Vec::new();
+    put_varint(&mut huge, 1 << 40);
+    assert_eq!(None, Lz::fast().decompress(&huge));
+}
@@ -0,0 +1,57 @@
+//CRC-32C (Castagnoli), the checksum stored behind every block of a run file.
+const POLY: u32 = 0x82f6_3b78;
+//stored checksums are masked, so the checksum of data that contains checksums stays robust
+static MASK_DELTA: u32 = 0xa282_ead8;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static TABLE: [u32; 256] = make_table();
+
+/// Returns the crc32c of `init_crc` extended by `data`.
+pub fn extend(init_crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !init_crc;
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub fn value(data: &[u8]) -> u32 {
+    extend(0, data)
+}
+
+pub fn mask(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(MASK_DELTA)
+}
+
+pub fn unmask(masked_crc: u32) -> u32 {
+    masked_crc.wrapping_sub(MASK_DELTA).rotate_left(15)
+}
+
+#[test]
+fn test_crc32c() {
+    assert_eq!(0xe306_9283, value(b"123456789"));
+    assert_eq!(0x8a91_36aa, value(&[0u8; 32]));
+    assert_eq!(value(b"hello world"), extend(value(b"hello "), b"world"));
+    assert_eq!(0x1234_5678, unmask(mask(0x1234_5678)));
+    assert_ne!(0x1234_5678, mask(0x1234_5678));
+    assert_eq!(POLY, TABLE[128]);
+}
@@ -1,5 +1,6 @@
 use std::cmp::Ordering;
 use std::hash::Hash;
+use std::str;
 
 /*
  *  use for components
@@ -44,6 +45,20 @@ impl PartialEq for Entry {
 
 pub type EntryT = Entry;
 
+//keys and values are stored left padded with spaces to their fixed size
+pub fn fill_str_with_witespace(input: &str, length: usize) -> Vec<u8> {
+    //longer strings are kept whole
+    let mut res = vec![b' '; length.saturating_sub(input.len())];
+    res.extend(input.as_bytes().to_vec());
+    //assert_eq!(res.len(), length);
+    res
+}
+
+pub fn vec_u8_to_str(input: &[u8]) -> String {
+    let res: String = str::from_utf8(input).unwrap().trim().to_owned();
+    res
+}
+
 /*
  *  use for bloom
  */
@@ -0,0 +1,33 @@
+use crate::lsm::LSMTree;
+
+/// The put/get/del/range API shared by every key value engine of this crate, so callers
+/// such as the command line can switch engines without changing code.
+pub trait Engine {
+    fn put(&mut self, key_str: &str, value_str: &str) -> bool;
+    fn get(&mut self, key_str: &str) -> Option<String>;
+    fn range(&mut self, start_str: &str, end_str: &str) -> Vec<String>;
+    fn del(&mut self, key_str: &str);
+    fn close(&mut self);
+}
+
+impl Engine for LSMTree {
+    fn put(&mut self, key_str: &str, value_str: &str) -> bool {
+        LSMTree::put(self, key_str, value_str)
+    }
+
+    fn get(&mut self, key_str: &str) -> Option<String> {
+        LSMTree::get(self, key_str)
+    }
+
+    fn range(&mut self, start_str: &str, end_str: &str) -> Vec<String> {
+        LSMTree::range(self, start_str, end_str)
+    }
+
+    fn del(&mut self, key_str: &str) {
+        LSMTree::del(self, key_str)
+    }
+
+    fn close(&mut self) {
+        LSMTree::close(self)
+    }
+}
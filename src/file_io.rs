@@ -0,0 +1,191 @@
+//How runs read and write their files. Mmap maps the whole file and lets the kernel page it
+//in, Pread reads blocks with pread through the page cache, and Direct opens files with
+//O_DIRECT so reads bypass the page cache and only the block cache of the engine caches.
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+//offsets, lengths and buffers of O_DIRECT I/O must be multiples of this
+pub static DIRECT_IO_ALIGNMENT: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoMode {
+    #[default]
+    Mmap,
+    Pread,
+    Direct,
+}
+
+/// A zeroed buffer whose start address is aligned for O_DIRECT.
+pub struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(len.max(1), DIRECT_IO_ALIGNMENT).unwrap();
+        let ptr = unsafe { alloc_zeroed(layout) };
+        assert!(!ptr.is_null(), "allocating {} aligned bytes failed", len);
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+pub fn align_down(value: usize) -> usize {
+    value - value % DIRECT_IO_ALIGNMENT
+}
+
+pub fn align_up(value: usize) -> usize {
+    align_down(value + DIRECT_IO_ALIGNMENT - 1)
+}
+
+/// Opens `path` with O_DIRECT, or without it if the file system does not support it (e.g.
+/// tmpfs). Returns whether the file was opened for direct I/O.
+pub fn open_direct(path: &Path, write: bool) -> io::Result<(File, bool)> {
+    let mut options = OpenOptions::new();
+    options.read(true).write(write);
+    match options.clone().custom_flags(libc::O_DIRECT).open(path) {
+        Ok(file) => Ok((file, true)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok((options.open(path)?, false)),
+        Err(e) => Err(e),
+    }
+}
+
+/// An open run file.
+pub enum RunFile {
+    Mapped(memmap::Mmap),
+    Pread(File),
+    //the flag tells whether O_DIRECT is in effect
+    Direct(File, bool),
+}
+
+impl RunFile {
+    pub fn open(path: &Path, mode: IoMode) -> io::Result<RunFile> {
+        match mode {
+            IoMode::Mmap => {
+                let file = File::open(path)?;
+                Ok(RunFile::Mapped(unsafe { memmap::Mmap::map(&file)? }))
+            }
+            IoMode::Pread => Ok(RunFile::Pread(File::open(path)?)),
+            IoMode::Direct => {
+                let (file, direct) = open_direct(path, false)?;
+                Ok(RunFile::Direct(file, direct))
+            }
+        }
+    }
+
+    pub fn len(&self) -> io::Result<usize> {
+        match self {
+            RunFile::Mapped(mmap) => Ok(mmap.len()),
+            RunFile::Pread(file) | RunFile::Direct(file, _) => Ok(file.metadata()?.len() as usize),
+        }
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Reads `len` bytes at `offset`, which must lie within the file.
+    pub fn read(&self, len: usize, offset: usize) -> io::Result<Vec<u8>> {
+        match self {
+            RunFile::Mapped(mmap) => Ok(mmap[offset..offset + len].to_vec()),
+            RunFile::Pread(file) | RunFile::Direct(file, false) => {
+                let mut res: Vec<u8> = vec![0; len];
+                file.read_exact_at(&mut res, offset as u64)?;
+                Ok(res)
+            }
+            RunFile::Direct(file, true) => {
+                //read the aligned pages around the range, the last one may be cut by the end of file
+                let start = align_down(offset);
+                let mut buffer = AlignedBuffer::new(align_up(offset + len) - start);
+                let mut read = 0;
+                while read < offset + len - start {
+                    match file.read_at(&mut buffer[read..], (start + read) as u64)? {
+                        0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                        n => read += n,
+                    }
+                }
+                Ok(buffer[offset - start..offset - start + len].to_vec())
+            }
+        }
+    }
+}
+
+/// Writes `data` as the whole content of `file`, which was just created at `path`, and syncs
+/// it. Pread mode writes through the page cache, Direct mode with O_DIRECT. Mmap mode is
+/// written by the run itself.
+pub fn write_file(file: &mut File, path: &Path, data: &[u8], mode: IoMode) -> io::Result<()> {
+    match mode {
+        IoMode::Mmap | IoMode::Pread => {
+            let mut writer = io::BufWriter::new(&mut *file);
+            writer.write_all(data)?;
+            writer.flush()?;
+        }
+        IoMode::Direct => {
+            let (direct_file, direct) = open_direct(path, true)?;
+            if direct {
+                //O_DIRECT writes whole pages, the padding is cut off afterwards
+                let mut buffer = AlignedBuffer::new(align_up(data.len()));
+                buffer[..data.len()].copy_from_slice(data);
+                direct_file.write_all_at(&buffer, 0)?;
+                direct_file.set_len(data.len() as u64)?;
+            } else {
+                direct_file.write_all_at(data, 0)?;
+            }
+        }
+    }
+    file.sync_data()
+}
+
+#[test]
+fn test_run_file() {
+    let path = Path::new("/tmp/file_io_test");
+    let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+    for mode in [IoMode::Mmap, IoMode::Pread, IoMode::Direct].iter() {
+        let mut file = File::create(path).unwrap();
+        write_file(&mut file, path, &data, *mode).unwrap();
+        assert_eq!(data, std::fs::read(path).unwrap());
+
+        let run_file = RunFile::open(path, *mode).unwrap();
+        assert_eq!(data.len(), run_file.len().unwrap());
+        for (len, offset) in [(10, 0), (100, 4090), (5000, 5000), (1, 9999)].iter() {
+            assert_eq!(
+                &data[*offset..*offset + *len],
+                run_file.read(*len, *offset).unwrap().as_slice()
+            );
+        }
+    }
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_aligned_buffer() {
+    let mut buffer = AlignedBuffer::new(100);
+    assert_eq!(0, buffer.as_ptr() as usize % DIRECT_IO_ALIGNMENT);
+    assert_eq!(100, buffer.len());
+    buffer[99] = 1;
+    assert_eq!(1, buffer[99]);
+}
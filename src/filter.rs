@@ -0,0 +1,149 @@
+//Filters of the runs, which rule out the runs that cannot hold a key before any block is
+//read. A bloom filter takes keys as they are put, a static filter like the xor filter
+//collects them and is built when the run is finished. Runs are never changed after that,
+//so the static filters can use less memory for the same false positive rate.
+//Every kind is stored in the run file under its own meta block name.
+use crate::bloom_filter::BloomFilter;
+use crate::table;
+use crate::xor_filter::XorFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterKind {
+    #[default]
+    Bloom,
+    //bloom filter keeping all bits of a key in one cache line
+    BlockedBloom,
+    Xor,
+}
+
+impl FilterKind {
+    /// Name of the meta block the filter is stored in.
+    pub fn block_name(&self) -> &'static str {
+        match self {
+            FilterKind::Bloom | FilterKind::BlockedBloom => table::FILTER_BLOCK_NAME,
+            FilterKind::Xor => table::XOR_FILTER_BLOCK_NAME,
+        }
+    }
+
+    /// The kind stored under meta block `name`, or None if it is not a filter block.
+    /// Blocked bloom filters are stored like the others and come back as `Bloom`.
+    pub fn from_block_name(name: &[u8]) -> Option<FilterKind> {
+        if name == table::FILTER_BLOCK_NAME.as_bytes() {
+            Some(FilterKind::Bloom)
+        } else if name == table::XOR_FILTER_BLOCK_NAME.as_bytes() {
+            Some(FilterKind::Xor)
+        } else {
+            None
+        }
+    }
+}
+
+pub trait Filter: Send + Sync {
+    /// Adds a key of the run.
+    fn set(&mut self, key: &[u8]);
+    /// Called once all keys were added, before the filter is checked or encoded.
+    fn finish(&mut self) {}
+    /// Returns false if `key` was never added, and true if it may have been.
+    fn check(&self, key: &[u8]) -> bool;
+    fn kind(&self) -> FilterKind;
+    /// Memory of the filter in bits.
+    fn num_bits(&self) -> u64;
+    fn encode(&self) -> Vec<u8>;
+}
+
+impl Filter for BloomFilter {
+    fn set(&mut self, key: &[u8]) {
+        BloomFilter::set(self, key)
+    }
+
+    fn check(&self, key: &[u8]) -> bool {
+        BloomFilter::check(self, key)
+    }
+
+    fn kind(&self) -> FilterKind {
+        if self.is_blocked() {
+            FilterKind::BlockedBloom
+        } else {
+            FilterKind::Bloom
+        }
+    }
+
+    fn num_bits(&self) -> u64 {
+        BloomFilter::num_bits(self)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        BloomFilter::encode(self)
+    }
+}
+
+impl Filter for XorFilter {
+    fn set(&mut self, key: &[u8]) {
+        XorFilter::set(self, key)
+    }
+
+    fn finish(&mut self) {
+        self.build()
+    }
+
+    fn check(&self, key: &[u8]) -> bool {
+        XorFilter::check(self, key)
+    }
+
+    fn kind(&self) -> FilterKind {
+        FilterKind::Xor
+    }
+
+    fn num_bits(&self) -> u64 {
+        XorFilter::num_bits(self)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        XorFilter::encode(self)
+    }
+}
+
+/// Returns an empty filter of `kind` for `expected_entries` keys with about `bits_per_entry`
+/// bits per key.
+pub fn new_filter(kind: FilterKind, expected_entries: u64, bits_per_entry: f32) -> Box<dyn Filter> {
+    match kind {
+        FilterKind::Bloom => Box::new(BloomFilter::with_bits_per_entry(
+            expected_entries,
+            bits_per_entry,
+        )),
+        FilterKind::BlockedBloom => {
+            Box::new(BloomFilter::blocked(expected_entries, bits_per_entry))
+        }
+        FilterKind::Xor => Box::new(XorFilter::with_bits_per_entry(bits_per_entry)),
+    }
+}
+
+/// Restores a filter of `kind` written by `Filter::encode`, returns None if `src` is not one.
+pub fn decode(kind: FilterKind, src: &[u8]) -> Option<Box<dyn Filter>> {
+    match kind {
+        FilterKind::Bloom | FilterKind::BlockedBloom => {
+            Some(Box::new(BloomFilter::decode(src)?) as Box<dyn Filter>)
+        }
+        FilterKind::Xor => Some(Box::new(XorFilter::decode(src)?) as Box<dyn Filter>),
+    }
+}
+
+#[test]
+fn test_filter_kinds() {
+    let key = |i: u32| format!("{:08}", i).into_bytes();
+    for kind in [FilterKind::Bloom, FilterKind::BlockedBloom, FilterKind::Xor].iter() {
+        let mut filter = new_filter(*kind, 1000, 10.0);
+        for i in 0..1000 {
+            filter.set(&key(i));
+        }
+        filter.finish();
+        assert_eq!(*kind, filter.kind());
+        let name = FilterKind::from_block_name(kind.block_name().as_bytes()).unwrap();
+        let decoded = decode(name, &filter.encode()).unwrap();
+        assert_eq!(*kind, decoded.kind());
+        assert_eq!(filter.num_bits(), decoded.num_bits());
+        assert!((0..1000).all(|i| decoded.check(&key(i))));
+        assert!((1000..2000).filter(|i| decoded.check(&key(*i))).count() < 50);
+    }
+    assert_eq!(None, FilterKind::from_block_name(b"properties"));
+}
@@ -0,0 +1,175 @@
+//Hash memtable for point lookup heavy trees. A key is hashed to one of a fixed number of
+//buckets, each a linked list sorted by key, so a get or put walks only the few entries
+//sharing its bucket. Reading the entries in key order has to merge the buckets, which
+//makes range and the flush cost a sort of all entries.
+use crate::bloom_filter::BloomFilter;
+use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::memtable::{entry_charge, MemTable};
+
+pub static DEFAULT_BUCKETS: usize = 1 << 14;
+
+struct Node {
+    entry: EntryT,
+    next: Option<Box<Node>>,
+}
+
+pub struct HashLinkList {
+    buckets: Vec<Option<Box<Node>>>,
+    len: usize,
+    //approximate memory of the entries in bytes
+    size: usize,
+}
+
+impl HashLinkList {
+    pub fn new(num_buckets: usize) -> HashLinkList {
+        assert!(num_buckets > 0);
+        HashLinkList {
+            buckets: (0..num_buckets).map(|_| None).collect(),
+            len: 0,
+            size: 0,
+        }
+    }
+
+    fn bucket(&self, key: &[u8]) -> usize {
+        (BloomFilter::hash_key(key) % self.buckets.len() as u64) as usize
+    }
+
+    fn bucket_iter(&self, bucket: usize) -> impl Iterator<Item = &EntryT> {
+        let mut node = self.buckets[bucket].as_deref();
+        std::iter::from_fn(move || {
+            let current = node?;
+            node = current.next.as_deref();
+            Some(&current.entry)
+        })
+    }
+
+    //every entry matching pred, sorted by key. A key lives in a single bucket, so there
+    //are no duplicates to drop.
+    fn sorted_entries<F: Fn(&EntryT) -> bool>(&self, pred: F) -> Vec<EntryT> {
+        let mut res: Vec<EntryT> = (0..self.buckets.len())
+            .flat_map(|bucket| self.bucket_iter(bucket))
+            .filter(|entry| pred(entry))
+            .cloned()
+            .collect();
+        res.sort_unstable();
+        res
+    }
+}
+
+impl Default for HashLinkList {
+    fn default() -> Self {
+        HashLinkList::new(DEFAULT_BUCKETS)
+    }
+}
+
+impl Drop for HashLinkList {
+    fn drop(&mut self) {
+        //unlinks the lists node by node, dropping a long list recursively could overflow
+        //the stack
+        self.empty();
+    }
+}
+
+impl MemTable for HashLinkList {
+    fn put(&mut self, key: KeyT, value: ValueT) {
+        let bucket = self.bucket(&key);
+        self.size += entry_charge(&key, &value);
+        let mut link = &mut self.buckets[bucket];
+        while link.as_ref().is_some_and(|node| node.entry.key < key) {
+            link = &mut link.as_mut().unwrap().next;
+        }
+        match link {
+            Some(node) if node.entry.key == key => {
+                self.size -= entry_charge(&node.entry.key, &node.entry.value);
+                node.entry.value = value;
+            }
+            _ => {
+                let next = link.take();
+                *link = Some(Box::new(Node {
+                    entry: EntryT { key, value },
+                    next,
+                }));
+                self.len += 1;
+            }
+        }
+    }
+
+    fn get(&self, key: &KeyT) -> Option<ValueT> {
+        self.bucket_iter(self.bucket(key))
+            .take_while(|entry| entry.key <= *key)
+            .find(|entry| entry.key == *key)
+            .map(|entry| entry.value.clone())
+    }
+
+    fn range(&self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
+        self.sorted_entries(|entry| entry.key >= *start && entry.key <= *end)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = EntryT> + '_> {
+        Box::new(self.sorted_entries(|_| true).into_iter())
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.size
+    }
+
+    fn empty(&mut self) {
+        for bucket in self.buckets.iter_mut() {
+            let mut node = bucket.take();
+            while let Some(mut current) = node {
+                node = current.next.take();
+            }
+        }
+        self.len = 0;
+        self.size = 0;
+    }
+}
+
+#[test]
+fn test_hash_linklist() {
+    //few buckets, so every list holds many keys
+    let mut table = HashLinkList::new(7);
+    let key = |i: u32| format!("{:04}", i).into_bytes();
+    for i in (0..100).rev() {
+        table.put(key(i), key(i));
+    }
+    table.put(key(7), b"seven".to_vec());
+    assert_eq!(100, table.len());
+    assert_eq!(
+        99 * entry_charge(&key(0), &key(0)) + entry_charge(&key(7), b"seven"),
+        table.approximate_size()
+    );
+    assert_eq!(Some(b"seven".to_vec()), table.get(&key(7)));
+    assert_eq!(None, table.get(&key(100)));
+    for bucket in 0..7 {
+        let keys: Vec<&EntryT> = table.bucket_iter(bucket).collect();
+        assert!(keys.windows(2).all(|pair| pair[0].key < pair[1].key));
+    }
+    assert_eq!(
+        (0..100).map(key).collect::<Vec<KeyT>>(),
+        table.iter().map(|e| e.key).collect::<Vec<KeyT>>()
+    );
+    assert_eq!(
+        (10..=20).map(key).collect::<Vec<KeyT>>(),
+        table
+            .range(&key(10), &key(20))
+            .into_iter()
+            .map(|e| e.key)
+            .collect::<Vec<KeyT>>()
+    );
+    table.empty();
+    assert!(table.is_empty());
+    assert_eq!(None, table.get(&key(7)));
+
+    //a single long list is dropped without recursing, the keys are put in reverse to
+    //link each at the head
+    let mut long = HashLinkList::new(1);
+    for i in (0..100_000).rev() {
+        long.put(key(i), Vec::new());
+    }
+    drop(long);
+}
@@ -0,0 +1,526 @@
+//KVell-style engine (see papers/KVell.pdf) for SSD-backed caches. Items are written unsorted
+//into slab files and never compacted; a per-shard in-memory B-tree index serves lookups and
+//scans. Every shard is owned by one worker of the pool and shares nothing with the others.
+use crate::bloom_filter::BloomFilter;
+use crate::data_type::{KeyT, ValueT};
+use crate::engine::Engine;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::ops::Bound::Included;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+//item header: state (1 byte), key length (2 bytes), value length (4 bytes)
+static ITEM_HEADER_SIZE: usize = 7;
+static ITEM_FREE: u8 = 0;
+static ITEM_LIVE: u8 = 1;
+//slot sizes of the slabs, every slab only holds items that fit its slot size
+pub static MIN_SLOT_SIZE: usize = 64;
+pub static MAX_SLOT_SIZE: usize = 1 << 20;
+
+fn slab_class(item_size: usize) -> usize {
+    let mut class = 0;
+    let mut slot_size = MIN_SLOT_SIZE;
+    while slot_size < item_size {
+        slot_size *= 2;
+        class += 1;
+    }
+    assert!(
+        slot_size <= MAX_SLOT_SIZE,
+        "item of {} bytes is too large",
+        item_size
+    );
+    class
+}
+
+struct Slab {
+    file: File,
+    slot_size: usize,
+    num_slots: u64,
+    free_slots: Vec<u64>,
+}
+
+impl Slab {
+    fn open(path: PathBuf, slot_size: usize) -> io::Result<Slab> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let num_slots = file.metadata()?.len() / slot_size as u64;
+        Ok(Slab {
+            file,
+            slot_size,
+            num_slots,
+            free_slots: Vec::new(),
+        })
+    }
+
+    fn allocate(&mut self) -> u64 {
+        match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                self.num_slots += 1;
+                self.num_slots - 1
+            }
+        }
+    }
+
+    fn write(&self, slot: u64, key: &KeyT, value: &ValueT) -> io::Result<()> {
+        let key_len = u16::try_from(key.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key of {} bytes is too large", key.len()),
+            )
+        })?;
+        let mut item: Vec<u8> = Vec::with_capacity(self.slot_size);
+        item.push(ITEM_LIVE);
+        item.extend_from_slice(&key_len.to_le_bytes());
+        item.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        item.extend_from_slice(key);
+        item.extend_from_slice(value);
+        item.resize(self.slot_size, 0);
+        self.file.write_all_at(&item, slot * self.slot_size as u64)
+    }
+
+    fn free(&mut self, slot: u64) -> io::Result<()> {
+        self.file
+            .write_all_at(&[ITEM_FREE], slot * self.slot_size as u64)?;
+        self.free_slots.push(slot);
+        Ok(())
+    }
+
+    //returns the key and value of a slot, or None for a free slot
+    fn read(&self, slot: u64) -> io::Result<Option<(KeyT, ValueT)>> {
+        let mut item: Vec<u8> = vec![0; self.slot_size];
+        self.file
+            .read_exact_at(&mut item, slot * self.slot_size as u64)?;
+        if item[0] != ITEM_LIVE {
+            return Ok(None);
+        }
+        let key_len = u16::from_le_bytes([item[1], item[2]]) as usize;
+        let value_len = u32::from_le_bytes([item[3], item[4], item[5], item[6]]) as usize;
+        let key_start = ITEM_HEADER_SIZE;
+        let value_start = key_start + key_len;
+        Ok(Some((
+            item[key_start..value_start].to_vec(),
+            item[value_start..value_start + value_len].to_vec(),
+        )))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ItemLocation {
+    class: usize,
+    slot: u64,
+}
+
+type RangeReply = io::Result<Vec<(KeyT, ValueT)>>;
+
+enum Request {
+    Put(KeyT, ValueT, Sender<io::Result<()>>),
+    Get(KeyT, Sender<io::Result<Option<ValueT>>>),
+    Del(KeyT, Sender<io::Result<()>>),
+    Range(KeyT, KeyT, Sender<RangeReply>),
+    Close(Sender<io::Result<()>>),
+}
+
+struct Shard {
+    dir: PathBuf,
+    slabs: Vec<Option<Slab>>,
+    index: BTreeMap<KeyT, ItemLocation>,
+}
+
+impl Shard {
+    //opens the slabs found in dir and rebuilds the index by scanning them
+    fn open(dir: PathBuf) -> io::Result<Shard> {
+        fs::create_dir_all(&dir)?;
+        let mut shard = Shard {
+            dir,
+            slabs: Vec::new(),
+            index: BTreeMap::new(),
+        };
+        let mut slot_size = MIN_SLOT_SIZE;
+        let mut class = 0;
+        while slot_size <= MAX_SLOT_SIZE {
+            if shard.slab_path(slot_size).exists() {
+                shard.slab(class)?;
+                let slab = shard.slabs[class].as_mut().unwrap();
+                for slot in 0..slab.num_slots {
+                    match slab.read(slot)? {
+                        Some((key, _)) => {
+                            shard.index.insert(key, ItemLocation { class, slot });
+                        }
+                        None => slab.free_slots.push(slot),
+                    }
+                }
+            }
+            slot_size *= 2;
+            class += 1;
+        }
+        Ok(shard)
+    }
+
+    fn slab_path(&self, slot_size: usize) -> PathBuf {
+        self.dir.join(format!("slab-{}.dat", slot_size))
+    }
+
+    //slabs are opened lazily, the first time an item of their class is written
+    fn slab(&mut self, class: usize) -> io::Result<&mut Slab> {
+        if self.slabs.len() <= class {
+            self.slabs.resize_with(class + 1, || None);
+        }
+        if self.slabs[class].is_none() {
+            let slot_size = MIN_SLOT_SIZE << class;
+            self.slabs[class] = Some(Slab::open(self.slab_path(slot_size), slot_size)?);
+        }
+        Ok(self.slabs[class].as_mut().unwrap())
+    }
+
+    fn put(&mut self, key: KeyT, value: ValueT) -> io::Result<()> {
+        let class = slab_class(ITEM_HEADER_SIZE + key.len() + value.len());
+        match self.index.get(&key).cloned() {
+            //same size class, update in place
+            Some(location) if location.class == class => {
+                self.slab(class)?.write(location.slot, &key, &value)
+            }
+            old => {
+                let slab = self.slab(class)?;
+                let slot = slab.allocate();
+                if let Err(e) = slab.write(slot, &key, &value) {
+                    slab.free_slots.push(slot);
+                    return Err(e);
+                }
+                if let Some(location) = old {
+                    self.slab(location.class)?.free(location.slot)?;
+                }
+                self.index.insert(key, ItemLocation { class, slot });
+                Ok(())
+            }
+        }
+    }
+
+    fn get(&mut self, key: &KeyT) -> io::Result<Option<ValueT>> {
+        match self.index.get(key).cloned() {
+            Some(location) => Ok(self
+                .slab(location.class)?
+                .read(location.slot)?
+                .map(|(_, value)| value)),
+            None => Ok(None),
+        }
+    }
+
+    fn del(&mut self, key: &KeyT) -> io::Result<()> {
+        if let Some(location) = self.index.remove(key) {
+            self.slab(location.class)?.free(location.slot)?;
+        }
+        Ok(())
+    }
+
+    fn range(&mut self, start: &KeyT, end: &KeyT) -> io::Result<Vec<(KeyT, ValueT)>> {
+        let locations: Vec<ItemLocation> = self
+            .index
+            .range((Included(start.clone()), Included(end.clone())))
+            .map(|(_, location)| *location)
+            .collect();
+        let mut res: Vec<(KeyT, ValueT)> = Vec::with_capacity(locations.len());
+        for location in locations {
+            if let Some(item) = self.slab(location.class)?.read(location.slot)? {
+                res.push(item);
+            }
+        }
+        Ok(res)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        for slab in self.slabs.iter().flatten() {
+            slab.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    //serves requests until the engine is closed or dropped
+    fn run(mut self, requests: Receiver<Request>) {
+        for request in requests {
+            match request {
+                Request::Put(key, value, reply) => {
+                    let _ = reply.send(self.put(key, value));
+                }
+                Request::Get(key, reply) => {
+                    let _ = reply.send(self.get(&key));
+                }
+                Request::Del(key, reply) => {
+                    let _ = reply.send(self.del(&key));
+                }
+                Request::Range(start, end, reply) => {
+                    let _ = reply.send(self.range(&start, &end));
+                }
+                Request::Close(reply) => {
+                    let _ = reply.send(self.sync());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+pub struct KVell {
+    shards: Vec<Sender<Request>>,
+    worker_pool: threadpool::ThreadPool,
+    tree_name: String,
+}
+
+impl KVell {
+    /// Returns a KVell-style key value store
+    ///
+    /// # Arguments
+    ///
+    /// * `num_shards` - Number of shards, each one served by its own worker thread. A store
+    ///   must be reopened with the number of shards it was created with.
+    /// * `tree_name` - Items are stored under /tmp/tree_name/
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///
+    /// use lsm_kv::kvell;
+    /// let mut kv = kvell::KVell::new(4, "kvell_doc_test".to_string());
+    /// kv.put("hello", "world");
+    /// kv.put("facebook", "google");
+    /// assert_eq!(kv.get("hello"), Some("world".to_string()));
+    /// assert_eq!(kv.range("a", "g"), vec!["google"]);
+    /// kv.del("hello");
+    /// assert_eq!(kv.get("hello"), None);
+    /// kv.clear();
+    ///
+    /// ```
+    pub fn new(num_shards: u64, tree_name: String) -> KVell {
+        //keys are spread over the shards by their number, a store opened with another number
+        //would look for keys in the wrong shards
+        let count_file = PathBuf::from(format!("/tmp/{}/shards", tree_name));
+        match fs::read_to_string(&count_file) {
+            Ok(stored) => {
+                if stored.trim() != num_shards.to_string() {
+                    panic!(
+                        "{} was created with {} shards, it cannot be opened with {}",
+                        tree_name,
+                        stored.trim(),
+                        num_shards
+                    );
+                }
+            }
+            Err(_) => {
+                let written = fs::create_dir_all(format!("/tmp/{}/", tree_name))
+                    .and_then(|_| fs::write(&count_file, num_shards.to_string()));
+                if let Err(e) = written {
+                    panic!("could not record the shards of {} due to {}", tree_name, e);
+                }
+            }
+        }
+        let worker_pool = threadpool::ThreadPool::new(num_shards as usize);
+        let mut shards: Vec<Sender<Request>> = Vec::new();
+        for i in 0..num_shards {
+            let dir = PathBuf::from(format!("/tmp/{}/shard-{}/", tree_name, i));
+            let shard = match Shard::open(dir) {
+                Ok(shard) => shard,
+                Err(e) => panic!("could not open shard {} due to {}", i, e),
+            };
+            let (sender, receiver) = channel();
+            worker_pool.execute(move || shard.run(receiver));
+            shards.push(sender);
+        }
+        KVell {
+            shards,
+            worker_pool,
+            tree_name,
+        }
+    }
+
+    //the shard of a key is persisted in effect, so the hash must not change between builds
+    fn shard(&self, key: &KeyT) -> &Sender<Request> {
+        let hash = BloomFilter::hash_key(key);
+        &self.shards[(hash % self.shards.len() as u64) as usize]
+    }
+
+    //sends a request built around a fresh reply channel and waits for the answer
+    fn call<T>(sender: &Sender<Request>, request: impl FnOnce(Sender<T>) -> Request) -> T {
+        let (reply, answer) = channel();
+        sender.send(request(reply)).expect("shard worker stopped");
+        answer.recv().expect("shard worker stopped")
+    }
+
+    pub fn put(&mut self, key_str: &str, value_str: &str) -> bool {
+        let key = key_str.as_bytes().to_vec();
+        let value = value_str.as_bytes().to_vec();
+        let sender = self.shard(&key);
+        match KVell::call(sender, |reply| Request::Put(key, value, reply)) {
+            Ok(()) => true,
+            Err(e) => panic!("could not put due to {}", e),
+        }
+    }
+
+    pub fn get(&mut self, key_str: &str) -> Option<String> {
+        let key = key_str.as_bytes().to_vec();
+        let sender = self.shard(&key);
+        match KVell::call(sender, |reply| Request::Get(key, reply)) {
+            Ok(value) => value.map(|v| String::from_utf8(v).unwrap()),
+            Err(e) => panic!("could not get due to {}", e),
+        }
+    }
+
+    pub fn del(&mut self, key_str: &str) {
+        let key = key_str.as_bytes().to_vec();
+        let sender = self.shard(&key);
+        if let Err(e) = KVell::call(sender, |reply| Request::Del(key, reply)) {
+            panic!("could not delete due to {}", e);
+        }
+    }
+
+    pub fn range(&mut self, start_str: &str, end_str: &str) -> Vec<String> {
+        let start = start_str.as_bytes().to_vec();
+        let end = end_str.as_bytes().to_vec();
+        if end < start {
+            return Vec::new();
+        }
+        //every shard scans its own index at the same time
+        let mut answers: Vec<Receiver<RangeReply>> = Vec::new();
+        for sender in self.shards.iter() {
+            let (reply, answer) = channel();
+            sender
+                .send(Request::Range(start.clone(), end.clone(), reply))
+                .expect("shard worker stopped");
+            answers.push(answer);
+        }
+        let mut items: Vec<(KeyT, ValueT)> = Vec::new();
+        for answer in answers {
+            match answer.recv().expect("shard worker stopped") {
+                Ok(shard_items) => items.extend(shard_items),
+                Err(e) => panic!("could not scan due to {}", e),
+            }
+        }
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        items
+            .into_iter()
+            .map(|(_, value)| String::from_utf8(value).unwrap())
+            .collect()
+    }
+
+    /// Syncs every slab to disk and stops the shard workers.
+    pub fn close(&mut self) {
+        for sender in self.shards.drain(..) {
+            if let Err(e) = KVell::call(&sender, Request::Close) {
+                panic!("could not close shard due to {}", e);
+            }
+        }
+        self.worker_pool.join();
+    }
+
+    /// Stops the shard workers and removes every slab file.
+    pub fn clear(&mut self) {
+        self.shards.clear();
+        self.worker_pool.join();
+        let _ = fs::remove_dir_all(format!("/tmp/{}/", self.tree_name));
+    }
+}
+
+impl Engine for KVell {
+    fn put(&mut self, key_str: &str, value_str: &str) -> bool {
+        KVell::put(self, key_str, value_str)
+    }
+
+    fn get(&mut self, key_str: &str) -> Option<String> {
+        KVell::get(self, key_str)
+    }
+
+    fn range(&mut self, start_str: &str, end_str: &str) -> Vec<String> {
+        KVell::range(self, start_str, end_str)
+    }
+
+    fn del(&mut self, key_str: &str) {
+        KVell::del(self, key_str)
+    }
+
+    fn close(&mut self) {
+        KVell::close(self)
+    }
+}
+
+#[test]
+fn test_slab_class() {
+    assert_eq!(0, slab_class(1));
+    assert_eq!(0, slab_class(MIN_SLOT_SIZE));
+    assert_eq!(1, slab_class(MIN_SLOT_SIZE + 1));
+    assert_eq!(4, slab_class(MIN_SLOT_SIZE * 16));
+}
+
+#[test]
+fn test_kvell() {
+    let test_size = 1000;
+    let mut kv = KVell::new(4, "kvell_test".to_string());
+    kv.clear();
+    let mut kv = KVell::new(4, "kvell_test".to_string());
+    for i in 0..test_size {
+        kv.put(&format!("{:04}", i), &i.to_string());
+    }
+    //grow some values into a larger slab class and delete others
+    for i in 0..test_size / 2 {
+        if i % 2 == 0 {
+            kv.put(&format!("{:04}", i), &"x".repeat(200));
+        } else {
+            kv.del(&format!("{:04}", i));
+        }
+    }
+    let expected = |i: usize| {
+        if i >= test_size / 2 {
+            Some(i.to_string())
+        } else if i % 2 == 0 {
+            Some("x".repeat(200))
+        } else {
+            None
+        }
+    };
+    for j in 0..test_size {
+        assert_eq!(expected(j), kv.get(&format!("{:04}", j)));
+    }
+    assert_eq!(vec!["600", "601", "602"], kv.range("0600", "0602"));
+    kv.close();
+
+    //the index is rebuilt from the slabs
+    let mut kv2 = KVell::new(4, "kvell_test".to_string());
+    for j in 0..test_size {
+        assert_eq!(expected(j), kv2.get(&format!("{:04}", j)));
+    }
+    assert_eq!(test_size / 4, kv2.range("0000", "0499").len());
+    kv2.clear();
+}
+
+#[test]
+fn test_oversized_key() {
+    let dir = PathBuf::from("/tmp/kvell_key_test/shard-0/");
+    let _ = fs::remove_dir_all(&dir);
+    let mut shard = Shard::open(dir.clone()).unwrap();
+    let key = vec![b'k'; 1 << 16];
+    let class = slab_class(ITEM_HEADER_SIZE + key.len() + 1);
+    let err = shard.put(key, b"v".to_vec()).err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    //the slot of the rejected item is free again
+    assert_eq!(1, shard.slabs[class].as_ref().unwrap().free_slots.len());
+    shard.put(b"key".to_vec(), b"v".to_vec()).unwrap();
+    assert_eq!(Some(b"v".to_vec()), shard.get(&b"key".to_vec()).unwrap());
+    let _ = fs::remove_dir_all("/tmp/kvell_key_test/");
+}
+
+#[test]
+#[should_panic(expected = "created with 2 shards")]
+fn test_shard_count() {
+    let mut kv = KVell::new(2, "kvell_shard_test".to_string());
+    kv.clear();
+    let mut kv = KVell::new(2, "kvell_shard_test".to_string());
+    kv.close();
+    KVell::new(3, "kvell_shard_test".to_string());
+}
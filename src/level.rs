@@ -1,25 +1,155 @@
+use crate::compress::{Compressor, NoCompression};
+use crate::data_type::KeyT;
 use crate::run;
 //use core::fmt::Alignment::Left;
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A guard partitions a level of a fragmented LSM tree (FLSM). It owns every key in
+/// `[key, next guard's key)` and holds the fragments (runs) appended into it by compactions.
+pub struct Guard {
+    pub key: KeyT,
+    pub runs: VecDeque<run::Run>,
+}
+
+impl Guard {
+    pub fn new(key: KeyT) -> Guard {
+        Guard {
+            key,
+            runs: VecDeque::new(),
+        }
+    }
+}
 
 pub struct Level {
     pub runs: VecDeque<run::Run>,
     pub max_runs: usize,
     pub max_run_size: usize,
+    //only used in FLSM mode. guards[0] is the sentinel guard with an empty key.
+    pub guards: Vec<Guard>,
+    //guard keys picked since the last time this level was emptied
+    pub uncommitted_guards: BTreeSet<KeyT>,
+    //used to give every fragment in this level a distinct file name
+    pub next_run_id: usize,
+    //directory the runs of this level are written to
+    pub path: PathBuf,
+    //codec for the data blocks of runs written to this level
+    pub compressor: Arc<dyn Compressor>,
 }
 
 impl Level {
-    pub fn new(max_runs: usize, max_run_size: usize) -> Level {
+    pub fn new(max_runs: usize, max_run_size: usize, path: PathBuf) -> Level {
         Level {
             runs: VecDeque::new(),
             max_runs: max_runs,
             max_run_size: max_run_size,
+            path,
+            compressor: Arc::new(NoCompression),
+            guards: Vec::new(),
+            uncommitted_guards: BTreeSet::new(),
+            next_run_id: 0,
         }
     }
 
+    pub fn run_file(&self, id: usize) -> PathBuf {
+        self.path.join(format!("run_file-{}.txt", id))
+    }
+
     pub fn remaining(&self) -> usize {
-        self.max_runs - self.runs.len()
+        if self.guards.is_empty() {
+            self.max_runs - self.runs.len()
+        } else {
+            //a guarded level is full as soon as one of its guards is full
+            let fullest = self.guards.iter().map(|g| g.runs.len()).max().unwrap_or(0);
+            self.max_runs.saturating_sub(fullest)
+        }
+    }
+
+    pub fn is_guarded(&self) -> bool {
+        !self.guards.is_empty()
+    }
+
+    /// Returns the index of the guard covering `key`, i.e. the last guard whose key is <= `key`.
+    pub fn guard_index(&self, key: &KeyT) -> usize {
+        assert!(self.is_guarded());
+        match self.guards.binary_search_by(|g| g.key.cmp(key)) {
+            Ok(find) => find,
+            Err(not) => not - 1,
+        }
     }
+
+    /// Turns the uncommitted guard keys into real guards. Only done while the guards
+    /// being split hold no fragments, so no existing data has to be re-partitioned.
+    pub fn commit_guards(&mut self) {
+        let pending: Vec<KeyT> = self.uncommitted_guards.iter().cloned().collect();
+        for key in pending {
+            let index = self.guard_index(&key);
+            if self.guards[index].key == key {
+                //already a guard, nothing left to commit
+                self.uncommitted_guards.remove(&key);
+                continue;
+            }
+            if !self.guards[index].runs.is_empty() {
+                continue;
+            }
+            self.guards.insert(index + 1, Guard::new(key.clone()));
+            self.uncommitted_guards.remove(&key);
+        }
+    }
+
+    pub fn num_fragments(&self) -> usize {
+        self.guards.iter().map(|g| g.runs.len()).sum()
+    }
+
+    /// Every run of the level, including the fragments of guards.
+    pub fn all_runs(&self) -> impl Iterator<Item = &run::Run> {
+        self.runs
+            .iter()
+            .chain(self.guards.iter().flat_map(|g| g.runs.iter()))
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.all_runs().map(|run| run.size).sum()
+    }
+}
+
+/// Returns the id in the name of a file written by `Level::run_file`.
+pub fn run_file_id(path: &Path) -> Option<usize> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.strip_prefix("run_file-"))
+        .and_then(|id| id.parse::<usize>().ok())
+}
+
+#[test]
+fn test_run_file() {
+    let level = Level::new(4, 10, PathBuf::from("/tmp/level_test/3"));
+    let path = level.run_file(12);
+    assert_eq!(PathBuf::from("/tmp/level_test/3/run_file-12.txt"), path);
+    assert_eq!(Some(12), run_file_id(&path));
+    assert_eq!(None, run_file_id(Path::new("/tmp/level_test/guards")));
+}
+
+#[test]
+fn test_guard_index() {
+    let mut level = Level::new(4, 10, PathBuf::from("/tmp/level_test/1"));
+    level.guards.push(Guard::new(KeyT::new()));
+    level.uncommitted_guards.insert(vec![5]);
+    level.uncommitted_guards.insert(vec![9]);
+    level.commit_guards();
+    assert_eq!(3, level.guards.len());
+    assert_eq!(0, level.guard_index(&vec![1]));
+    assert_eq!(1, level.guard_index(&vec![5]));
+    assert_eq!(1, level.guard_index(&vec![7]));
+    assert_eq!(2, level.guard_index(&vec![200]));
+    assert!(level.uncommitted_guards.is_empty());
+    assert_eq!(4, level.remaining());
+    //a key that already is a guard is no longer pending
+    level.uncommitted_guards.insert(vec![5]);
+    level.commit_guards();
+    assert_eq!(3, level.guards.len());
+    assert!(level.uncommitted_guards.is_empty());
 }
 
 // pub fn access_iter(cur: &Level) {
@@ -1,6 +1,29 @@
+pub mod block;
+pub mod bloom_filter;
 pub mod buffer;
+pub mod cache;
+pub mod compress;
+pub mod crc32c;
 pub mod data_type;
+pub mod engine;
+pub mod error;
+pub mod file_io;
+pub mod filter;
+pub mod hash_linklist;
+pub mod kvell;
 pub mod level;
 pub mod lsm;
+pub mod memtable;
 pub mod merge;
+pub mod range_filter;
 pub mod run;
+pub mod skiplist;
+pub mod stats;
+pub mod table;
+pub mod trie;
+pub mod uring;
+pub mod vector_memtable;
+pub mod vlog;
+pub mod write_buffer;
+pub mod write_stall;
+pub mod xor_filter;
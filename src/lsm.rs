@@ -1,22 +1,39 @@
-use crate::buffer;
-use crate::data_type::{EntryT, ValueT, ENTRY_SIZE, TOMBSTONE};
+use crate::bloom_filter;
+use crate::cache;
+use crate::cache::{BlockCache, TableCache};
+use crate::compress::Compressor;
+use crate::data_type::{EntryT, KeyT, ValueT, TOMBSTONE};
+use crate::error;
+use crate::file_io::IoMode;
+use crate::filter::{self, FilterKind};
 use crate::level;
+use crate::memtable::{self, MemTable, MemTableKind};
 use crate::merge;
+use crate::range_filter::RangeFilter;
 use crate::run;
+use crate::stats;
+use crate::table;
+use crate::uring;
+use crate::vlog;
+use crate::write_buffer::WriteBufferManager;
+use crate::write_stall::{
+    StallReason, WriteController, WriteStallCondition, WriteStallOptions, WriteStallStats,
+};
 use rand::{thread_rng, Rng};
 use std::{io, thread};
 //use bit_vec::Iter;
 //use rand::distributions::weighted::WeightedError::TooMany;
 //use std::borrow::Borrow;
-use std::collections::HashMap;
 //use std::ptr::null;
 //use std::sync::{Arc, Mutex};
 use crate::data_type;
 use rand::distributions::UnitSphereSurface;
 use std::cmp::max;
+use std::collections::{HashMap, VecDeque};
 use std::fs::read_dir;
 use std::iter::Inspect;
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
 use std::{fs, str};
 
@@ -29,12 +46,49 @@ pub static DEFAULT_TREE_NAME: &str = "rust";
 
 pub struct LSMTree {
     levels: Vec<level::Level>,
-    buffer: buffer::Buffer,
+    buffer: Box<dyn MemTable>,
+    //kind of memtable the buffer is replaced with
+    memtable_kind: MemTableKind,
+    //full buffers being flushed to level 0 in the background, newest first
+    immutable_buffers: VecDeque<ImmutableBuffer>,
+    //the buffer and the immutable buffers together are at most this many
+    max_write_buffer_number: usize,
+    //names the files of background flushes until they join level 0
+    next_flush_id: usize,
+    //delays or stops puts the tree cannot keep up with
+    write_controller: Option<WriteController>,
+    //the buffer is flushed once its entries take this many bytes
+    buffer_max_bytes: usize,
+    //shares a memory cap with the buffers of other trees
+    write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    //bytes of the buffer reported to write_buffer_manager
+    buffer_reserved: usize,
     worker_pool: threadpool::ThreadPool,
+    //writes the immutable buffers, apart from worker_pool so reads never queue behind a flush
+    flush_pool: threadpool::ThreadPool,
     bf_bits_per_entry: f32,
-    //used for bloom filter initialization
-    depth: u64,
+    //memory for all bloom filters in bytes, split over the levels with Monkey when set
+    filter_memory_budget: Option<u64>,
+    //kind of filter new runs get
+    filter_kind: FilterKind,
+    //max prefix length and bits per prefix of the range filters of new runs
+    range_filter: Option<(usize, f32)>,
     tree_name: String,
+    //runs read this many times are moved to the hot tier
+    promotion_threshold: Option<u64>,
+    //verify block checksums on reads
+    verify_checksums: bool,
+    block_cache: Option<Arc<BlockCache>>,
+    table_cache: Arc<TableCache>,
+    io_mode: IoMode,
+    //reads the blocks of several runs in one batch, set by enable_io_uring
+    batch_reader: Option<uring::BatchReader>,
+    //get probes the candidate runs on worker_pool at once
+    parallel_get: bool,
+    //WiscKey: values above the threshold live in a separate log
+    value_log: Option<vlog::ValueLog>,
+    //fragmented LSM (PebblesDB): levels below 0 are partitioned by guards
+    flsm: bool,
 }
 
 impl LSMTree {
@@ -42,7 +96,8 @@ impl LSMTree {
     ///
     /// # Arguments
     ///
-    /// * `buf_max_entries` - Max number of entries in memory buffer
+    /// * `buf_max_entries` - Size of the memory buffer in entries of the default key and value
+    ///   size, see `set_buffer_size` to give it in bytes
     /// * `dep` - depth of LSM tree
     /// * `fanout` - A factor that determines how to scale Run size for deeper levels
     /// * `bf_bits_per_entry` - Used for bloom filter size initialization
@@ -85,24 +140,726 @@ impl LSMTree {
         fs::create_dir(format!("/tmp/{}/", tree_name));
         while tmp_deps > 0 {
             //level id starts from 0 to depth-1
-            fs::create_dir(format!(
-                "/tmp/{}/{}/",
-                tree_name,
-                tmp_levels.len().to_string()
+            let level_path = format!("/tmp/{}/{}/", tree_name, tmp_levels.len());
+            let _ = fs::create_dir(&level_path);
+            tmp_levels.push(level::Level::new(
+                fanout as usize,
+                max_run_size as usize,
+                PathBuf::from(level_path),
             ));
-            tmp_levels.push(level::Level::new(fanout as usize, max_run_size as usize));
             //create a subdir for corresponding level
             max_run_size *= fanout;
             tmp_deps -= 1;
         }
+        //runs of flushes a previous tree never installed are dropped, their buffers are lost
+        let flush_dir = format!("/tmp/{}/flush/", tree_name);
+        let _ = fs::remove_dir_all(&flush_dir);
+        let _ = fs::create_dir(&flush_dir);
 
         LSMTree {
             levels: tmp_levels,
-            depth: dep,
             bf_bits_per_entry: bf_bits_per_entry,
+            filter_memory_budget: None,
+            filter_kind: FilterKind::Bloom,
+            range_filter: None,
             worker_pool: threadpool::ThreadPool::new(num_threads as usize),
-            buffer: buffer::Buffer::new(buf_max_entries as usize),
+            //flushes are installed in order, so a single thread loses little
+            flush_pool: threadpool::ThreadPool::new(1),
+            buffer: memtable::new_memtable(MemTableKind::BTree),
+            memtable_kind: MemTableKind::BTree,
+            immutable_buffers: VecDeque::new(),
+            max_write_buffer_number: 1,
+            next_flush_id: 0,
+            write_controller: None,
+            //as many entries of the default key and value size as requested
+            buffer_max_bytes: buf_max_entries as usize
+                * (data_type::ENTRY_SIZE + memtable::ENTRY_OVERHEAD),
+            write_buffer_manager: None,
+            buffer_reserved: 0,
             tree_name: tree_name,
+            value_log: None,
+            flsm: false,
+            promotion_threshold: None,
+            verify_checksums: true,
+            block_cache: None,
+            table_cache: cache::default_table_cache(),
+            io_mode: IoMode::Mmap,
+            batch_reader: None,
+            parallel_get: false,
+        }
+    }
+
+    /// Turns checksum verification of blocks read from runs on or off. Verification is on by
+    /// default; turning it off saves a crc32c per block read on latency-critical paths.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.verify_checksums = verify;
+        for run in self.runs_mut() {
+            run.verify_checksums = verify;
+        }
+    }
+
+    /// Caches the data blocks read by `get` and `range` in `cache`. The same cache can be
+    /// shared by several trees to put one memory budget on all of them.
+    pub fn set_block_cache(&mut self, cache: Arc<BlockCache>) {
+        for run in self.runs_mut() {
+            run.block_cache = Some(cache.clone());
+        }
+        self.block_cache = Some(cache);
+    }
+
+    pub fn block_cache(&self) -> Option<&Arc<BlockCache>> {
+        self.block_cache.as_ref()
+    }
+
+    /// Keeps at most `max_open_files` run files of this tree open and mapped. By default the
+    /// runs of all trees share a table cache of `DEFAULT_MAX_OPEN_FILES` files.
+    pub fn set_max_open_files(&mut self, max_open_files: usize) {
+        let table_cache = Arc::new(TableCache::new(max_open_files));
+        for run in self.runs_mut() {
+            run.table_cache.evict(run.cache_id);
+            run.table_cache = table_cache.clone();
+        }
+        self.table_cache = table_cache;
+    }
+
+    pub fn table_cache(&self) -> &Arc<TableCache> {
+        &self.table_cache
+    }
+
+    /// Selects how runs are read and written: through mmap (the default), with pread and
+    /// buffered writes, or with O_DIRECT. Direct I/O bypasses the page cache, so it is best
+    /// combined with a block cache. File systems without O_DIRECT support fall back to pread.
+    pub fn set_io_mode(&mut self, mode: IoMode) {
+        self.io_mode = mode;
+        for run in self.runs_mut() {
+            run.set_io_mode(mode);
+        }
+    }
+
+    /// Reads the blocks needed by `range` from all runs in one batch, and the blocks needed by
+    /// `multi_get` from all runs of a level in one batch, submitted to io_uring so the reads
+    /// are served concurrently. Kernels without io_uring get the batch read with pread.
+    /// Returns whether io_uring is used.
+    pub fn enable_io_uring(&mut self) -> bool {
+        let reader = uring::BatchReader::new(uring::DEFAULT_RING_ENTRIES);
+        let is_uring = reader.is_uring();
+        self.batch_reader = Some(reader);
+        is_uring
+    }
+
+    /// Makes `get` read the candidate runs of a key in parallel on the worker pool and take
+    /// the value of the newest run that has it. Bloom filters are still checked up front, so
+    /// only runs whose filter passes cost a block read, but with false positives in several
+    /// runs these reads overlap instead of adding up. A `get` returns once every read it
+    /// started is done, and flushes run on their own thread, so reads never wait for them.
+    pub fn set_parallel_get(&mut self, enabled: bool) {
+        self.parallel_get = enabled;
+    }
+
+    /// Sets the memtable writes are buffered in. `MemTableKind::SkipList` is a skiplist whose
+    /// reads take no locks, `Vector` appends writes and sorts them at the flush, for bulk
+    /// loads, and `HashLinkList` hashes keys to short lists, for point lookups of recent
+    /// writes. `SkipList` is the only `ConcurrentMemTable`, `put_batch` inserts into it from
+    /// several threads at once.
+    ///
+    /// Must be called while the buffer is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::memtable::MemTableKind;
+    /// let mut lsm = LSMTree::new(100, 5, 10, 0.5, 4, "memtable_doc".to_string());
+    /// lsm.set_memtable(MemTableKind::SkipList);
+    /// lsm.put("hello", "world");
+    /// assert_eq!(Some("world".to_string()), lsm.get("hello"));
+    /// ```
+    pub fn set_memtable(&mut self, kind: MemTableKind) {
+        assert!(self.buffer.is_empty());
+        self.buffer = memtable::new_memtable(kind);
+        self.memtable_kind = kind;
+    }
+
+    /// Lets up to `number` buffers hold writes: once the buffer is full it becomes immutable
+    /// and is flushed to level 0 on a background thread, while writes go on into a fresh buffer.
+    /// Immutable buffers stay readable until their run joins level 0. With `number - 1`
+    /// flushes pending, a full buffer waits for the oldest one. With 1, the default, the
+    /// buffer is flushed by the put that finds it full.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// let mut lsm = LSMTree::new(10, 5, 10, 0.5, 4, "write_buffer_number_doc".to_string());
+    /// lsm.set_max_write_buffer_number(3);
+    /// for i in 0..100 {
+    ///     lsm.put(&i.to_string(), "value");
+    /// }
+    /// assert_eq!(Some("value".to_string()), lsm.get("42"));
+    /// lsm.wait_for_flushes();
+    /// assert_eq!(0, lsm.num_immutable_buffers());
+    /// ```
+    pub fn set_max_write_buffer_number(&mut self, number: usize) {
+        assert!(number > 0);
+        self.max_write_buffer_number = number;
+        if number == 1 {
+            self.wait_for_flushes();
+        }
+    }
+
+    /// Number of full buffers whose flush has not joined level 0 yet.
+    pub fn num_immutable_buffers(&self) -> usize {
+        self.immutable_buffers.len()
+    }
+
+    //turns the buffer into an immutable buffer and writes it to a level 0 run on the worker
+    //pool. The run joins level 0 once install_flushes finds it done.
+    fn schedule_flush(&mut self) {
+        while !self.immutable_buffers.is_empty()
+            && self.immutable_buffers.len() + 1 >= self.max_write_buffer_number
+        {
+            self.install_oldest_flush();
+        }
+        let fresh = memtable::new_memtable(self.memtable_kind);
+        let memtable: Arc<dyn MemTable> = Arc::from(std::mem::replace(&mut self.buffer, fresh));
+        //the run is written outside the level directories, which load reads runs from, and
+        //gets its place in level 0 when it is installed
+        let file = PathBuf::from(format!(
+            "/tmp/{}/flush/flush-{}.txt",
+            self.tree_name, self.next_flush_id
+        ));
+        self.next_flush_id += 1;
+        let size = max(self.levels[0].max_run_size, memtable.len()) as u64;
+        let mut run = self.new_run(size, 0, file);
+        let (sender, receiver) = mpsc::channel();
+        let entries = memtable.clone();
+        self.flush_pool.execute(move || {
+            run.map_write();
+            for entry in entries.iter() {
+                run.put(&entry);
+            }
+            run.unmap();
+            let _ = sender.send(run);
+        });
+        self.immutable_buffers.push_front(ImmutableBuffer {
+            memtable,
+            flushed: receiver,
+        });
+    }
+
+    //moves the runs of finished flushes into level 0, oldest first, without waiting
+    fn install_flushes(&mut self) {
+        while let Some(oldest) = self.immutable_buffers.back() {
+            match oldest.flushed.try_recv() {
+                Ok(run) => {
+                    self.immutable_buffers.pop_back();
+                    self.install_run(run);
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    panic!("a flush was dropped by the flush pool")
+                }
+            }
+        }
+    }
+
+    //waits for the oldest flush and moves its run into level 0
+    fn install_oldest_flush(&mut self) {
+        if let Some(oldest) = self.immutable_buffers.pop_back() {
+            let run = oldest
+                .flushed
+                .recv()
+                .expect("a flush was dropped by the flush pool");
+            self.install_run(run);
+        }
+    }
+
+    fn install_run(&mut self, mut run: run::Run) {
+        self.merge_down(0);
+        let file = self.levels[0].run_file(self.levels[0].runs.len());
+        if let Err(e) = fs::rename(&run.tmp_file, &file) {
+            panic!(
+                "could not move {:?} into level 0 due to {}",
+                run.tmp_file, e
+            );
+        }
+        run.tmp_file = file;
+        self.levels[0].runs.push_front(run);
+        self.charge_buffer();
+    }
+
+    /// Waits until every immutable buffer is flushed and its run is in level 0.
+    pub fn wait_for_flushes(&mut self) {
+        while !self.immutable_buffers.is_empty() {
+            self.install_oldest_flush();
+        }
+    }
+
+    /// Slows down and stops puts once the number of level 0 runs, the bytes of the full
+    /// levels waiting to be merged down or the number of immutable buffers reach the limits
+    /// of `options`. Past a soft limit every put sleeps for the time its key and value take
+    /// at the delayed write rate, past a hard limit a put first waits for a flush or does
+    /// the pending compaction itself.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::write_stall::WriteStallOptions;
+    /// let mut lsm = LSMTree::new(10, 5, 10, 0.5, 4, "write_stall_doc".to_string());
+    /// lsm.set_write_stall_options(WriteStallOptions {
+    ///     level0_slowdown_runs: 4,
+    ///     level0_stop_runs: 6,
+    ///     ..WriteStallOptions::default()
+    /// });
+    /// for i in 0..100 {
+    ///     lsm.put(&i.to_string(), "value");
+    /// }
+    /// let stats = lsm.write_stall_stats().unwrap();
+    /// assert!(stats.delays.len() + stats.stops.len() > 0);
+    /// ```
+    pub fn set_write_stall_options(&mut self, options: WriteStallOptions) {
+        self.write_controller = Some(WriteController::new(options));
+    }
+
+    /// Number and duration of the delayed and stopped puts for every reason, if write stalls
+    /// are set.
+    pub fn write_stall_stats(&self) -> Option<&WriteStallStats> {
+        self.write_controller
+            .as_ref()
+            .map(|controller| controller.stats())
+    }
+
+    //bytes of the runs in full levels, which the next flush reaching them merges down. The
+    //last level is never merged down.
+    fn pending_compaction_bytes(&self) -> u64 {
+        let last = self.levels.len().saturating_sub(1);
+        self.levels[..last]
+            .iter()
+            .filter(|level| level.remaining() == 0)
+            .flat_map(|level| level.all_runs())
+            .map(|run| run.properties.data_size)
+            .sum()
+    }
+
+    //delays or stops a put of bytes as the write controller says
+    fn throttle(&mut self, bytes: usize) {
+        loop {
+            let condition = match self.write_controller.as_ref() {
+                Some(controller) => controller.condition(
+                    self.levels[0].runs.len(),
+                    self.pending_compaction_bytes(),
+                    self.immutable_buffers.len(),
+                ),
+                None => return,
+            };
+            let controller = self.write_controller.as_mut().unwrap();
+            match condition {
+                WriteStallCondition::Normal => return,
+                WriteStallCondition::Delayed { reason, rate } => {
+                    let wait = controller.delay(reason, rate, bytes);
+                    if wait > Duration::from_secs(0) {
+                        thread::sleep(wait);
+                    }
+                    return;
+                }
+                WriteStallCondition::Stopped(reason) => {
+                    let start = Instant::now();
+                    let progress = self.resolve_stall(reason);
+                    let controller = self.write_controller.as_mut().unwrap();
+                    controller.record_stop(reason, start.elapsed());
+                    if !progress {
+                        //nothing left to flush or compact, the put goes through
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    //flushes or compacts to get below the hard limit of reason, returns whether it could
+    fn resolve_stall(&mut self, reason: StallReason) -> bool {
+        match reason {
+            StallReason::ImmutableBuffers => {
+                let pending = !self.immutable_buffers.is_empty();
+                self.install_oldest_flush();
+                pending
+            }
+            StallReason::Level0Runs => {
+                let can_compact = self.levels.len() > 1 && !self.levels[0].runs.is_empty();
+                if can_compact {
+                    self.compact_level(0);
+                }
+                can_compact
+            }
+            StallReason::PendingCompactionBytes => {
+                let before = self.pending_compaction_bytes();
+                for level in (0..self.levels.len() - 1).rev() {
+                    self.merge_down(level);
+                }
+                self.pending_compaction_bytes() < before
+            }
+        }
+    }
+
+    //value of key in the buffer or, failing that, the newest immutable buffer holding it
+    fn memtables_get(&self, key: &KeyT) -> Option<ValueT> {
+        self.buffer.get(key).or_else(|| {
+            self.immutable_buffers
+                .iter()
+                .find_map(|immutable| immutable.memtable.get(key))
+        })
+    }
+
+    /// Flushes the buffer once its keys and values take about `bytes` of memory, instead of
+    /// after `buf_max_entries` entries of the default size.
+    pub fn set_buffer_size(&mut self, bytes: usize) {
+        self.buffer_max_bytes = bytes;
+    }
+
+    /// Counts the buffer of this tree against `manager`, which caps the memory of the
+    /// buffers of all trees sharing it. Once they take the capacity of the manager, a tree
+    /// whose buffer holds its share of it flushes the buffer on its next put, even if the
+    /// buffer is not full yet. There is no manager shared by default: trees are only capped
+    /// together when they are given clones of the same `Arc`, and a tree without a manager
+    /// is bounded by its own buffer size alone.
+    pub fn set_write_buffer_manager(&mut self, manager: Arc<WriteBufferManager>) {
+        if let Some(old) = self.write_buffer_manager.take() {
+            old.free(self.buffer_reserved);
+            old.unregister();
+        }
+        manager.register();
+        manager.reserve(self.buffer_reserved);
+        self.write_buffer_manager = Some(manager);
+    }
+
+    fn buffer_full(&self) -> bool {
+        let size = self.buffer.approximate_size();
+        !self.buffer.is_empty()
+            && (size >= self.buffer_max_bytes
+                || self
+                    .write_buffer_manager
+                    .as_ref()
+                    .is_some_and(|manager| manager.should_flush(size)))
+    }
+
+    //reports the current size of the buffer and the immutable buffers to the write buffer
+    //manager
+    fn charge_buffer(&mut self) {
+        let size = self.buffer.approximate_size()
+            + self
+                .immutable_buffers
+                .iter()
+                .map(|immutable| immutable.memtable.approximate_size())
+                .sum::<usize>();
+        if let Some(manager) = self.write_buffer_manager.as_ref() {
+            if size > self.buffer_reserved {
+                manager.reserve(size - self.buffer_reserved);
+            } else {
+                manager.free(self.buffer_reserved - size);
+            }
+        }
+        self.buffer_reserved = size;
+    }
+
+    //every run of the tree, including the fragments of guards
+    fn runs_mut(&mut self) -> impl Iterator<Item = &mut run::Run> {
+        self.levels.iter_mut().flat_map(|level| {
+            level.runs.iter_mut().chain(
+                level
+                    .guards
+                    .iter_mut()
+                    .flat_map(|guard| guard.runs.iter_mut()),
+            )
+        })
+    }
+
+    /// Compresses the data blocks of runs written to `level` with `compressor`, e.g. a fast
+    /// codec for level 0 and a stronger one for the bottom level. Runs already written keep
+    /// their codec, which is recorded per block.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::compress::Lz;
+    /// use lsm_kv::lsm::LSMTree;
+    /// use std::sync::Arc;
+    /// let mut lsm = LSMTree::new(100, 5, 10, 0.5, 4, "compress_doc".to_string());
+    /// lsm.set_level_compression(4, Arc::new(Lz::high()));
+    /// ```
+    pub fn set_level_compression(&mut self, level: usize, compressor: Arc<dyn Compressor>) {
+        self.levels[level].compressor = compressor;
+    }
+
+    /// Gives the bloom filters of all runs `bytes` of memory in total instead of
+    /// `bf_bits_per_entry` bits per entry. The budget is split over the levels the way
+    /// Monkey does: to minimize the expected number of wasted reads of a lookup, small upper
+    /// levels get more bits per entry than the large deep ones. The split follows the number
+    /// of entries in every level and is recomputed for every new run.
+    pub fn set_filter_memory_budget(&mut self, bytes: u64) {
+        self.filter_memory_budget = Some(bytes);
+    }
+
+    /// Makes new runs use blocked bloom filters, which keep all bits of a key in one 64-byte
+    /// cache line. A probe then costs one cache miss instead of one per hash function, for a
+    /// slightly higher false positive rate at the same bits per entry. Runs already written
+    /// keep their filter.
+    pub fn set_blocked_bloom_filters(&mut self, enabled: bool) {
+        self.filter_kind = if enabled {
+            FilterKind::BlockedBloom
+        } else {
+            FilterKind::Bloom
+        };
+    }
+
+    /// Sets the kind of filter runs written from now on get. `FilterKind::Xor` builds an xor
+    /// filter from the keys of a run when the run is finished, which takes about 30% less
+    /// memory than a bloom filter for the same false positive rate. The bits per entry,
+    /// fixed or from the memory budget, apply to every kind. Runs already written keep their
+    /// filter, which is read back with its kind by `load`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::filter::FilterKind;
+    /// use lsm_kv::lsm::LSMTree;
+    /// let mut lsm = LSMTree::new(100, 5, 10, 10.0, 4, "xor_filter_doc".to_string());
+    /// lsm.set_filter_kind(FilterKind::Xor);
+    /// lsm.put("hello", "world");
+    /// assert_eq!(Some("world".to_string()), lsm.get("hello"));
+    /// ```
+    pub fn set_filter_kind(&mut self, kind: FilterKind) {
+        self.filter_kind = kind;
+    }
+
+    /// Gives runs written from now on a range filter over the prefixes of their keys up to
+    /// `max_prefix_len` bytes, with `bits_per_entry` bits per prefix. `range` skips the runs
+    /// whose filter rules out the queried range, so a short range inside the key span of a
+    /// run no longer reads a block of it unless it holds keys. Keys are padded to
+    /// `data_type::KEY_SIZE` bytes, with that many bytes a short range is checked key by key.
+    /// Panics unless `max_prefix_len` is between 1 and 127 bytes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::data_type::KEY_SIZE;
+    /// use lsm_kv::lsm::LSMTree;
+    /// let mut lsm = LSMTree::new(100, 5, 10, 10.0, 4, "range_filter_doc".to_string());
+    /// lsm.set_range_filter(KEY_SIZE, 10.0);
+    /// lsm.put("100", "a");
+    /// lsm.put("200", "b");
+    /// assert!(lsm.range("101", "199").is_empty());
+    /// ```
+    pub fn set_range_filter(&mut self, max_prefix_len: usize, bits_per_entry: f32) {
+        assert!(
+            max_prefix_len > 0 && max_prefix_len < 128,
+            "the prefixes of a range filter are 1 to 127 bytes long, not {}",
+            max_prefix_len
+        );
+        self.range_filter = Some((max_prefix_len, bits_per_entry));
+    }
+
+    //bits per entry for the filter of a new run of level holding new_entries
+    fn filter_bits_per_entry(&self, level: usize, new_entries: u64) -> f32 {
+        match self.filter_memory_budget {
+            Some(_) => {
+                let mut entries: Vec<u64> = self.levels.iter().map(|l| l.num_entries()).collect();
+                entries[level] += new_entries;
+                self.filter_allocation(&entries)[level]
+            }
+            None => self.bf_bits_per_entry,
+        }
+    }
+
+    //bits per entry of every level for levels holding entries
+    fn filter_allocation(&self, entries: &[u64]) -> Vec<f32> {
+        match self.filter_memory_budget {
+            Some(bytes) => bloom_filter::monkey_bits_per_entry(entries, bytes * 8),
+            None => vec![self.bf_bits_per_entry; entries.len()],
+        }
+    }
+
+    /// Returns the number of runs and entries of every level, and the memory and measured
+    /// false positive rate of their filters.
+    pub fn stats(&self) -> stats::TreeStats {
+        let entries: Vec<u64> = self.levels.iter().map(|l| l.num_entries()).collect();
+        let bits_per_entry = self.filter_allocation(&entries);
+        let levels = self
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                let negatives: u64 = level.all_runs().map(|r| r.filter_negatives).sum();
+                let false_positives: u64 = level.all_runs().map(|r| r.filter_false_positives).sum();
+                stats::LevelStats {
+                    num_runs: level.all_runs().count(),
+                    num_entries: entries[i],
+                    filter_bits_per_entry: bits_per_entry[i],
+                    filter_bits: level.all_runs().map(|r| r.filter.num_bits()).sum(),
+                    false_positive_rate: if negatives + false_positives > 0 {
+                        false_positives as f64 / (negatives + false_positives) as f64
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+        stats::TreeStats { levels }
+    }
+
+    //an empty run of level, which is written to file
+    fn new_run(&self, max_size: u64, level: usize, file: PathBuf) -> run::Run {
+        let bits_per_entry = self.filter_bits_per_entry(level, max_size);
+        let mut run = run::Run::from(max_size, bits_per_entry, level, file);
+        run.filter = filter::new_filter(self.filter_kind, max_size.max(1), bits_per_entry);
+        run.range_filter = self
+            .range_filter
+            .map(|(prefix_len, bits)| RangeFilter::new(max_size.max(1), prefix_len, bits));
+        run.verify_checksums = self.verify_checksums;
+        run.compressor = self.levels[level].compressor.clone();
+        run.block_cache = self.block_cache.clone();
+        run.table_cache = self.table_cache.clone();
+        run.io_mode = self.io_mode;
+        run
+    }
+
+    fn open_run(&self, level: usize, file: PathBuf) -> io::Result<run::Run> {
+        let mut run = run::Run::open(level, file)?;
+        run.verify_checksums = self.verify_checksums;
+        run.compressor = self.levels[level].compressor.clone();
+        run.block_cache = self.block_cache.clone();
+        run.table_cache = self.table_cache.clone();
+        run.set_io_mode(self.io_mode);
+        Ok(run)
+    }
+
+    /// Stores the runs of `level` under `root` (as root/tree_name/level/) instead of /tmp,
+    /// e.g. to keep the upper levels on a fast NVMe directory and the deeper levels on a
+    /// capacity directory. `merge_down` writes its output into the path of the target level.
+    ///
+    /// Must be called before the first `put` or `load`.
+    pub fn set_level_path(&mut self, level: usize, root: &str) -> io::Result<()> {
+        assert!(self.levels[level].runs.is_empty() && self.levels[level].num_fragments() == 0);
+        let path = Path::new(root)
+            .join(&self.tree_name)
+            .join(level.to_string());
+        fs::create_dir_all(&path)?;
+        self.levels[level].path = path;
+        Ok(())
+    }
+
+    /// Runs that were read from disk `threshold` times are moved from a cold level path
+    /// to the path of level 0, the hot tier. They stay in their level. A read that fails to
+    /// move its run returns the error, and the run stays readable where it was.
+    pub fn set_promotion_threshold(&mut self, threshold: u64) {
+        self.promotion_threshold = Some(threshold);
+    }
+
+    //directory promoted runs of level are moved to, it mirrors the layout under level 0's root
+    fn hot_path(&self, level: usize) -> PathBuf {
+        match self.levels[0].path.parent() {
+            Some(tree_dir) => tree_dir.join(level.to_string()),
+            None => self.levels[level].path.clone(),
+        }
+    }
+
+    //level of the run_id-th run, in the order used by get_run
+    fn run_level(&self, mut run_id: usize) -> usize {
+        for (depth, level) in self.levels.iter().enumerate() {
+            if run_id < level.runs.len() {
+                return depth;
+            }
+            run_id -= level.runs.len();
+        }
+        self.levels.len() - 1
+    }
+
+    /// Switches the tree to a fragmented LSM (FLSM, see PebblesDB). Every level below
+    /// level 0 is partitioned by guard keys picked at random from the inserted keys, and
+    /// compaction only appends fragments into the guards of the next level instead of
+    /// rewriting the data already there.
+    ///
+    /// Must be called before the first `put` or `load`.
+    pub fn enable_flsm(&mut self) {
+        assert!(self.num_runs() == 0 && self.buffer.is_empty());
+        for level in self.levels.iter_mut().skip(1) {
+            //the sentinel guard covers every key smaller than the first real guard
+            level.guards.push(level::Guard::new(KeyT::new()));
+        }
+        self.flsm = true;
+    }
+
+    /// Enables key-value separation (see WiscKey). Values longer than `threshold` bytes
+    /// are appended to a value log under the tree directory and the tree only stores a
+    /// pointer to them, so they are no longer rewritten by every `merge_down`. Values
+    /// longer than `VALUE_SIZE` can only be stored this way.
+    ///
+    /// Must be called before the first `put`, and before `load` when reopening a tree.
+    pub fn enable_value_log(&mut self, threshold: usize) -> io::Result<()> {
+        assert!(threshold <= data_type::VALUE_SIZE);
+        let dir = PathBuf::from(format!("/tmp/{}/vlog/", self.tree_name));
+        self.value_log = Some(vlog::ValueLog::open(dir, threshold)?);
+        Ok(())
+    }
+
+    /// Reclaims the space of the oldest sealed value log file. Values that are still
+    /// referenced by the tree are appended to the head of the log and their pointers
+    /// updated before the file is removed, and the buffer holding the new pointers is
+    /// flushed to level 0 first. Returns the number of values moved.
+    pub fn gc_value_log(&mut self) -> io::Result<usize> {
+        let (oldest, records) = match self.value_log.as_ref() {
+            Some(value_log) => match value_log.sealed_files()?.first() {
+                Some(oldest) => (*oldest, value_log.records(*oldest)?),
+                None => return Ok(0),
+            },
+            None => return Ok(0),
+        };
+        let mut moved: usize = 0;
+        for (key, pointer) in records {
+            //a record is live only if the tree still points at this exact copy
+            let live = match self.get_raw(&key)? {
+                Some(slot) => vlog::ValuePointer::decode(&slot) == Some(pointer),
+                None => false,
+            };
+            if !live {
+                continue;
+            }
+            let value_log = self.value_log.as_mut().unwrap();
+            let value = value_log.read(&pointer)?;
+            let new_pointer = value_log.append(&key, &value)?;
+            self.put_value(key, new_pointer.encode());
+            moved += 1;
+        }
+        if moved > 0 {
+            //the new pointers must reach a run before the only other copy of the values goes
+            self.value_log.as_ref().unwrap().sync()?;
+            self.flush_buffer();
+        }
+        self.value_log.as_ref().unwrap().remove_file(oldest)?;
+        Ok(moved)
+    }
+
+    //A key becomes a guard of level i with probability 1 / (buf * fanout^(depth - 1 - i)),
+    //so deeper levels get more guards. Every guard of level i is also a guard of the deeper levels.
+    fn pick_guard(&mut self, key: &KeyT) {
+        //guards are persisted, so the hash must not change between builds
+        let hash = bloom_filter::BloomFilter::hash_key(key);
+        let buf_size = self.levels[0].max_run_size as u64;
+        let mut picked = false;
+        for current in 1..self.levels.len() {
+            let mut modulus = max(1, buf_size);
+            for _ in current..self.levels.len() - 1 {
+                modulus = modulus.saturating_mul(self.levels[current].max_runs as u64);
+            }
+            if hash.is_multiple_of(modulus) {
+                let level = &mut self.levels[current];
+                level.uncommitted_guards.insert(key.clone());
+                level.commit_guards();
+                picked = true;
+            }
+        }
+        if picked {
+            self.persist_guards();
         }
     }
 
@@ -129,14 +886,24 @@ impl LSMTree {
 
     //compact level i data to level i+1
     fn merge_down(&mut self, current: usize) {
+        if self.levels[current].remaining() > 0 {
+            //no need for compaction and merge down
+            return;
+        }
+        self.compact_level(current);
+    }
+
+    //merges level current into the next level whether current is full or not
+    fn compact_level(&mut self, current: usize) {
+        if self.flsm {
+            self.merge_down_guarded(current);
+            return;
+        }
         let mut merge_ctx: merge::MergeContextT = merge::MergeContextT::new();
         let mut entry: EntryT;
         let next: usize;
         //assert!(current >= self.levels.iter());
-        if self.levels[current].remaining() > 0 {
-            //no need for compaction and merge down
-            return;
-        } else if current == self.levels.len() - 1 {
+        if current == self.levels.len() - 1 {
             //can not merge down anymore
             println!("No more space in tree");
             return;
@@ -162,15 +929,12 @@ impl LSMTree {
             //add all entries in current levels for merging
             merge_ctx.add(run.map_read_default(), run.size as usize);
         }
-        let size = self.levels[next].max_run_size as u64;
+        let entries: u64 = self.levels[current].runs.iter().map(|r| r.size).sum();
+        let size = max(self.levels[next].max_run_size as u64, entries);
         let id = self.levels[next].runs.len();
-        self.levels[next].runs.push_front(run::Run::new(
-            size,
-            self.bf_bits_per_entry,
-            &self.tree_name,
-            next,
-            id,
-        ));
+        let file = self.levels[next].run_file(id);
+        let run = self.new_run(size, next, file);
+        self.levels[next].runs.push_front(run);
         //start writing back this compacted run in next level to a new file on disk
         self.levels[next].runs[0].map_write();
         //merge_ctx.print();
@@ -186,169 +950,629 @@ impl LSMTree {
         //finish writing back for compacted run
 
         //unmap the old runs and clear these files
-        for run in self.levels[current].runs.iter_mut() {
-            run.unmap();
+        let old_runs: Vec<run::Run> = self.levels[current].runs.drain(..).collect();
+        self.remove_runs(old_runs);
+    }
+
+    //FLSM compaction of level i into level i+1. The merged data of level i is split by the
+    //guards of level i+1 and every piece is appended as a new fragment of its guard.
+    fn merge_down_guarded(&mut self, current: usize) {
+        let mut merge_ctx: merge::MergeContextT = merge::MergeContextT::new();
+        if current == self.levels.len() - 1 {
+            //nothing below, so merge the fragments of the full guards in place
+            self.compact_guards_in_place(current);
+            return;
+        }
+        let next = current + 1;
+        if self.levels[next].remaining() == 0 {
+            self.merge_down_guarded(next);
+            assert!(self.levels[next].remaining() > 0)
         }
-        self.levels[current].runs.clear();
+
+        let mut old_runs: Vec<run::Run> = self.levels[current].runs.drain(..).collect();
+        for guard in self.levels[current].guards.iter_mut() {
+            old_runs.extend(guard.runs.drain(..));
+        }
+        for run in old_runs.iter_mut() {
+            merge_ctx.add(run.map_read_default(), run.size as usize);
+        }
+        //older versions may still live in other fragments of the next level, so tombstones stay
+        self.write_fragments(next, &mut merge_ctx, false);
+
+        //level current is empty now, so pending guards can be added without splitting data
+        if self.levels[current].is_guarded() {
+            self.levels[current].commit_guards();
+        }
+        //the manifest lists the new fragments before the old ones go
+        self.persist_guards();
+        self.remove_runs(old_runs);
     }
 
-    fn fill_str_with_witespace(&self, input: &str, length: usize) -> Vec<u8> {
-        let mut res = vec![' ' as u8; length - input.len()];
-        res.extend(input.as_bytes().to_vec());
-        //assert_eq!(res.len(), length);
-        res
+    fn compact_guards_in_place(&mut self, current: usize) {
+        let max_runs = self.levels[current].max_runs;
+        let full_guards: Vec<KeyT> = self.levels[current]
+            .guards
+            .iter()
+            .filter(|g| g.runs.len() >= max_runs)
+            .map(|g| g.key.clone())
+            .collect();
+        for guard_key in full_guards {
+            let mut merge_ctx: merge::MergeContextT = merge::MergeContextT::new();
+            let index = self.levels[current].guard_index(&guard_key);
+            let mut old_runs: Vec<run::Run> =
+                self.levels[current].guards[index].runs.drain(..).collect();
+            for run in old_runs.iter_mut() {
+                merge_ctx.add(run.map_read_default(), run.size as usize);
+            }
+            //the guard is empty now, so it can be split by the pending guards
+            self.levels[current].commit_guards();
+            //every version of these keys is in this merge, so tombstones can be dropped
+            self.write_fragments(current, &mut merge_ctx, true);
+            self.persist_guards();
+            self.remove_runs(old_runs);
+        }
     }
 
-    fn vec_u8_to_str(&self, input: &Vec<u8>) -> String {
-        let res: String = str::from_utf8(input).unwrap().trim().to_owned();
-        res
+    fn write_fragments(
+        &mut self,
+        level: usize,
+        merge_ctx: &mut merge::MergeContextT,
+        drop_tombstones: bool,
+    ) {
+        let mut fragment: Vec<EntryT> = Vec::new();
+        let mut guard: usize = 0;
+        while !merge_ctx.done() {
+            let entry = merge_ctx.next();
+            if drop_tombstones && entry.value == TOMBSTONE.as_bytes() {
+                continue;
+            }
+            let index = self.levels[level].guard_index(&entry.key);
+            if index != guard && !fragment.is_empty() {
+                self.write_fragment(level, guard, &fragment);
+                fragment.clear();
+            }
+            guard = index;
+            fragment.push(entry);
+        }
+        if !fragment.is_empty() {
+            self.write_fragment(level, guard, &fragment);
+        }
     }
 
-    pub fn put(&mut self, key_str: &str, value_str: &str) -> bool {
-        let key = self.fill_str_with_witespace(key_str, data_type::KEY_SIZE);
-        let value = self.fill_str_with_witespace(value_str, data_type::VALUE_SIZE);
-        if self.buffer.full() == false {
-            //put to buffer success
-            self.buffer.put(key, value);
-            return true;
-        } else {
-            /*
-             * If the buffer is full, flush level 0 if necessary
-             * to create space
-             */
-            //println!("start merge");
-            self.merge_down(0);
-
-            /*
-             * Flush the buffer to level 0.
-             */
-            let size = self.levels[0].max_run_size as u64;
-            let id = self.levels[0].runs.len();
-            self.levels[0].runs.push_front(run::Run::new(
-                size,
-                self.bf_bits_per_entry,
-                &self.tree_name,
-                0,
-                id,
-            ));
-            self.levels[0].runs[0].map_write();
+    fn write_fragment(&mut self, level: usize, guard: usize, entries: &[EntryT]) {
+        let id = self.levels[level].next_run_id;
+        self.levels[level].next_run_id += 1;
+        let mut fragment =
+            self.new_run(entries.len() as u64, level, self.levels[level].run_file(id));
+        fragment.map_write();
+        for entry in entries {
+            fragment.put(entry);
+        }
+        fragment.unmap();
+        self.levels[level].guards[guard].runs.push_front(fragment);
+    }
 
-            for entry_in_buf in self.buffer.entries.iter() {
-                self.levels[0].runs[0].put(&entry_in_buf);
+    fn remove_runs(&mut self, runs: Vec<run::Run>) {
+        for mut run in runs {
+            run.unmap();
+            if let Some(cache) = self.block_cache.as_ref() {
+                cache.erase_run(run.cache_id);
             }
-            self.levels[0].runs[0].unmap();
-
-            //buffer already written to levels.front().runs.front(). We can clear it now for inserting new entry.
-            self.buffer.empty();
-            self.buffer.put(key, value);
-            true
+            let _ = fs::remove_file(&run.tmp_file);
         }
     }
 
-    pub fn get(&mut self, key_str: &str) -> Option<String> {
-        let key = self.fill_str_with_witespace(key_str, data_type::KEY_SIZE);
-        let res: String;
-        //read from buffer first. then from level 0 to max_level. return first match entry.
-        let mut latest_val: ValueT = ValueT::new();
-        let mut latest_run: i32 = -1;
-        //multi threading searching on multiple Runs is not available for now
-        match self.buffer.get(&key) {
-            Some(v) => {
-                //found in buffer, return the result;
-                res = self.vec_u8_to_str(&v);
-                if res != TOMBSTONE.to_string() {
-                    return Some(res);
-                } else {
-                    return None;
+    fn get_from_guards(&mut self, key: &KeyT) -> error::Result<Option<ValueT>> {
+        for depth in 0..self.levels.len() {
+            if !self.levels[depth].is_guarded() {
+                continue;
+            }
+            let hot_path = self.hot_path(depth);
+            let threshold = self.promotion_threshold;
+            //only the guard covering the key can hold it
+            let index = self.levels[depth].guard_index(key);
+            for run in self.levels[depth].guards[index].runs.iter_mut() {
+                if let Some(val) = run.try_get(key)? {
+                    promote_if_hot(run, &hot_path, threshold)?;
+                    return Ok(Some(val));
                 }
             }
-            _ => {
-                //not found in buffer, start searching in vector<Level>
-                //println!("key {} not found in buffer", str::from_utf8(&key).unwrap());
-                for current_run in 0..self.num_runs() as u64 {
-                    let current_val: ValueT;
-                    // println!(
-                    //     "search in Run {}, latest_run is {}",
-                    //     current_run, latest_run
-                    // );
-                    //let mut run: run::Run;
-                    if latest_run >= 0 || (self.get_run(current_run as usize).is_none()) {
-                        // Stop search if we discovered a key in another run, or
-                        // if there are no more runs to search
-                        break;
-                    } else {
-                        let run = self.get_run(current_run as usize).unwrap();
-                        if run.get(&key).is_none() {
-                            // Couldn't find the key in the current run, so we need
-                            // to keep searching.
-                            //search();
-                            // println!(
-                            //     "key {} not found in Run {}",
-                            //     str::from_utf8(&key).unwrap(),
-                            //     current_run
-                            // );
-                        } else {
-                            // Update val if the run is more recent than the
-                            // last, then stop searching since there's no need
-                            // to search later runs.
-                            current_val = run.get(&key).unwrap();
+        }
+        Ok(None)
+    }
+
+    fn fill_str_with_witespace(&self, input: &str, length: usize) -> Vec<u8> {
+        data_type::fill_str_with_witespace(input, length)
+    }
+
+    fn vec_u8_to_str(&self, input: &Vec<u8>) -> String {
+        data_type::vec_u8_to_str(input)
+    }
+
+    //reads the value behind a value log pointer, inline values are returned as they are
+    fn value_to_str(&self, input: &ValueT) -> String {
+        match (vlog::ValuePointer::decode(input), self.value_log.as_ref()) {
+            (Some(pointer), Some(value_log)) => match value_log.read(&pointer) {
+                Ok(value) => String::from_utf8(value).unwrap(),
+                Err(e) => panic!("could not read value log due to {}", e),
+            },
+            _ => self.vec_u8_to_str(input),
+        }
+    }
+
+    pub fn put(&mut self, key_str: &str, value_str: &str) -> bool {
+        let (key, value) = self.encode_entry(key_str, value_str);
+        self.put_value(key, value)
+    }
+
+    /// Puts every entry, a later entry of the same key wins. With a `SkipList` memtable the
+    /// entries are inserted by up to `num_threads` threads at once, with the other memtables
+    /// they are put one after the other.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::memtable::MemTableKind;
+    /// let mut lsm = LSMTree::new(100, 5, 10, 0.5, 4, "put_batch_doc".to_string());
+    /// lsm.set_memtable(MemTableKind::SkipList);
+    /// lsm.put_batch(&[("a", "1"), ("b", "2"), ("a", "3")]);
+    /// assert_eq!(Some("3".to_string()), lsm.get("a"));
+    /// assert_eq!(Some("2".to_string()), lsm.get("b"));
+    /// ```
+    pub fn put_batch(&mut self, entries: &[(&str, &str)]) -> bool {
+        let encoded: Vec<(KeyT, ValueT)> = entries
+            .iter()
+            .map(|(key, value)| self.encode_entry(key, value))
+            .collect();
+        if self.buffer.as_concurrent().is_none() {
+            for (key, value) in encoded {
+                self.put_value(key, value);
+            }
+            return true;
+        }
+        //inserts of the same key race, so only the last one is inserted
+        let mut last = HashMap::new();
+        for (i, (key, _)) in encoded.iter().enumerate() {
+            last.insert(key.clone(), i);
+        }
+        let mut pending: Vec<(KeyT, ValueT)> = encoded
+            .into_iter()
+            .enumerate()
+            .filter(|(i, (key, _))| last[key] == *i)
+            .map(|(_, entry)| entry)
+            .collect();
+        if self.flsm {
+            for (key, _) in pending.iter() {
+                self.pick_guard(key);
+            }
+        }
+        while !pending.is_empty() {
+            self.install_flushes();
+            if self.buffer_full() {
+                self.make_room();
+            }
+            //takes the entries that fit into the buffer, at least one
+            let room = self
+                .buffer_max_bytes
+                .saturating_sub(self.buffer.approximate_size());
+            let mut bytes = 0;
+            let mut count = 0;
+            for (key, value) in pending.iter() {
+                let charge = memtable::entry_charge(key, value);
+                if count > 0 && bytes + charge > room {
+                    break;
+                }
+                bytes += charge;
+                count += 1;
+            }
+            let rest = pending.split_off(count);
+            self.throttle(pending.iter().map(|(k, v)| k.len() + v.len()).sum());
+            let memtable = self.buffer.as_concurrent().unwrap();
+            let per_thread = pending.len().div_ceil(self.worker_pool.max_count());
+            thread::scope(|scope| {
+                while !pending.is_empty() {
+                    let part = pending.split_off(pending.len().saturating_sub(per_thread));
+                    scope.spawn(move || {
+                        for (key, value) in part {
+                            memtable.insert(key, value);
+                        }
+                    });
+                }
+            });
+            self.charge_buffer();
+            pending = rest;
+        }
+        true
+    }
+
+    //pads the key and the value, or moves a large value to the value log and returns its
+    //pointer instead
+    fn encode_entry(&mut self, key_str: &str, value_str: &str) -> (KeyT, ValueT) {
+        let key = self.fill_str_with_witespace(key_str, data_type::KEY_SIZE);
+        let value = match self.value_log.as_mut() {
+            //tombstones always stay in the tree
+            Some(value_log) if value_str.len() > value_log.threshold && value_str != TOMBSTONE => {
+                match value_log.append(&key, value_str.as_bytes()) {
+                    Ok(pointer) => pointer.encode(),
+                    Err(e) => panic!("could not append to value log due to {}", e),
+                }
+            }
+            _ => self.fill_str_with_witespace(value_str, data_type::VALUE_SIZE),
+        };
+        (key, value)
+    }
+
+    //inserts an already encoded key and value slot
+    fn put_value(&mut self, key: KeyT, value: ValueT) -> bool {
+        if self.flsm {
+            self.pick_guard(&key);
+        }
+        self.install_flushes();
+        self.throttle(key.len() + value.len());
+        if self.buffer_full() {
+            self.make_room();
+        }
+        self.buffer.put(key, value);
+        self.charge_buffer();
+        true
+    }
+
+    //empties the full buffer
+    fn make_room(&mut self) {
+        if self.max_write_buffer_number > 1 {
+            //writes go on into a fresh buffer while the full one is flushed
+            self.schedule_flush();
+        } else {
+            self.flush_buffer();
+        }
+    }
+
+    pub fn get(&mut self, key_str: &str) -> Option<String> {
+        match self.try_get(key_str) {
+            Ok(res) => res,
+            Err(e) => panic!("get failed due to {}", e),
+        }
+    }
+
+    /// Like `get`, but returns `Error::Corruption` if a block read from disk is damaged.
+    pub fn try_get(&mut self, key_str: &str) -> error::Result<Option<String>> {
+        let key = self.fill_str_with_witespace(key_str, data_type::KEY_SIZE);
+        match self.get_raw(&key)? {
+            Some(v) => {
+                let res = self.value_to_str(&v);
+                if res != TOMBSTONE {
+                    Ok(Some(res))
+                } else {
+                    Ok(None)
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    //returns the newest value slot stored for key, which may be a tombstone or a value log pointer
+    fn get_raw(&mut self, key: &KeyT) -> error::Result<Option<ValueT>> {
+        //read from buffer first. then from level 0 to max_level. return first match entry.
+        let mut latest_val: ValueT = ValueT::new();
+        let mut latest_run: i32 = -1;
+        match self.memtables_get(key) {
+            Some(v) => {
+                //found in buffer, return the result;
+                return Ok(Some(v));
+            }
+            _ if self.parallel_get => return self.get_parallel(key),
+            _ => {
+                //not found in buffer, start searching in vector<Level>
+                //println!("key {} not found in buffer", str::from_utf8(&key).unwrap());
+                for current_run in 0..self.num_runs() as u64 {
+                    let current_val: ValueT;
+                    // println!(
+                    //     "search in Run {}, latest_run is {}",
+                    //     current_run, latest_run
+                    // );
+                    //let mut run: run::Run;
+                    if latest_run >= 0 || (self.get_run(current_run as usize).is_none()) {
+                        // Stop search if we discovered a key in another run, or
+                        // if there are no more runs to search
+                        break;
+                    } else {
+                        let hot_path = self.hot_path(self.run_level(current_run as usize));
+                        let threshold = self.promotion_threshold;
+                        let run = self.get_run(current_run as usize).unwrap();
+                        if let Some(found) = run.try_get(key)? {
+                            // Update val if the run is more recent than the
+                            // last, then stop searching since there's no need
+                            // to search later runs.
+                            current_val = found;
+                            promote_if_hot(run, &hot_path, threshold)?;
                             if latest_run < 0 || current_run < latest_run as u64 {
                                 latest_run = current_run as i32;
                                 latest_val = current_val;
                             }
                             break; //find the newest entry and break the for loop.
                         }
+                        // Couldn't find the key in the current run, so we need
+                        // to keep searching.
+                    }
+                }
+
+                if latest_run < 0 {
+                    //in FLSM mode only level 0 keeps plain runs, the rest is in guards
+                    if let Some(val) = self.get_from_guards(key)? {
+                        latest_run = self.num_runs() as i32;
+                        latest_val = val;
                     }
                 }
 
                 if latest_run >= 0 {
-                    res = self.vec_u8_to_str(&latest_val);
-                    if res != TOMBSTONE.to_string() {
-                        return Some(res);
+                    return Ok(Some(latest_val));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Looks up all `keys` at once and returns their values in the order of `keys`. The
+    /// buffer is probed once, and every run checks its bloom filter for all keys not found
+    /// yet and reads each block holding some of them only once.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// let mut lsm = LSMTree::new(100, 5, 10, 0.5, 4, "multi_get_doc".to_string());
+    /// lsm.put("a", "1");
+    /// lsm.put("b", "2");
+    /// assert_eq!(
+    ///     vec![Some("2".to_string()), None, Some("1".to_string())],
+    ///     lsm.multi_get(&["b", "c", "a"])
+    /// );
+    /// ```
+    pub fn multi_get(&mut self, keys: &[&str]) -> Vec<Option<String>> {
+        match self.try_multi_get(keys) {
+            Ok(res) => res,
+            Err(e) => panic!("multi_get failed due to {}", e),
+        }
+    }
+
+    /// Like `multi_get`, but returns `Error::Corruption` if a block read from disk is damaged.
+    pub fn try_multi_get(&mut self, keys: &[&str]) -> error::Result<Vec<Option<String>>> {
+        let keys: Vec<KeyT> = keys
+            .iter()
+            .map(|key| self.fill_str_with_witespace(key, data_type::KEY_SIZE))
+            .collect();
+        //every distinct key is looked up once, in key order
+        let mut sorted = keys.clone();
+        sorted.sort();
+        sorted.dedup();
+        let mut found = self.buffer.multi_get(&sorted);
+        for immutable in self.immutable_buffers.iter() {
+            let values = immutable.memtable.multi_get(&sorted);
+            for (slot, value) in found.iter_mut().zip(values) {
+                if slot.is_none() {
+                    *slot = value;
+                }
+            }
+        }
+        let threshold = self.promotion_threshold;
+
+        //plain runs of all levels first, newest first like get, then the fragments of the
+        //guard covering each key. The runs of a level are searched together.
+        let num_levels = self.levels.len();
+        for group in 0..2 * num_levels {
+            let (depth, guarded) = (group % num_levels, group >= num_levels);
+            if guarded && !self.levels[depth].is_guarded() {
+                continue;
+            }
+            let hot_path = self.hot_path(depth);
+            let level = &mut self.levels[depth];
+            //every run of the level with the keys it may hold
+            let mut runs: Vec<(&mut run::Run, Vec<usize>)> = Vec::new();
+            if guarded {
+                let mut by_guard: Vec<Vec<usize>> = vec![Vec::new(); level.guards.len()];
+                for (i, key) in sorted.iter().enumerate() {
+                    if found[i].is_none() {
+                        by_guard[level.guard_index(key)].push(i);
+                    }
+                }
+                for (guard, candidates) in level.guards.iter_mut().zip(by_guard) {
+                    runs.extend(guard.runs.iter_mut().map(|run| (run, candidates.clone())));
+                }
+            } else {
+                let pending: Vec<usize> =
+                    (0..sorted.len()).filter(|i| found[*i].is_none()).collect();
+                runs.extend(level.runs.iter_mut().map(|run| (run, pending.clone())));
+            }
+            let hits = match self.batch_reader.as_mut() {
+                Some(reader) => multi_get_batch(&mut runs, &sorted, &mut found, reader)?,
+                None => {
+                    let mut hits: Vec<bool> = Vec::with_capacity(runs.len());
+                    for (run, candidates) in runs.iter_mut() {
+                        hits.push(multi_get_run(run, &sorted, candidates, &mut found)?);
                     }
+                    hits
+                }
+            };
+            for ((run, _), hit) in runs.into_iter().zip(hits) {
+                if hit {
+                    promote_if_hot(run, &hot_path, threshold)?;
                 }
             }
         }
-        None
+
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let value = found[sorted.binary_search(key).unwrap()].as_ref()?;
+                let res = self.value_to_str(value);
+                if res != TOMBSTONE {
+                    Some(res)
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    //reads the blocks of all runs that may hold key at once on the worker pool, the newest
+    //run that has the key wins
+    fn get_parallel(&mut self, key: &KeyT) -> error::Result<Option<ValueT>> {
+        //candidates in the order get_raw searches the runs
+        let mut probes: Vec<(RunPosition, run::BlockRead)> = Vec::new();
+        for depth in 0..self.levels.len() {
+            for (i, run) in self.levels[depth].runs.iter_mut().enumerate() {
+                if let Some(read) = run.probe(key) {
+                    probes.push(((depth, None, i), read));
+                }
+            }
+        }
+        for depth in 0..self.levels.len() {
+            if !self.levels[depth].is_guarded() {
+                continue;
+            }
+            let guard = self.levels[depth].guard_index(key);
+            for (i, run) in self.levels[depth].guards[guard].runs.iter_mut().enumerate() {
+                if let Some(read) = run.probe(key) {
+                    probes.push(((depth, Some(guard), i), read));
+                }
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        if probes.len() == 1 {
+            //nothing to overlap with
+            let _ = sender.send((0, probes[0].1.read().map(|block| block.get(key))));
+        } else {
+            for (position, (_, read)) in probes.iter().enumerate() {
+                let sender = sender.clone();
+                let read = read.clone();
+                let key = key.clone();
+                self.worker_pool.execute(move || {
+                    let _ = sender.send((position, read.read().map(|block| block.get(&key))));
+                });
+            }
+        }
+        //a probe lost to a panic ends the waits below instead of blocking them
+        drop(sender);
+
+        //a hit is final once every newer candidate came back without the key
+        let mut results: Vec<Option<error::Result<Option<ValueT>>>> =
+            probes.iter().map(|_| None).collect();
+        let mut received = 0;
+        let mut newest = 0;
+        let found = loop {
+            if newest == probes.len() {
+                break Ok(None);
+            }
+            match results[newest].take() {
+                None => {
+                    let (position, result) = receiver
+                        .recv()
+                        .expect("a probe of get was dropped by the worker pool");
+                    results[position] = Some(result);
+                    received += 1;
+                }
+                Some(Ok(None)) => {
+                    //the bloom filter let the key through, but the run does not have it
+                    self.run_at(probes[newest].0).filter_false_positives += 1;
+                    newest += 1;
+                }
+                Some(Ok(Some(value))) => break Ok(Some((probes[newest].0, value))),
+                Some(Err(e)) => break Err(e),
+            }
+        };
+        //older probes still running hold the cache id of their run. A later compaction may
+        //remove the run, and a probe finishing after that would cache its file again.
+        for _ in received..probes.len() {
+            let _ = receiver.recv();
+        }
+
+        match found? {
+            Some((position, value)) => {
+                let hot_path = self.hot_path(position.0);
+                let threshold = self.promotion_threshold;
+                promote_if_hot(self.run_at(position), &hot_path, threshold)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn run_at(&mut self, (depth, guard, i): RunPosition) -> &mut run::Run {
+        let level = &mut self.levels[depth];
+        match guard {
+            Some(guard) => &mut level.guards[guard].runs[i],
+            None => &mut level.runs[i],
+        }
     }
 
     pub fn range(&mut self, start_str: &str, end_str: &str) -> Vec<String> {
+        match self.try_range(start_str, end_str) {
+            Ok(res) => res,
+            Err(e) => panic!("range failed due to {}", e),
+        }
+    }
+
+    /// Like `range`, but returns `Error::Corruption` if a block read from disk is damaged.
+    pub fn try_range(&mut self, start_str: &str, end_str: &str) -> error::Result<Vec<String>> {
         let start = self.fill_str_with_witespace(start_str, data_type::KEY_SIZE);
         let end = self.fill_str_with_witespace(end_str, data_type::KEY_SIZE);
         let mut buffer_range: Vec<String> = Vec::new(); //this is return value list
         if end < start {
             //invalid input
-            return buffer_range;
+            return Ok(buffer_range);
         }
-        let mut ranges: HashMap<usize, Vec<EntryT>> = HashMap::new(); //record candidates in each level.
+        //record candidates from newest to oldest, the merge context prefers earlier ones.
+        let mut ranges: Vec<Vec<EntryT>> = Vec::new();
         let mut merge_ctx = merge::MergeContextT::new();
         let mut entry: EntryT;
         //search in buffer and record result
-        ranges.insert(0, self.buffer.range(&start, &end));
+        ranges.push(self.buffer.range(&start, &end));
+        for immutable in self.immutable_buffers.iter() {
+            ranges.push(immutable.memtable.range(&start, &end));
+        }
 
-        for current_run in 0..self.depth {
-            match self.get_run(current_run as usize) {
-                Some(r) => {
-                    //start and end are used multiple times which causes "use of moved value"
-                    ranges.insert((current_run + 1) as usize, r.range(&start, &end));
+        let mut runs = range_runs(&mut self.levels, &start, &end);
+        match self.batch_reader.as_mut() {
+            Some(reader) => {
+                //readahead: the blocks of all runs are read in one batch
+                let handles: Vec<Vec<table::BlockHandle>> =
+                    runs.iter().map(|r| r.range_handles(&start, &end)).collect();
+                let reads: Vec<(&run::Run, table::BlockHandle)> = runs
+                    .iter()
+                    .zip(handles.iter())
+                    .flat_map(|(r, run_handles)| run_handles.iter().map(move |h| (&**r, *h)))
+                    .collect();
+                let mut blocks = run::read_data_blocks(&reads, reader)?.into_iter();
+                for (r, run_handles) in runs.iter_mut().zip(handles.iter()) {
+                    if !run_handles.is_empty() {
+                        r.access_count += 1;
+                    }
+                    let run_blocks: Vec<_> = blocks.by_ref().take(run_handles.len()).collect();
+                    ranges.push(run::entries_in_range(&run_blocks, &start, &end));
+                }
+            }
+            None => {
+                for r in runs.iter_mut() {
+                    ranges.push(r.try_range(&start, &end)?);
                 }
-                _ => {}
             }
         }
 
-        for kv in ranges.iter() {
-            //TODO is to_vec() a good option????
-            merge_ctx.add(kv.1.to_vec(), kv.1.len());
+        for candidates in ranges {
+            let len = candidates.len();
+            merge_ctx.add(candidates, len);
         }
         while !merge_ctx.done() {
             entry = merge_ctx.next();
-            let res = self.vec_u8_to_str(&entry.value);
+            let res = self.value_to_str(&entry.value);
             if res != TOMBSTONE.to_string() {
                 buffer_range.push(res);
             }
         }
 
-        buffer_range
+        Ok(buffer_range)
     }
 
     pub fn del(&mut self, key_str: &str) {
@@ -357,51 +1581,169 @@ impl LSMTree {
 
     pub fn load(&mut self) -> io::Result<()> {
         //TODO iterate through every level subdir in the directory "/tmp/tree_name/" and load Runs
+        if self.flsm {
+            self.load_guards()?;
+        }
         for depth in 0..self.levels.len() {
-            let level_dir_str = format!("/tmp/{}/{}/", self.tree_name, depth).to_string();
-            let level_dir: &Path = Path::new(&level_dir_str);
-            let max_size = self.levels[depth].max_run_size;
-            //visit every run and load into LSMTree vec<Level>
-            if level_dir.is_dir() {
-                let files = fs::read_dir(level_dir)?;
-                let mut entries: Vec<PathBuf> = files
-                    .filter(Result::is_ok)
-                    .map(|e| e.unwrap().path())
-                    .collect();
-                //make sure we read from Run-0.text to Run-max_runs.txt
-                entries.sort();
-                for run_file in entries {
-                    let run_file_entry = run_file;
-                    //println!("cur file path is {:?}", run_file_entry);
-                    let mut cur_run = run::Run::from(
-                        max_size as u64,
-                        self.bf_bits_per_entry,
-                        depth,
-                        run_file_entry,
-                    );
-                    //reconstruct the bloom filter for cur_run. information like max_key, fence_pointers could be stored for easier reconstructions
-                    let mut counter = 0;
-                    for key in cur_run.get_keys().iter() {
-                        //println!("{} set", str::from_utf8(key).unwrap());
-                        if counter % (page_size::get() / ENTRY_SIZE) as u64 == 0 {
-                            cur_run.fence_pointers.push(key.clone());
-                        }
-                        cur_run.bloom_filter.set(key);
-                        counter += 1;
-                        cur_run.max_key = max(key.clone(), cur_run.max_key.clone());
-                    }
-                    cur_run.size = counter;
-                    self.levels[depth].runs.push_back(cur_run);
+            if self.levels[depth].is_guarded() {
+                //fragments are listed in the guard manifest
+                continue;
+            }
+            //runs of a level live in its own path, or in the hot path once promoted
+            let mut level_dirs: Vec<PathBuf> = vec![self.levels[depth].path.clone()];
+            if self.hot_path(depth) != self.levels[depth].path {
+                level_dirs.push(self.hot_path(depth));
+            }
+            let mut entries: Vec<PathBuf> = Vec::new();
+            for level_dir in level_dirs {
+                //visit every run and load into LSMTree vec<Level>
+                if level_dir.is_dir() {
+                    let files = fs::read_dir(level_dir)?;
+                    entries.extend(files.filter(Result::is_ok).map(|e| e.unwrap().path()));
                 }
             }
+            //newer runs have larger ids and go first, from Run-max_runs.txt down to Run-0.txt
+            entries.sort_by_key(|path| std::cmp::Reverse(level::run_file_id(path)));
+            for run_file in entries {
+                let run_file_entry = run_file;
+                //println!("cur file path is {:?}", run_file_entry);
+                let cur_run = self.open_run(depth, run_file_entry)?;
+                self.levels[depth].runs.push_back(cur_run);
+            }
             //println!("cur level has {} Runs", self.levels[depth].runs.len());
         }
 
         Ok(())
     }
 
+    //The guard manifest has one line per guard: "g <level> <key> <fragment files, newest first>"
+    //and one line per uncommitted guard: "p <level> <key>". Keys are hex encoded, "-" is the empty key.
+    //Fragments are listed by file name, they are in the path of their level or in its hot path.
+    //The manifest is saved whenever the guards or their fragments change.
+    fn guard_manifest(&self) -> PathBuf {
+        PathBuf::from(format!("/tmp/{}/guards", self.tree_name))
+    }
+
+    fn save_guards(&self) -> io::Result<()> {
+        let mut manifest = String::new();
+        for (depth, level) in self.levels.iter().enumerate() {
+            for guard in level.guards.iter() {
+                manifest.push_str(&format!("g {} {}", depth, key_to_hex(&guard.key)));
+                for run in guard.runs.iter() {
+                    let name = run.tmp_file.file_name().unwrap_or_default();
+                    manifest.push_str(&format!(" {}", Path::new(name).display()));
+                }
+                manifest.push('\n');
+            }
+            for key in level.uncommitted_guards.iter() {
+                manifest.push_str(&format!("p {} {}\n", depth, key_to_hex(key)));
+            }
+        }
+        //a crash while writing leaves the old manifest in place
+        let tmp = self.guard_manifest().with_extension("tmp");
+        fs::write(&tmp, manifest)?;
+        fs::rename(&tmp, self.guard_manifest())
+    }
+
+    fn persist_guards(&self) {
+        if let Err(e) = self.save_guards() {
+            panic!("could not save guards due to {}", e);
+        }
+    }
+
+    fn load_guards(&mut self) -> io::Result<()> {
+        let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+        let manifest = match fs::read_to_string(self.guard_manifest()) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                //guards were never saved, which is only fine if no fragments were written
+                for depth in 1..self.levels.len() {
+                    for dir in [self.levels[depth].path.clone(), self.hot_path(depth)].iter() {
+                        let has_runs = match fs::read_dir(dir) {
+                            Ok(files) => files
+                                .filter_map(Result::ok)
+                                .any(|file| level::run_file_id(&file.path()).is_some()),
+                            Err(_) => false,
+                        };
+                        if has_runs {
+                            return Err(invalid(format!(
+                                "the guard manifest of {} is missing but {:?} holds fragments",
+                                self.tree_name, dir
+                            )));
+                        }
+                    }
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        for level in self.levels.iter_mut().skip(1) {
+            level.guards.clear();
+        }
+        for line in manifest.lines() {
+            let malformed = || invalid(format!("malformed guard manifest line {:?}", line));
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.len() < 3 {
+                return Err(malformed());
+            }
+            let depth: usize = tokens[1].parse().map_err(|_| malformed())?;
+            let key = key_from_hex(tokens[2]).ok_or_else(malformed)?;
+            if depth == 0 || depth >= self.levels.len() {
+                return Err(malformed());
+            }
+            match tokens[0] {
+                "p" if tokens.len() == 3 => {
+                    self.levels[depth].uncommitted_guards.insert(key);
+                    continue;
+                }
+                "g" => {}
+                _ => return Err(malformed()),
+            }
+            //guards are listed in key order, starting with the sentinel
+            let guards = &self.levels[depth].guards;
+            let in_order = match guards.last() {
+                Some(last) => last.key < key,
+                None => key.is_empty(),
+            };
+            if !in_order {
+                return Err(malformed());
+            }
+            let mut guard = level::Guard::new(key);
+            for file in tokens[3..].iter() {
+                let name = Path::new(file).file_name().ok_or_else(malformed)?;
+                let mut path = self.levels[depth].path.join(name);
+                if !path.exists() {
+                    path = self.hot_path(depth).join(name);
+                }
+                if let Some(id) = level::run_file_id(&path) {
+                    let next_id = &mut self.levels[depth].next_run_id;
+                    *next_id = max(*next_id, id + 1);
+                }
+                guard.runs.push_back(self.open_run(depth, path)?);
+            }
+            self.levels[depth].guards.push(guard);
+        }
+        if let Some(depth) = (1..self.levels.len()).find(|d| !self.levels[*d].is_guarded()) {
+            return Err(invalid(format!(
+                "the guard manifest has no guards for level {}",
+                depth
+            )));
+        }
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
+        //pending flushes would write into the removed directories
+        for immutable in self.immutable_buffers.drain(..) {
+            if let Ok(run) = immutable.flushed.recv() {
+                let _ = fs::remove_file(&run.tmp_file);
+            }
+        }
         //remove all files and clear all Runs in self.levels
+        for depth in 0..self.levels.len() {
+            let _ = fs::remove_dir_all(&self.levels[depth].path);
+            let _ = fs::remove_dir_all(self.hot_path(depth));
+        }
         if let Ok(dir) = read_dir(format!("/tmp/{}/", self.tree_name)) {
             for entry in dir {
                 if let Ok(entry) = entry {
@@ -415,35 +1757,232 @@ impl LSMTree {
             }
             self.levels.clear();
             self.buffer.empty();
+            self.charge_buffer();
+            self.value_log = None;
         }
     }
 
     pub fn close(&mut self) {
-        //save the buffer as a Run in level 0 even if it is not full.
+        self.flush_buffer();
+        if self.flsm {
+            if let Err(e) = self.save_guards() {
+                panic!("could not save guards due to {}", e);
+            }
+        }
+    }
+
+    //writes the immutable buffers and the buffer to level 0 runs, even if it is not full
+    fn flush_buffer(&mut self) {
+        self.wait_for_flushes();
         self.merge_down(0);
 
         /*
          * Flush the buffer to level 0.
          */
-        let size = self.levels[0].max_run_size as u64;
+        //entries of other than the default size make the buffer hold more or fewer
+        let size = max(self.levels[0].max_run_size, self.buffer.len()) as u64;
         let id = self.levels[0].runs.len();
-        self.levels[0].runs.push_front(run::Run::new(
-            size,
-            self.bf_bits_per_entry,
-            &self.tree_name,
-            0,
-            id,
-        ));
+        let file = self.levels[0].run_file(id);
+        let run = self.new_run(size, 0, file);
+        self.levels[0].runs.push_front(run);
         self.levels[0].runs[0].map_write();
 
-        for entry_in_buf in self.buffer.entries.iter() {
+        for entry_in_buf in self.buffer.iter() {
             self.levels[0].runs[0].put(&entry_in_buf);
         }
         self.levels[0].runs[0].unmap();
 
         //buffer already written to levels.front().runs.front(). We can clear it now for inserting new entry.
         self.buffer.empty();
+        self.charge_buffer();
+    }
+}
+
+impl Drop for LSMTree {
+    fn drop(&mut self) {
+        //runs of level 0 outlive the tree on disk, so do the flushed buffers
+        if !thread::panicking() {
+            self.wait_for_flushes();
+        }
+        //the buffer no longer counts against the shared cap
+        if let Some(manager) = self.write_buffer_manager.as_ref() {
+            manager.free(self.buffer_reserved);
+            manager.unregister();
+        }
+    }
+}
+
+//a full buffer, readable until the run it is flushed to arrives on flushed
+struct ImmutableBuffer {
+    memtable: Arc<dyn MemTable>,
+    flushed: mpsc::Receiver<run::Run>,
+}
+
+//level of a run, its guard in FLSM mode, and its position in the level or guard
+type RunPosition = (usize, Option<usize>, usize);
+
+//looks up the keys of sorted at candidates that are not found yet in run, returns whether
+//run had any of them
+fn multi_get_run(
+    run: &mut run::Run,
+    sorted: &[KeyT],
+    candidates: &[usize],
+    found: &mut [Option<ValueT>],
+) -> error::Result<bool> {
+    let pending: Vec<usize> = candidates
+        .iter()
+        .filter(|i| found[**i].is_none())
+        .cloned()
+        .collect();
+    if pending.is_empty() {
+        return Ok(false);
+    }
+    let keys: Vec<KeyT> = pending.iter().map(|i| sorted[*i].clone()).collect();
+    let mut hit = false;
+    for (i, value) in pending.into_iter().zip(run.try_multi_get(&keys, None)?) {
+        if value.is_some() {
+            found[i] = value;
+            hit = true;
+        }
+    }
+    Ok(hit)
+}
+
+//looks up the candidates of runs, newest first, reading the blocks of all runs that may hold
+//them in one batch. Returns which runs had a value that was taken.
+fn multi_get_batch(
+    runs: &mut [(&mut run::Run, Vec<usize>)],
+    sorted: &[KeyT],
+    found: &mut [Option<ValueT>],
+    reader: &mut uring::BatchReader,
+) -> error::Result<Vec<bool>> {
+    let plans: Vec<(Vec<KeyT>, run::MultiGetPlan)> = runs
+        .iter_mut()
+        .map(|(run, candidates)| {
+            let keys: Vec<KeyT> = candidates.iter().map(|i| sorted[*i].clone()).collect();
+            let plan = run.plan_multi_get(&keys);
+            (keys, plan)
+        })
+        .collect();
+    let reads: Vec<(&run::Run, table::BlockHandle)> = runs
+        .iter()
+        .zip(plans.iter())
+        .flat_map(|((run, _), (_, plan))| plan.handles.iter().map(move |h| (&**run, *h)))
+        .collect();
+    let mut blocks = run::read_data_blocks(&reads, reader)?.into_iter();
+    let mut hits: Vec<bool> = Vec::with_capacity(runs.len());
+    for ((run, candidates), (keys, plan)) in runs.iter_mut().zip(plans.iter()) {
+        let run_blocks: Vec<_> = blocks.by_ref().take(plan.handles.len()).collect();
+        let mut hit = false;
+        for (i, value) in candidates
+            .iter()
+            .zip(run.resolve_multi_get(keys, plan, &run_blocks))
+        {
+            //an older run may have a key a newer one already answered
+            if value.is_some() && found[*i].is_none() {
+                found[*i] = value;
+                hit = true;
+            }
+        }
+        hits.push(hit);
+    }
+    Ok(hits)
+}
+
+//runs that may hold keys in [start, end], newest first
+fn range_runs<'a>(
+    levels: &'a mut [level::Level],
+    start: &KeyT,
+    end: &KeyT,
+) -> Vec<&'a mut run::Run> {
+    let mut res: Vec<&mut run::Run> = Vec::new();
+    let mut guarded: Vec<&mut run::Run> = Vec::new();
+    for level in levels.iter_mut() {
+        //only the guards overlapping [start, end] are consulted
+        let guards = if level.is_guarded() {
+            level.guard_index(start)..level.guard_index(end) + 1
+        } else {
+            0..0
+        };
+        res.extend(level.runs.iter_mut());
+        for guard in level.guards[guards].iter_mut() {
+            guarded.extend(guard.runs.iter_mut());
+        }
+    }
+    //plain runs of all levels come before the fragments of guards
+    res.extend(guarded);
+    res
+}
+
+//moves a frequently read run from a cold path to the hot path of its level. On an error the
+//run stays readable at its old path.
+fn promote_if_hot(run: &mut run::Run, hot_path: &Path, threshold: Option<u64>) -> io::Result<()> {
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => return Ok(()),
+    };
+    if run.access_count < threshold || run.tmp_file.parent() == Some(hot_path) {
+        return Ok(());
+    }
+    let target = hot_path.join(run.tmp_file.file_name().unwrap());
+    fs::create_dir_all(hot_path)?;
+    //a rename moves the file at once, copying is left for paths on different file systems
+    if fs::rename(&run.tmp_file, &target).is_err() {
+        let copied = fs::copy(&run.tmp_file, &target).and_then(|_| fs::remove_file(&run.tmp_file));
+        if let Err(e) = copied {
+            //load would find the run twice, the run stays readable where it is
+            let _ = fs::remove_file(&target);
+            return Err(e);
+        }
+    }
+    //the mapping of the old file must not outlive it
+    run.table_cache.evict(run.cache_id);
+    run.tmp_file = target;
+    Ok(())
+}
+
+fn key_to_hex(key: &KeyT) -> String {
+    if key.is_empty() {
+        return "-".to_string();
     }
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn key_from_hex(hex: &str) -> Option<KeyT> {
+    if hex == "-" {
+        return Some(KeyT::new());
+    }
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+//a tree without the files a previous run of the test left under name
+#[cfg(test)]
+fn fresh_tree(
+    buf_max_entries: u64,
+    dep: u64,
+    fanout: u64,
+    bf_bits_per_entry: f32,
+    num_threads: u64,
+    name: &str,
+) -> LSMTree {
+    let new = || {
+        LSMTree::new(
+            buf_max_entries,
+            dep,
+            fanout,
+            bf_bits_per_entry,
+            num_threads,
+            name.to_string(),
+        )
+    };
+    new().clear();
+    new()
 }
 
 #[test]
@@ -475,6 +2014,732 @@ fn test_range() {
     assert_eq!(vec!["linkedin", "google"], lsm.range("amazon", "facebook"));
 }
 
+#[test]
+fn test_flsm() {
+    let test_size = 2000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "flsm_test");
+    lsm.enable_flsm();
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    for i in (0..test_size).step_by(3) {
+        lsm.del(&i.to_string());
+    }
+    assert!(lsm.levels.iter().any(|level| level.guards.len() > 1));
+    for j in 0..test_size {
+        let expected = if j % 3 == 0 {
+            None
+        } else {
+            Some(j.to_string())
+        };
+        assert_eq!(expected, lsm.get(&j.to_string()));
+    }
+    assert_eq!(vec!["1000", "1001"], lsm.range("999", "1002"));
+    lsm.close();
+
+    let mut lsm2 = LSMTree::new(8, 4, 4, 0.5, 4, "flsm_test".to_string());
+    lsm2.enable_flsm();
+    lsm2.load().unwrap();
+    for j in 0..test_size {
+        let expected = if j % 3 == 0 {
+            None
+        } else {
+            Some(j.to_string())
+        };
+        assert_eq!(expected, lsm2.get(&j.to_string()));
+    }
+    lsm2.clear();
+
+    //the guards and fragments survive a tree dropped without close, only the buffer is lost
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "flsm_test");
+    lsm.enable_flsm();
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    let buffered: Vec<KeyT> = lsm.buffer.iter().map(|entry| entry.key).collect();
+    drop(lsm);
+    let mut lsm = LSMTree::new(8, 4, 4, 0.5, 4, "flsm_test".to_string());
+    lsm.enable_flsm();
+    lsm.load().unwrap();
+    assert!(lsm.levels.iter().any(|level| level.guards.len() > 1));
+    for j in 0..test_size {
+        let key = lsm.fill_str_with_witespace(&j.to_string(), data_type::KEY_SIZE);
+        if !buffered.contains(&key) {
+            assert_eq!(Some(j.to_string()), lsm.get(&j.to_string()));
+        }
+    }
+
+    //a damaged or missing manifest is an error, not an empty tree
+    let manifest = lsm.guard_manifest();
+    let saved = fs::read_to_string(&manifest).unwrap();
+    for damaged in ["g x -\n", "g 1\n", "g 9 -\n", "p 1 abc\n"].iter() {
+        fs::write(&manifest, damaged).unwrap();
+        let mut reopened = LSMTree::new(8, 4, 4, 0.5, 4, "flsm_test".to_string());
+        reopened.enable_flsm();
+        let err = reopened.load().err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+    fs::remove_file(&manifest).unwrap();
+    let mut reopened = LSMTree::new(8, 4, 4, 0.5, 4, "flsm_test".to_string());
+    reopened.enable_flsm();
+    assert!(reopened.load().is_err());
+    fs::write(&manifest, saved).unwrap();
+    lsm.clear();
+}
+
+#[test]
+fn test_value_log() {
+    let test_size = 500;
+    let mut lsm = fresh_tree(8, 5, 8, 0.5, 4, "vlog_test");
+    lsm.enable_value_log(16).unwrap();
+    lsm.value_log.as_mut().unwrap().max_file_size = 4096;
+    let large = |i: usize| format!("{}-{}", i, "x".repeat(100));
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &large(i));
+    }
+    //overwrite and delete some keys so the oldest log files hold dead values
+    for i in 0..test_size / 2 {
+        if i % 2 == 0 {
+            lsm.put(&i.to_string(), &i.to_string());
+        } else {
+            lsm.del(&i.to_string());
+        }
+    }
+    let expected = |i: usize| {
+        if i >= test_size / 2 {
+            Some(large(i))
+        } else if i % 2 == 0 {
+            Some(i.to_string())
+        } else {
+            None
+        }
+    };
+    for j in 0..test_size {
+        assert_eq!(expected(j), lsm.get(&j.to_string()));
+    }
+    assert_eq!(vec![large(300), large(301)], lsm.range("300", "301"));
+
+    let before = lsm.value_log.as_ref().unwrap().sealed_files().unwrap();
+    assert!(before.len() > 1);
+    let mut moved = 0;
+    for _ in 0..before.len() {
+        moved += lsm.gc_value_log().unwrap();
+    }
+    //only the values of the untouched upper half were still live
+    assert!(moved > 0 && moved <= test_size / 2);
+    let after = lsm.value_log.as_ref().unwrap().sealed_files().unwrap();
+    assert!(after.iter().all(|id| !before.contains(id)));
+    for j in 0..test_size {
+        assert_eq!(expected(j), lsm.get(&j.to_string()));
+    }
+
+    //the moved values survive a tree dropped without close
+    drop(lsm);
+    let mut lsm = LSMTree::new(8, 5, 8, 0.5, 4, "vlog_test".to_string());
+    lsm.enable_value_log(16).unwrap();
+    lsm.load().unwrap();
+    for j in 0..test_size {
+        assert_eq!(expected(j), lsm.get(&j.to_string()));
+    }
+    lsm.clear();
+}
+
+#[test]
+fn test_tiered_paths() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "tier_test");
+    lsm.set_level_path(0, "/tmp/tier_test_hot").unwrap();
+    lsm.set_level_path(1, "/tmp/tier_test_hot").unwrap();
+    lsm.set_level_path(2, "/tmp/tier_test_cold").unwrap();
+    lsm.set_level_path(3, "/tmp/tier_test_cold").unwrap();
+    lsm.set_promotion_threshold(3);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    let cold_runs = || lsm.levels[2..].iter().flat_map(|level| level.runs.iter());
+    assert!(cold_runs().count() > 0);
+    assert!(cold_runs().all(|r| r.tmp_file.starts_with("/tmp/tier_test_cold/tier_test")));
+
+    //the oldest keys were merged down to the cold levels
+    for _ in 0..3 {
+        assert_eq!(Some("0".to_string()), lsm.get("0"));
+    }
+    let promoted: Vec<&run::Run> = lsm.levels[2..]
+        .iter()
+        .flat_map(|level| level.runs.iter())
+        .filter(|r| r.tmp_file.starts_with("/tmp/tier_test_hot/tier_test"))
+        .collect();
+    assert_eq!(1, promoted.len());
+    assert!(promoted[0].access_count >= 3);
+    lsm.close();
+
+    let mut lsm2 = LSMTree::new(8, 4, 4, 0.5, 4, "tier_test".to_string());
+    lsm2.set_level_path(0, "/tmp/tier_test_hot").unwrap();
+    lsm2.set_level_path(1, "/tmp/tier_test_hot").unwrap();
+    lsm2.set_level_path(2, "/tmp/tier_test_cold").unwrap();
+    lsm2.set_level_path(3, "/tmp/tier_test_cold").unwrap();
+    lsm2.load().unwrap();
+    for j in 0..test_size {
+        assert_eq!(Some(j.to_string()), lsm2.get(&j.to_string()));
+    }
+    lsm2.clear();
+}
+
+#[test]
+fn test_level_compression() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "compress_test");
+    lsm.set_level_compression(2, Arc::new(crate::compress::Lz::fast()));
+    lsm.set_level_compression(3, Arc::new(crate::compress::Lz::high()));
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &format!("{{\"v\":{}}}", i % 10));
+    }
+    for (depth, name) in [(0, "none"), (2, "lz"), (3, "lz-high")].iter() {
+        for run in lsm.levels[*depth].runs.iter() {
+            assert_eq!(*name, run.properties.compression);
+        }
+    }
+    let bottom = &lsm.levels[3].runs[0].properties;
+    assert!(bottom.compression_ratio() > 1.5);
+    lsm.close();
+
+    let mut lsm2 = LSMTree::new(8, 4, 4, 0.5, 4, "compress_test".to_string());
+    lsm2.load().unwrap();
+    assert_eq!(
+        lsm2.levels[3].runs[0].properties.compression_ratio(),
+        lsm.levels[3].runs[0].properties.compression_ratio()
+    );
+    for j in 0..test_size {
+        assert_eq!(
+            Some(format!("{{\"v\":{}}}", j % 10)),
+            lsm2.get(&j.to_string())
+        );
+    }
+    lsm2.clear();
+}
+
+#[test]
+fn test_block_cache() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "cache_test");
+    let cache = Arc::new(BlockCache::new(1024 * 1024, 4));
+    lsm.set_block_cache(cache.clone());
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    assert_eq!(Some("7".to_string()), lsm.get("7"));
+    let misses = cache.misses();
+    assert!(misses > 0);
+    //the second lookup of the same key is served from the cache
+    assert_eq!(Some("7".to_string()), lsm.get("7"));
+    assert_eq!(misses, cache.misses());
+    assert!(cache.hits() > 0);
+
+    assert_eq!(vec!["100", "101"], lsm.range("100", "101"));
+    let hits = cache.hits();
+    assert_eq!(vec!["100", "101"], lsm.range("100", "101"));
+    assert!(cache.hits() > hits);
+    assert!(cache.usage() > 0 && cache.usage() <= cache.capacity());
+    lsm.clear();
+}
+
+#[test]
+fn test_io_uring_range() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "io_uring_test");
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    lsm.del("150");
+    let expected = lsm.range("100", "199");
+    assert_eq!(99, expected.len());
+
+    lsm.enable_io_uring();
+    assert_eq!(expected, lsm.range("100", "199"));
+    //the batch fills the block cache like single reads do
+    let cache = Arc::new(BlockCache::new(1024 * 1024, 4));
+    lsm.set_block_cache(cache.clone());
+    lsm.set_io_mode(IoMode::Direct);
+    assert_eq!(expected, lsm.range("100", "199"));
+    let misses = cache.misses();
+    assert_eq!(expected, lsm.range("100", "199"));
+    assert_eq!(misses, cache.misses());
+    lsm.clear();
+}
+
+#[test]
+fn test_multi_get() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "multi_get_test");
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    //newer versions in upper levels and the buffer win over older ones
+    for i in (0..test_size).step_by(9) {
+        lsm.put(&i.to_string(), &format!("new{}", i));
+    }
+    lsm.put("10", "ten");
+    lsm.del("20");
+    let keys: Vec<String> = (0..test_size + 10).rev().map(|i| i.to_string()).collect();
+    let mut keys: Vec<&str> = keys.iter().map(|key| key.as_str()).collect();
+    keys.push("10");
+    let expected: Vec<Option<String>> = keys.iter().map(|key| lsm.get(key)).collect();
+    assert_eq!(Some("ten".to_string()), expected[expected.len() - 1]);
+    assert_eq!(expected, lsm.multi_get(&keys));
+    lsm.enable_io_uring();
+    assert_eq!(expected, lsm.multi_get(&keys));
+
+    lsm.clear();
+
+    let mut flsm = LSMTree::new(8, 4, 4, 0.5, 4, "multi_get_test".to_string());
+    flsm.enable_flsm();
+    for i in 0..test_size {
+        flsm.put(&(i * 7 % test_size).to_string(), &i.to_string());
+    }
+    let expected: Vec<Option<String>> = keys[10..].iter().map(|key| flsm.get(key)).collect();
+    assert!(expected.iter().all(|value| value.is_some()));
+    assert_eq!(expected, flsm.multi_get(&keys[10..]));
+    flsm.enable_io_uring();
+    assert_eq!(expected, flsm.multi_get(&keys[10..]));
+    flsm.clear();
+}
+
+#[test]
+fn test_parallel_get() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "parallel_get_test");
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    for i in 0..test_size / 10 {
+        lsm.put(&(i * 10).to_string(), "updated");
+    }
+    lsm.del("5");
+    let keys: Vec<String> = (0..test_size + 10).map(|i| i.to_string()).collect();
+    let expected: Vec<Option<String>> = keys.iter().map(|key| lsm.get(key)).collect();
+    assert_eq!(Some("updated".to_string()), expected[20]);
+    assert_eq!(None, expected[5]);
+
+    //a few bits per entry leave plenty of false positives to probe in parallel
+    lsm.set_parallel_get(true);
+    for (key, value) in keys.iter().zip(expected.iter()) {
+        assert_eq!(*value, lsm.get(key));
+    }
+    lsm.clear();
+}
+
+#[test]
+fn test_monkey_filters() {
+    let test_size = 2000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "monkey_test");
+    //5 bits per entry on average
+    lsm.set_filter_memory_budget(test_size * 5 / 8);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    for i in 0..test_size {
+        assert_eq!(Some(i.to_string()), lsm.get(&i.to_string()));
+        assert_eq!(None, lsm.get(&(i + test_size).to_string()));
+    }
+
+    let stats = lsm.stats();
+    assert_eq!(test_size, stats.num_entries() + lsm.buffer.len() as u64);
+    let levels: Vec<&stats::LevelStats> =
+        stats.levels.iter().filter(|l| l.num_entries > 0).collect();
+    assert!(levels.len() > 1);
+    for pair in levels.windows(2) {
+        assert!(pair[0].filter_bits_per_entry > pair[1].filter_bits_per_entry);
+    }
+    //the filters of the upper levels rule out absent keys more often
+    assert!(levels[0].false_positive_rate < levels[levels.len() - 1].false_positive_rate);
+    lsm.clear();
+}
+
+#[test]
+fn test_blocked_filters() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 10.0, 4, "blocked_filter_test");
+    lsm.set_blocked_bloom_filters(true);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    for i in 0..test_size {
+        assert_eq!(Some(i.to_string()), lsm.get(&i.to_string()));
+        assert_eq!(None, lsm.get(&(i + test_size).to_string()));
+    }
+    let runs: Vec<&run::Run> = lsm.levels.iter().flat_map(|l| l.all_runs()).collect();
+    assert!(!runs.is_empty());
+    assert!(runs
+        .iter()
+        .all(|r| r.filter.kind() == FilterKind::BlockedBloom));
+    assert!(lsm
+        .stats()
+        .levels
+        .iter()
+        .all(|l| l.false_positive_rate < 0.1));
+    lsm.clear();
+}
+
+#[test]
+fn test_xor_filters() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 10.0, 4, "xor_filter_test");
+    lsm.set_filter_kind(FilterKind::Xor);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    let check = |lsm: &mut LSMTree| {
+        for i in 0..test_size {
+            assert_eq!(Some(i.to_string()), lsm.get(&i.to_string()));
+            assert_eq!(None, lsm.get(&(i + test_size).to_string()));
+        }
+        let runs: Vec<&run::Run> = lsm.levels.iter().flat_map(|l| l.all_runs()).collect();
+        assert!(!runs.is_empty());
+        assert!(runs.iter().all(|r| r.filter.kind() == FilterKind::Xor));
+        //8 bit fingerprints
+        assert!(lsm
+            .stats()
+            .levels
+            .iter()
+            .all(|l| l.false_positive_rate < 0.02));
+    };
+    check(&mut lsm);
+    lsm.close();
+
+    let mut lsm2 = LSMTree::new(8, 4, 4, 10.0, 4, "xor_filter_test".to_string());
+    lsm2.load().unwrap();
+    check(&mut lsm2);
+    lsm2.clear();
+}
+
+#[test]
+fn test_range_filters() {
+    let test_size = 2000;
+    //every key is a multiple of 10, a range inside a gap holds no key
+    let gaps: Vec<(String, String)> = (0..100)
+        .map(|i| (i * 137 % test_size) * 10)
+        .map(|key| ((key + 1).to_string(), (key + 9).to_string()))
+        .collect();
+    let mut reads: Vec<u64> = Vec::new();
+    for with_filter in [false, true].iter() {
+        let mut lsm = fresh_tree(8, 4, 4, 10.0, 4, "range_filter_test");
+        if *with_filter {
+            lsm.set_range_filter(data_type::KEY_SIZE, 10.0);
+        }
+        for i in 0..test_size {
+            lsm.put(&(i * 10).to_string(), &i.to_string());
+        }
+        assert_eq!(
+            (100..=200).map(|i| i.to_string()).collect::<Vec<String>>(),
+            lsm.range("1000", "2000")
+        );
+        for (start, end) in gaps.iter() {
+            assert!(lsm.range(start, end).is_empty());
+        }
+        reads.push(
+            lsm.levels
+                .iter()
+                .flat_map(|l| l.all_runs())
+                .map(|r| r.access_count)
+                .sum(),
+        );
+        lsm.clear();
+    }
+    //the filters spare the reads of nearly all ranges in gaps
+    assert!(reads[0] > gaps.len() as u64 / 2, "{:?}", reads);
+    assert!(reads[1] * 4 < reads[0], "{:?}", reads);
+}
+
+#[test]
+#[should_panic(expected = "1 to 127 bytes")]
+fn test_range_filter_prefix_len() {
+    let mut lsm = LSMTree::new(8, 4, 4, 10.0, 4, "range_filter_prefix_test".to_string());
+    lsm.set_range_filter(0, 10.0);
+}
+
+#[test]
+fn test_skiplist_memtable() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "skiplist_test");
+    lsm.set_memtable(MemTableKind::SkipList);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    lsm.put("7", "seven");
+    for i in 0..test_size {
+        let expected = if i == 7 {
+            "seven".to_string()
+        } else {
+            i.to_string()
+        };
+        assert_eq!(Some(expected), lsm.get(&i.to_string()));
+    }
+    assert_eq!(
+        (100..=200).map(|i| i.to_string()).collect::<Vec<String>>(),
+        lsm.range("100", "200")
+    );
+    lsm.clear();
+}
+
+#[test]
+fn test_put_batch() {
+    let test_size = 1000;
+    for (kind, buffers) in [
+        (MemTableKind::SkipList, 1),
+        (MemTableKind::SkipList, 3),
+        (MemTableKind::BTree, 1),
+    ]
+    .iter()
+    {
+        let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "put_batch_test");
+        lsm.set_memtable(*kind);
+        lsm.set_max_write_buffer_number(*buffers);
+        assert_eq!(
+            *kind == MemTableKind::SkipList,
+            lsm.buffer.as_concurrent().is_some()
+        );
+        let keys: Vec<String> = (0..test_size).map(|i| i.to_string()).collect();
+        let overwrites: Vec<String> = (0..test_size).map(|i| format!("{}x", i)).collect();
+        let mut entries = Vec::new();
+        for i in 0..test_size {
+            entries.push((keys[i].as_str(), keys[i].as_str()));
+            //the last put of a key in the batch wins
+            if i % 3 == 0 {
+                entries.push((keys[i].as_str(), overwrites[i].as_str()));
+            }
+        }
+        assert!(lsm.put_batch(&entries));
+        for i in 0..test_size {
+            let expected = if i % 3 == 0 {
+                overwrites[i].clone()
+            } else {
+                keys[i].clone()
+            };
+            assert_eq!(Some(expected), lsm.get(&keys[i]));
+        }
+        lsm.clear();
+    }
+}
+
+#[test]
+fn test_vector_and_hash_memtables() {
+    let test_size = 1000;
+    for kind in [MemTableKind::Vector, MemTableKind::HashLinkList].iter() {
+        let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "memtable_kinds_test");
+        lsm.set_memtable(*kind);
+        for i in 0..test_size {
+            lsm.put(&i.to_string(), &i.to_string());
+            //overwrites within the buffer keep only the last value
+            if i % 3 == 0 {
+                lsm.put(&i.to_string(), &format!("{}x", i));
+            }
+        }
+        for i in 0..test_size {
+            let expected = if i % 3 == 0 {
+                format!("{}x", i)
+            } else {
+                i.to_string()
+            };
+            assert_eq!(Some(expected), lsm.get(&i.to_string()), "{:?}", kind);
+        }
+        assert_eq!(101, lsm.range("100", "200").len());
+        lsm.clear();
+    }
+}
+
+#[test]
+fn test_immutable_buffers() {
+    let test_size = 2000;
+    let mut lsm = fresh_tree(8, 5, 4, 0.5, 4, "immutable_buffers_test");
+    lsm.set_max_write_buffer_number(3);
+    let value = |i: u32, round: u32| format!("{}-{}", i, round);
+    for round in 0..2 {
+        for i in 0..test_size {
+            lsm.put(&i.to_string(), &value(i, round));
+            assert!(lsm.num_immutable_buffers() < 3);
+        }
+        //the latest values are read from the buffers, pending flushes or runs alike
+        for i in (0..test_size).step_by(7) {
+            assert_eq!(Some(value(i, round)), lsm.get(&i.to_string()));
+        }
+        assert_eq!(
+            (100..=200)
+                .map(|i| value(i, round))
+                .collect::<Vec<String>>(),
+            lsm.range("100", "200")
+        );
+        assert_eq!(
+            vec![Some(value(5, round)), None],
+            lsm.multi_get(&["5", "x"])
+        );
+    }
+    lsm.wait_for_flushes();
+    assert_eq!(0, lsm.num_immutable_buffers());
+    lsm.close();
+    let runs: Vec<usize> = lsm.levels.iter().map(|l| l.runs.len()).collect();
+
+    //a flush left over by a tree that was not closed is removed, not loaded as a run
+    let leftover = PathBuf::from("/tmp/immutable_buffers_test/flush/flush-0.txt");
+    fs::write(&leftover, b"leftover").unwrap();
+    let mut lsm = LSMTree::new(8, 5, 4, 0.5, 4, "immutable_buffers_test".to_string());
+    assert!(!leftover.exists());
+    lsm.load().unwrap();
+    assert_eq!(
+        runs,
+        lsm.levels
+            .iter()
+            .map(|l| l.runs.len())
+            .collect::<Vec<usize>>()
+    );
+    for i in 0..test_size {
+        assert_eq!(Some(value(i, 1)), lsm.get(&i.to_string()));
+    }
+    lsm.clear();
+}
+
+#[test]
+fn test_write_stalls() {
+    let test_size = 2000;
+    let mut lsm = fresh_tree(8, 5, 4, 0.5, 4, "write_stall_test");
+    assert!(lsm.write_stall_stats().is_none());
+    lsm.set_max_write_buffer_number(4);
+    lsm.set_write_stall_options(WriteStallOptions {
+        level0_slowdown_runs: 2,
+        level0_stop_runs: 3,
+        immutable_buffers_slowdown: 1,
+        immutable_buffers_stop: 2,
+        delayed_write_rate: 1 << 20,
+        ..WriteStallOptions::default()
+    });
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+        //a put stops before the hard limits, and adds at most one run or immutable buffer
+        assert!(lsm.levels[0].runs.len() <= 3);
+        assert!(lsm.num_immutable_buffers() <= 2);
+    }
+    for i in 0..test_size {
+        assert_eq!(Some(i.to_string()), lsm.get(&i.to_string()));
+    }
+    let stats = lsm.write_stall_stats().unwrap();
+    assert!(stats.delays[&StallReason::Level0Runs].count > 0);
+    assert!(stats.stops[&StallReason::Level0Runs].count > 0);
+    assert!(stats.total_delay() > Duration::from_secs(0));
+    lsm.clear();
+}
+
+#[test]
+fn test_buffer_size() {
+    let test_size = 200;
+    let mut lsm = fresh_tree(50, 4, 4, 0.5, 4, "buffer_size_test");
+    //values 10 times the default size fill the buffer after a tenth of the entries
+    let large = |i: u32| format!("{:0240}", i);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &large(i));
+    }
+    assert!(lsm.buffer.len() < 10);
+    assert!(lsm.buffer.approximate_size() < lsm.buffer_max_bytes);
+    assert_eq!(
+        test_size as u64,
+        lsm.stats().num_entries() + lsm.buffer.len() as u64
+    );
+    for i in 0..test_size {
+        assert_eq!(Some(large(i)), lsm.get(&i.to_string()));
+    }
+    lsm.clear();
+
+    let mut lsm = LSMTree::new(50, 4, 4, 0.5, 4, "buffer_size_test".to_string());
+    lsm.set_buffer_size(1000);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+        //the buffer is flushed by the first put after it reached the size
+        assert!(lsm.buffer.approximate_size() < 1000 + memtable::entry_charge(&[0; 8], &[0; 24]));
+    }
+    lsm.clear();
+}
+
+#[test]
+fn test_write_buffer_manager() {
+    let test_size = 500;
+    let manager = Arc::new(WriteBufferManager::new(4000));
+    let mut trees: Vec<LSMTree> = ["write_buffer_test_1", "write_buffer_test_2"]
+        .iter()
+        .map(|name| {
+            let mut lsm = fresh_tree(50, 4, 4, 0.5, 4, name);
+            lsm.set_write_buffer_manager(manager.clone());
+            lsm
+        })
+        .collect();
+    //on their own each buffer would take about 4800 bytes, with the manager each tree goes
+    //over its share by at most an entry
+    let charge = memtable::entry_charge(&[0; 8], &[0; 24]);
+    for i in 0..test_size {
+        for lsm in trees.iter_mut() {
+            lsm.put(&i.to_string(), &i.to_string());
+            assert!(manager.usage() <= manager.capacity() + 2 * charge);
+        }
+    }
+    assert_eq!(
+        manager.usage(),
+        trees
+            .iter()
+            .map(|t| t.buffer.approximate_size())
+            .sum::<usize>()
+    );
+    for lsm in trees.iter_mut() {
+        for i in 0..test_size {
+            assert_eq!(Some(i.to_string()), lsm.get(&i.to_string()));
+        }
+        lsm.clear();
+    }
+    trees.clear();
+    assert_eq!(0, manager.usage());
+}
+
+#[test]
+fn test_max_open_files() {
+    let test_size = 1000;
+    let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "open_files_test");
+    lsm.set_max_open_files(3);
+    for i in 0..test_size {
+        lsm.put(&i.to_string(), &i.to_string());
+    }
+    for j in 0..test_size {
+        assert_eq!(Some(j.to_string()), lsm.get(&j.to_string()));
+        assert!(lsm.table_cache().num_open_files() <= 3);
+    }
+    //a hot key keeps its file open instead of reopening it on every lookup
+    let opens = lsm.table_cache().opens();
+    for _ in 0..10 {
+        assert_eq!(Some("5".to_string()), lsm.get("5"));
+    }
+    assert!(lsm.table_cache().opens() <= opens + 1);
+    lsm.clear();
+    assert_eq!(0, lsm.table_cache().num_open_files());
+}
+
+#[test]
+fn test_io_modes() {
+    let test_size = 1000;
+    for mode in [IoMode::Pread, IoMode::Direct].iter() {
+        let mut lsm = fresh_tree(8, 4, 4, 0.5, 4, "io_mode_test");
+        lsm.set_io_mode(*mode);
+        lsm.set_block_cache(Arc::new(BlockCache::new(1024 * 1024, 4)));
+        for i in 0..test_size {
+            lsm.put(&i.to_string(), &i.to_string());
+        }
+        assert_eq!(vec!["100", "101"], lsm.range("100", "101"));
+        lsm.close();
+
+        let mut lsm2 = LSMTree::new(8, 4, 4, 0.5, 4, "io_mode_test".to_string());
+        lsm2.set_io_mode(*mode);
+        lsm2.load().unwrap();
+        for j in 0..test_size {
+            assert_eq!(Some(j.to_string()), lsm2.get(&j.to_string()));
+        }
+        lsm2.clear();
+    }
+}
+
 #[test]
 fn test_clear() {
     let test_size = 1000;
@@ -519,6 +2784,7 @@ fn bench_put() {
         test_size, duration
     );
 
+    use std::collections::HashMap;
     let mut hashmap: HashMap<&str, &str> = HashMap::new();
     let start = Instant::now();
     for i in 0..test_size {
@@ -1,11 +1,13 @@
 use getopts::Options;
 use lsm_kv::data_type::ENTRY_SIZE;
+use lsm_kv::engine::Engine;
+use lsm_kv::kvell::KVell;
 use lsm_kv::lsm;
 use lsm_kv::lsm::LSMTree;
 use std::io::BufRead;
 use std::{env, io};
 
-fn command_loop(lsm_tree: &mut LSMTree, input: impl BufRead) {
+fn command_loop(lsm_tree: &mut dyn Engine, input: impl BufRead) {
     for line in input.lines() {
         match line {
             Ok(line) => {
@@ -76,6 +78,14 @@ fn main() {
     opts.optopt("f", "", "level fanout", "FANOUT");
     opts.optopt("t", "", "number of threads", "THREADS_NUM");
     opts.optopt("r", "", "bloom filter bits per entry", "BLOOM_BITS");
+    opts.optopt("e", "", "storage engine: lsm (default) or kvell", "ENGINE");
+    opts.optflag("g", "", "fragmented LSM with guards (PebblesDB-style)");
+    opts.optopt(
+        "v",
+        "",
+        "store values longer than THRESHOLD bytes in a value log",
+        "THRESHOLD",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => panic!(f.to_string()),
@@ -96,7 +106,15 @@ fn main() {
         bf_bits_per_entry = matches.opt_str("r").unwrap().parse().unwrap()
     }
 
-    let buffer_max_entries = buffer_num_pages * page_size::get() as u64 / ENTRY_SIZE as u64;
+    if matches.opt_str("e").as_deref() == Some("kvell") {
+        let mut kvell = KVell::new(num_threads, tree_name.to_string());
+        command_loop(&mut kvell, io::stdin().lock());
+        return;
+    }
+
+    let buffer_bytes = buffer_num_pages * page_size::get() as u64;
+    //sizes the runs of level 0, the buffer itself is flushed by bytes
+    let buffer_max_entries = buffer_bytes / ENTRY_SIZE as u64;
 
     let mut lsm_tree = LSMTree::new(
         buffer_max_entries,
@@ -107,5 +125,14 @@ fn main() {
         tree_name.to_string(),
     );
 
+    lsm_tree.set_buffer_size(buffer_bytes as usize);
+    if matches.opt_present("g") {
+        lsm_tree.enable_flsm();
+    }
+    if matches.opt_str("v").is_some() {
+        let threshold = matches.opt_str("v").unwrap().parse().unwrap();
+        lsm_tree.enable_value_log(threshold).unwrap();
+    }
+
     command_loop(&mut lsm_tree, io::stdin().lock())
 }
@@ -0,0 +1,116 @@
+//The memtable a tree buffers its latest writes in until they are flushed into a level 0 run.
+//Buffer keeps the entries in a BTreeSet and needs exclusive access for every put. SkipList is
+//also a ConcurrentMemTable, which takes inserts from several threads at once while readers
+//iterate. VectorMemTable appends puts and sorts them when they are read in order, for bulk
+//loads, and HashLinkList hashes keys to short sorted lists, for point lookups.
+//Memtables are flushed by the approximate memory of their entries, as keys and values vary
+//in size.
+use crate::buffer::Buffer;
+use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::hash_linklist::HashLinkList;
+use crate::skiplist::SkipList;
+use crate::vector_memtable::VectorMemTable;
+
+//memory of an entry besides its key and value: the key and value vectors and the share of
+//the node holding it
+pub static ENTRY_OVERHEAD: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemTableKind {
+    #[default]
+    BTree,
+    SkipList,
+    Vector,
+    HashLinkList,
+}
+
+pub trait MemTable: Send + Sync {
+    /// Inserts `key` or replaces its value.
+    fn put(&mut self, key: KeyT, value: ValueT);
+    fn get(&self, key: &KeyT) -> Option<ValueT>;
+    /// Looks up sorted `keys`.
+    fn multi_get(&self, keys: &[KeyT]) -> Vec<Option<ValueT>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+    /// Returns the entries with keys in `[start, end]` in key order.
+    fn range(&self, start: &KeyT, end: &KeyT) -> Vec<EntryT>;
+    /// Returns every entry in key order, e.g. to flush them into a run.
+    fn iter(&self) -> Box<dyn Iterator<Item = EntryT> + '_>;
+    /// Number of entries. A memtable that does not look for the key on a put may count
+    /// every put instead.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Approximate memory of the entries in bytes.
+    fn approximate_size(&self) -> usize;
+    /// Drops every entry.
+    fn empty(&mut self);
+    /// Returns the memtable as one that takes inserts from several threads, if it can.
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentMemTable> {
+        None
+    }
+}
+
+/// A memtable that several writers can insert into at once.
+pub trait ConcurrentMemTable: MemTable {
+    /// Inserts `key` or replaces its value. Safe to call from several threads at once, the
+    /// value of a key inserted by two threads at once is either of theirs.
+    fn insert(&self, key: KeyT, value: ValueT);
+}
+
+/// Approximate memory an entry takes in a memtable.
+pub fn entry_charge(key: &[u8], value: &[u8]) -> usize {
+    key.len() + value.len() + ENTRY_OVERHEAD
+}
+
+/// Returns an empty memtable of `kind`.
+pub fn new_memtable(kind: MemTableKind) -> Box<dyn MemTable> {
+    match kind {
+        //the tree flushes by size, not by the number of entries
+        MemTableKind::BTree => Box::new(Buffer::new(usize::MAX)),
+        MemTableKind::SkipList => Box::new(SkipList::new()),
+        MemTableKind::Vector => Box::new(VectorMemTable::new()),
+        MemTableKind::HashLinkList => Box::new(HashLinkList::default()),
+    }
+}
+
+#[test]
+fn test_memtable_kinds() {
+    let key = |i: u32| format!("{:04}", i).into_bytes();
+    for kind in [
+        MemTableKind::BTree,
+        MemTableKind::SkipList,
+        MemTableKind::Vector,
+        MemTableKind::HashLinkList,
+    ]
+    .iter()
+    {
+        let mut memtable = new_memtable(*kind);
+        assert!(memtable.is_empty());
+        for i in (0..50).rev() {
+            memtable.put(key(i * 2), key(i));
+        }
+        let size = memtable.approximate_size();
+        assert!(size >= 50 * entry_charge(&key(0), &key(0)));
+        assert_eq!(50, memtable.len());
+        memtable.put(key(0), b"zero".to_vec());
+        assert_eq!(Some(b"zero".to_vec()), memtable.get(&key(0)));
+        assert_eq!(None, memtable.get(&key(1)));
+        assert_eq!(
+            vec![Some(key(1)), None, Some(key(49))],
+            memtable.multi_get(&[key(2), key(3), key(98)])
+        );
+        let range = memtable.range(&key(9), &key(20));
+        assert_eq!(
+            vec![key(10), key(12), key(14), key(16), key(18), key(20)],
+            range.into_iter().map(|e| e.key).collect::<Vec<KeyT>>()
+        );
+        let entries: Vec<EntryT> = memtable.iter().collect();
+        assert_eq!(b"zero".to_vec(), entries[0].value);
+        let keys: Vec<KeyT> = entries.into_iter().map(|e| e.key).collect();
+        assert_eq!((0..50).map(|i| key(i * 2)).collect::<Vec<KeyT>>(), keys);
+        memtable.empty();
+        assert!(memtable.is_empty());
+    }
+}
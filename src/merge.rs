@@ -103,7 +103,7 @@ impl MergeContext {
 
     pub fn print(&mut self) {
         println!("merge ctx print start");
-        for tmp in &self.priority_queue{
+        for tmp in &self.priority_queue {
             for entry in tmp.entries.iter() {
                 println!("{}", str::from_utf8(&entry.value).unwrap());
             }
@@ -0,0 +1,186 @@
+//Range filter of the runs, a bloom filter over the prefixes of the keys. Every prefix of a
+//key up to max_prefix_len bytes is added, and so is every whole key of at most that length.
+//A range [start, end] is checked by walking down the byte trie the prefixes span: subtrees
+//whose prefix is not in the filter are cut off, subtrees that lie inside the range answer
+//with their prefix alone, and the bounds are followed byte by byte until they diverge.
+//A short range thus costs a few probes, and a range the probe budget does not cover is
+//assumed to hold keys.
+use crate::bloom_filter::BloomFilter;
+use std::convert::TryInto;
+
+//a check gives up and says the range may hold keys after this many probes
+static MAX_PROBES: usize = 128;
+
+#[derive(Debug)]
+pub struct RangeFilter {
+    max_prefix_len: usize,
+    bloom: BloomFilter,
+}
+
+impl RangeFilter {
+    /// Returns a filter for `expected_keys` keys, whose prefixes up to `max_prefix_len` bytes
+    /// get `bits_per_entry` bits each. Keys sharing a prefix share its bits, so the memory
+    /// per key is at most `(max_prefix_len + 1) * bits_per_entry` bits.
+    pub fn new(expected_keys: u64, max_prefix_len: usize, bits_per_entry: f32) -> RangeFilter {
+        assert!(max_prefix_len > 0 && max_prefix_len < 128);
+        RangeFilter {
+            max_prefix_len,
+            bloom: BloomFilter::with_bits_per_entry(
+                expected_keys * (max_prefix_len as u64 + 1),
+                bits_per_entry,
+            ),
+        }
+    }
+
+    //hash of a prefix, or of a whole key if full, which never equals the prefix hashes
+    fn element_hash(prefix: &[u8], full: bool) -> u64 {
+        let tag = (prefix.len() as u64) << 1 | full as u64;
+        (BloomFilter::hash_key(prefix) ^ tag).wrapping_mul(0x0100_0000_01b3)
+    }
+
+    fn probe(&self, prefix: &[u8], full: bool, probes: &mut usize) -> bool {
+        *probes += 1;
+        self.bloom
+            .bloom_check(RangeFilter::element_hash(prefix, full))
+    }
+
+    pub fn set(&mut self, key: &[u8]) {
+        let len = key.len().min(self.max_prefix_len);
+        for i in 1..=len {
+            self.bloom
+                .bloom_add(RangeFilter::element_hash(&key[..i], false));
+        }
+        if key.len() <= self.max_prefix_len {
+            self.bloom.bloom_add(RangeFilter::element_hash(key, true));
+        }
+    }
+
+    /// Returns false if no key in `[start, end]` was set, and true if one may have been.
+    pub fn may_contain_range(&self, start: &[u8], end: &[u8]) -> bool {
+        if start > end {
+            return false;
+        }
+        let mut prefix: Vec<u8> = Vec::with_capacity(self.max_prefix_len);
+        self.search(&mut prefix, Some(start), Some(end), &mut 0)
+    }
+
+    //whether a key starting with prefix, not below prefix + low and not above prefix + high,
+    //may have been set. None bounds are open.
+    fn search(
+        &self,
+        prefix: &mut Vec<u8>,
+        low: Option<&[u8]>,
+        high: Option<&[u8]>,
+        probes: &mut usize,
+    ) -> bool {
+        //every key starting with prefix is at least prefix
+        let low = low.filter(|low| !low.is_empty());
+        if low.is_none() && self.probe(prefix, true, probes) {
+            return true;
+        }
+        if high == Some(&[]) {
+            //the range ends at prefix itself
+            return false;
+        }
+        let first = low.map_or(0, |low| low[0]);
+        let last = high.map_or(u8::MAX, |high| high[0]);
+        for byte in first..=last {
+            if *probes >= MAX_PROBES {
+                return true;
+            }
+            let child_low = low.filter(|low| low[0] == byte).map(|low| &low[1..]);
+            let child_high = high.filter(|high| high[0] == byte).map(|high| &high[1..]);
+            prefix.push(byte);
+            //a child inside the range or at the longest prefix is answered by its prefix
+            let found = self.probe(prefix, false, probes)
+                && (prefix.len() == self.max_prefix_len
+                    || (child_low.is_none() && child_high.is_none())
+                    || self.search(prefix, child_low, child_high, probes));
+            prefix.pop();
+            if found {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn max_prefix_len(&self) -> usize {
+        self.max_prefix_len
+    }
+
+    pub fn num_bits(&self) -> u64 {
+        self.bloom.num_bits()
+    }
+
+    //encoded filter: [max prefix length u64][bloom filter]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = (self.max_prefix_len as u64).to_le_bytes().to_vec();
+        res.extend_from_slice(&self.bloom.encode());
+        res
+    }
+
+    /// Restores a filter written by `encode`, returns None if `src` is not one.
+    pub fn decode(src: &[u8]) -> Option<RangeFilter> {
+        if src.len() < 8 {
+            return None;
+        }
+        let max_prefix_len = u64::from_le_bytes(src[..8].try_into().unwrap()) as usize;
+        if max_prefix_len == 0 || max_prefix_len >= 128 {
+            return None;
+        }
+        Some(RangeFilter {
+            max_prefix_len,
+            bloom: BloomFilter::decode(&src[8..])?,
+        })
+    }
+}
+
+#[test]
+fn test_range_filter() {
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeSet;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let key = |i: u32| format!("{:08}", i).into_bytes();
+    let keys: BTreeSet<Vec<u8>> = (0..2000)
+        .map(|_| key(rng.gen_range(0, 10_000_000)))
+        .collect();
+    let mut filter = RangeFilter::new(keys.len() as u64, 8, 10.0);
+    for k in keys.iter() {
+        filter.set(k);
+    }
+    let filter = RangeFilter::decode(&filter.encode()).unwrap();
+
+    let (mut empty, mut false_positives) = (0, 0);
+    for _ in 0..2000 {
+        let start = rng.gen_range(0, 10_000_000);
+        let (start, end) = (key(start), key(start + rng.gen_range(0, 100)));
+        let holds_keys = keys.range(start.clone()..=end.clone()).next().is_some();
+        let passes = filter.may_contain_range(&start, &end);
+        assert!(passes || !holds_keys);
+        if !holds_keys {
+            empty += 1;
+            false_positives += passes as usize;
+        }
+    }
+    assert!(empty > 1000);
+    assert!(
+        false_positives * 10 < empty,
+        "{} of {}",
+        false_positives,
+        empty
+    );
+
+    //single keys, ranges across the whole key space and reversed ranges
+    for k in keys.iter() {
+        assert!(filter.may_contain_range(k, k));
+    }
+    assert!(filter.may_contain_range(&key(0), &key(99_999_999)));
+    assert!(!filter.may_contain_range(&key(2), &key(1)));
+    //keys shorter than the bounds
+    let mut short = RangeFilter::new(10, 4, 10.0);
+    short.set(b"ab");
+    assert!(short.may_contain_range(b"a", b"ac"));
+    assert!(short.may_contain_range(b"ab", b"ab"));
+    assert!(!short.may_contain_range(b"aba", b"abz"));
+    assert!(!short.may_contain_range(b"b", b"c"));
+}
@@ -1,11 +1,20 @@
-use crate::data_type::{EntryT, KeyT, ValueT, ENTRY_SIZE, KEY_SIZE, VALUE_SIZE};
+use crate::block;
+use crate::cache;
+use crate::compress;
+use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::error;
+use crate::file_io;
+use crate::filter;
+use crate::range_filter;
+use crate::table;
+use crate::uring;
 use libc;
-use memmap::{Mmap, MmapMut, MmapOptions};
+use memmap::{MmapMut, MmapOptions};
 use mktemp::Temp;
 use mmap::{MapOption, MemoryMap};
-use page_size;
 use std::cmp::max;
-use std::io::prelude::*;
+use std::fs;
+use std::io;
 //use std::collections::linked_list::Iter;
 use std::fs::{File, OpenOptions};
 use std::os::raw;
@@ -13,12 +22,21 @@ use std::os::raw::c_void;
 use std::os::unix::prelude::*;
 use std::path::PathBuf;
 use std::str;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
 
 pub struct Run {
-    pub bloom_filter: bloomfilter::Bloom<KeyT>,
-    //bloom_filer: bloom_filter::BloomFilter,
-    pub fence_pointers: Vec<KeyT>,
+    pub filter: Box<dyn filter::Filter>,
+    //lookups the filter ruled out, and lookups it let through for keys not in the run
+    pub filter_negatives: u64,
+    pub filter_false_positives: u64,
+    //rules out ranges without keys in the run, if the tree asks for one
+    pub range_filter: Option<range_filter::RangeFilter>,
+    //last key and location of every data block, read from the index block
+    pub index: Vec<table::IndexEntry>,
+    pub min_key: KeyT,
     pub max_key: KeyT,
     pub mapping: Option<MmapMut>,
     pub mapping_file: Option<File>,
@@ -27,6 +45,20 @@ pub struct Run {
     pub tmp_file: PathBuf,
     pub level_index: usize,
     pub read_write_lock: RwLock<usize>,
+    //number of reads that went to the file, used to find hot runs
+    pub access_count: u64,
+    pub properties: table::TableProperties,
+    //checksums of blocks read back are verified unless this is turned off
+    pub verify_checksums: bool,
+    //codec the data blocks are compressed with when the run is written
+    pub compressor: Arc<dyn compress::Compressor>,
+    pub block_cache: Option<Arc<cache::BlockCache>>,
+    //identifies the blocks and the open file of this run in the caches
+    pub cache_id: u64,
+    pub table_cache: Arc<cache::TableCache>,
+    pub io_mode: file_io::IoMode,
+    //set between map_write and unmap while the run is being written
+    builder: Option<table::TableBuilder>,
 }
 
 impl Run {
@@ -55,12 +87,16 @@ impl Run {
         id: usize,
     ) -> Run {
         Run {
-            bloom_filter: bloomfilter::Bloom::new(
-                (bf_bits_per_entry * max_size as f32) as usize,
-                max_size as usize,
+            filter: filter::new_filter(
+                filter::FilterKind::Bloom,
+                max(1, max_size),
+                bf_bits_per_entry,
             ),
-            //bloom_filer: bloom_filter::BloomFilter::new_with_size(max_size * bf_bits_per_entry),
-            fence_pointers: Vec::with_capacity((max_size / page_size::get() as u64) as usize),
+            filter_negatives: 0,
+            filter_false_positives: 0,
+            range_filter: None,
+            index: Vec::new(),
+            min_key: KeyT::default(),
             max_key: KeyT::default(),
             mapping: None,
             mapping_file: None,
@@ -69,17 +105,30 @@ impl Run {
             level_index: level,
             tmp_file: PathBuf::from(format!(r"/tmp/{}/{}/run_file-{}.txt", lsm_name, level, id)),
             read_write_lock: RwLock::new(0),
+            access_count: 0,
+            properties: table::TableProperties::default(),
+            verify_checksums: true,
+            compressor: Arc::new(compress::NoCompression),
+            block_cache: None,
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            table_cache: cache::default_table_cache(),
+            io_mode: file_io::IoMode::Mmap,
+            builder: None,
         }
     }
 
     pub fn from(max_size: u64, bf_bits_per_entry: f32, level: usize, file_path: PathBuf) -> Run {
         Run {
-            bloom_filter: bloomfilter::Bloom::new(
-                (bf_bits_per_entry * max_size as f32) as usize,
-                max_size as usize,
+            filter: filter::new_filter(
+                filter::FilterKind::Bloom,
+                max(1, max_size),
+                bf_bits_per_entry,
             ),
-            //bloom_filer: bloom_filter::BloomFilter::new_with_size(max_size * bf_bits_per_entry),
-            fence_pointers: Vec::with_capacity((max_size / page_size::get() as u64) as usize),
+            filter_negatives: 0,
+            filter_false_positives: 0,
+            range_filter: None,
+            index: Vec::new(),
+            min_key: KeyT::default(),
             max_key: KeyT::default(),
             mapping: None,
             mapping_file: None,
@@ -88,54 +137,187 @@ impl Run {
             level_index: level,
             tmp_file: file_path,
             read_write_lock: RwLock::new(0),
+            access_count: 0,
+            properties: table::TableProperties::default(),
+            verify_checksums: true,
+            compressor: Arc::new(compress::NoCompression),
+            block_cache: None,
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            table_cache: cache::default_table_cache(),
+            io_mode: file_io::IoMode::Mmap,
+            builder: None,
+        }
+    }
+
+    /// Opens an existing run file, restoring its index, filter and properties.
+    pub fn open(level: usize, file_path: PathBuf) -> error::Result<Run> {
+        let file_len = fs::metadata(&file_path)?.len() as usize;
+        let mut run = Run::from(0, 0.0, level, file_path);
+        if file_len < table::FOOTER_SIZE {
+            return Err(run.corruption(0));
+        }
+        let footer_offset = file_len - table::FOOTER_SIZE;
+        let footer = table::Footer::decode(&run.map_read(table::FOOTER_SIZE, footer_offset)?)
+            .ok_or_else(|| run.corruption(footer_offset as u64))?;
+        run.index = table::decode_index(run.read_block(&footer.index)?)
+            .ok_or_else(|| run.corruption(footer.index.offset))?;
+        let metaindex = table::decode_index(run.read_block(&footer.metaindex)?)
+            .ok_or_else(|| run.corruption(footer.metaindex.offset))?;
+        for meta in metaindex.iter() {
+            if let Some(kind) = filter::FilterKind::from_block_name(&meta.last_key) {
+                let filter = run.read_raw_block(&meta.handle)?;
+                run.filter = filter::decode(kind, &filter)
+                    .ok_or_else(|| run.corruption(meta.handle.offset))?;
+            } else if meta.last_key == table::RANGE_FILTER_BLOCK_NAME.as_bytes() {
+                let filter = run.read_raw_block(&meta.handle)?;
+                run.range_filter = Some(
+                    range_filter::RangeFilter::decode(&filter)
+                        .ok_or_else(|| run.corruption(meta.handle.offset))?,
+                );
+            } else if meta.last_key == table::PROPERTIES_BLOCK_NAME.as_bytes() {
+                run.properties = table::TableProperties::decode(run.read_block(&meta.handle)?);
+            }
         }
+        run.size = run.properties.num_entries;
+        run.max_size = run.properties.max_entries;
+        run.min_key = run.properties.min_key.clone();
+        run.max_key = run.properties.max_key.clone();
+        Ok(run)
     }
 
+    fn corruption(&self, offset: u64) -> error::Error {
+        error::Error::Corruption {
+            file: self.tmp_file.clone(),
+            offset,
+        }
+    }
+
+    /// Returns every entry of the run in key order.
     pub fn map_read_default(&mut self) -> Vec<EntryT> {
-        self.map_read(ENTRY_SIZE * self.max_size as usize, 0)
+        self.try_map_read_default()
+            .unwrap_or_else(|e| panic!("Reading run failed because {}", e))
     }
 
-    pub fn map_read(&mut self, len: usize, offset: usize) -> Vec<EntryT> {
-        //assert!(self.mapping.is_none());
+    pub fn try_map_read_default(&mut self) -> error::Result<Vec<EntryT>> {
+        let mut res: Vec<EntryT> = Vec::with_capacity(self.size as usize);
+        if self.index.is_empty() {
+            return Ok(res);
+        }
+        //data blocks are stored back to back at the start of the file
+        let data = self.map_read(self.properties.data_size as usize, 0)?;
+        for entry in self.index.iter() {
+            let block = table::block_data(
+                &data,
+                &entry.handle,
+                self.verify_checksums,
+                &*self.compressor,
+            )
+            .and_then(block::Block::new)
+            .ok_or_else(|| self.corruption(entry.handle.offset))?;
+            res.extend(block.entries());
+        }
+        Ok(res)
+    }
 
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(self.tmp_file.as_path())
-        {
-            Ok(file) => {
-                self.mapping_file = Some(file);
-            }
-            Err(_) => panic!("Open temp file failed!"),
+    /// Returns the open run file, which stays open in the table cache between calls.
+    pub fn file(&self) -> error::Result<Arc<file_io::RunFile>> {
+        Ok(self
+            .table_cache
+            .get_or_open(self.cache_id, &self.tmp_file, self.io_mode)?)
+    }
+
+    /// Returns `len` bytes of the run file starting at `offset`, read as set by `io_mode`.
+    pub fn map_read(&mut self, len: usize, offset: usize) -> error::Result<Vec<u8>> {
+        let file = self.file()?;
+        if offset + len > file.len()? {
+            //a damaged handle points past the end of the file
+            return Err(self.corruption(offset as u64));
+        }
+        Ok(file.read(len, offset)?)
+    }
+
+    /// Switches how the file is read and how it is written by `unmap`.
+    pub fn set_io_mode(&mut self, mode: file_io::IoMode) {
+        if self.io_mode != mode {
+            //reopen the file in the new mode on the next read
+            self.table_cache.evict(self.cache_id);
+            self.io_mode = mode;
+        }
+    }
+
+    //contents of the block at handle, verified against its checksum unless disabled
+    fn read_raw_block(&mut self, handle: &table::BlockHandle) -> error::Result<Vec<u8>> {
+        let raw = self.map_read(
+            handle.size as usize + table::BLOCK_TRAILER_SIZE,
+            handle.offset as usize,
+        )?;
+        self.block_contents(handle, &raw)
+    }
+
+    //contents of the block at handle from raw, the block and its trailer as read from the file
+    fn block_contents(&self, handle: &table::BlockHandle, raw: &[u8]) -> error::Result<Vec<u8>> {
+        let local = table::BlockHandle {
+            offset: 0,
+            size: handle.size,
         };
-        unsafe {
-            match MmapOptions::new()
-                .len(len)
-                .offset(offset as u64)
-                .map(self.mapping_file.as_ref().unwrap())
-            {
-                Ok(mmap) => {
-                    self.mapping = Some(mmap.make_mut().unwrap());
-                    let mut res: Vec<EntryT> = Vec::new();
-                    for i in 0..len / ENTRY_SIZE {
-                        let offset = ENTRY_SIZE * i as usize;
-                        res.push(EntryT {
-                            key: self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE]
-                                .to_vec(),
-                            value: self.mapping.as_ref().unwrap().as_ref()
-                                [offset + KEY_SIZE..offset + KEY_SIZE + VALUE_SIZE]
-                                .to_vec(),
-                        })
-                    }
-                    res
-                }
-                Err(_) => panic!("Mapping failed!"),
-            }
+        table::block_data(raw, &local, self.verify_checksums, &*self.compressor)
+            .ok_or_else(|| self.corruption(handle.offset))
+    }
+
+    fn read_block(&mut self, handle: &table::BlockHandle) -> error::Result<block::Block> {
+        block::Block::new(self.read_raw_block(handle)?)
+            .ok_or_else(|| self.corruption(handle.offset))
+    }
+
+    fn cache_key(&self, handle: &table::BlockHandle) -> cache::CacheKey {
+        cache::CacheKey {
+            run: self.cache_id,
+            offset: handle.offset,
+        }
+    }
+
+    fn cached_block(&self, handle: &table::BlockHandle) -> Option<Arc<block::Block>> {
+        let cache = self.block_cache.as_ref()?;
+        cache.get(&self.cache_key(handle))
+    }
+
+    //decodes a data block read from the file and adds it to the block cache
+    fn decode_data_block(
+        &self,
+        handle: &table::BlockHandle,
+        raw: &[u8],
+    ) -> error::Result<Arc<block::Block>> {
+        let block = block::Block::new(self.block_contents(handle, raw)?)
+            .ok_or_else(|| self.corruption(handle.offset))?;
+        let block = Arc::new(block);
+        if let Some(cache) = self.block_cache.as_ref() {
+            cache.insert(self.cache_key(handle), block.clone());
         }
+        Ok(block)
+    }
+
+    /// Returns the read of the data block at `handle`, which can be done without the run.
+    pub fn block_read(&self, handle: &table::BlockHandle) -> BlockRead {
+        BlockRead {
+            handle: *handle,
+            cache_id: self.cache_id,
+            path: self.tmp_file.clone(),
+            io_mode: self.io_mode,
+            table_cache: self.table_cache.clone(),
+            block_cache: self.block_cache.clone(),
+            verify_checksums: self.verify_checksums,
+            compressor: self.compressor.clone(),
+        }
+    }
+
+    //reads a data block through the block cache, if there is one
+    fn read_data_block(&mut self, handle: &table::BlockHandle) -> error::Result<Arc<block::Block>> {
+        self.block_read(handle).read()
     }
 
+    /// Creates the run file and starts building its blocks. The file is written by `unmap`.
     pub fn map_write(&mut self) {
-        assert!(self.mapping.is_none());
+        assert!(self.mapping.is_none() && self.builder.is_none());
 
         match OpenOptions::new()
             .read(true)
@@ -149,171 +331,285 @@ impl Run {
             }
             Err(e) => panic!("Open temp file failed because {}!", e),
         };
-
-        let len = ENTRY_SIZE * self.max_size as usize;
-
-        let mut fill: Vec<u8> = vec![32; len as usize];
-        fill.push(10);
-        self.mapping_file.as_ref().unwrap().write_all(fill.as_ref());
-
-        unsafe {
-            match Mmap::map(self.mapping_file.as_ref().unwrap()) {
-                Ok(mmap) => {
-                    self.mapping = Some(mmap.make_mut().unwrap());
-                }
-                Err(e) => panic!("Mapping failed because {}", e),
-            }
-        }
+        self.builder = Some(table::TableBuilder::new(
+            self.max_size,
+            self.compressor.clone(),
+        ));
     }
 
+    /// Finishes a run started with `map_write`: the data blocks, filter, properties, index
+    /// and footer are written to the file, through a writable mapping in `IoMode::Mmap` and
+    /// with buffered or direct writes in the other modes.
     pub fn unmap(&mut self) {
-        assert!(self.mapping.is_some());
+        if let Some(mut builder) = self.builder.take() {
+            self.filter.finish();
+            let mut filters = vec![(self.filter.kind().block_name(), self.filter.encode())];
+            if let Some(range_filter) = self.range_filter.as_ref() {
+                filters.push((table::RANGE_FILTER_BLOCK_NAME, range_filter.encode()));
+            }
+            let data = builder.finish(&filters);
+            let written = match self.io_mode {
+                file_io::IoMode::Mmap => self.write_mapped(&data),
+                mode => file_io::write_file(
+                    self.mapping_file.as_mut().unwrap(),
+                    &self.tmp_file,
+                    &data,
+                    mode,
+                ),
+            };
+            if let Err(e) = written {
+                panic!("Writing {:?} failed because {}", self.tmp_file, e);
+            }
+            self.index = builder.index;
+            self.properties = builder.properties;
+        }
 
         self.mapping = None;
         self.mapping_file = None;
     }
 
+    fn write_mapped(&mut self, data: &[u8]) -> io::Result<()> {
+        let file = self.mapping_file.as_ref().unwrap();
+        file.set_len(data.len() as u64)?;
+        let mut mapping = unsafe { MmapOptions::new().map_mut(file)? };
+        mapping.copy_from_slice(data);
+        mapping.flush()?;
+        self.mapping = Some(mapping);
+        Ok(())
+    }
+
+    //index of the only data block that may hold key
+    fn block_for(&self, key: &KeyT) -> Option<usize> {
+        let block = self.index.partition_point(|entry| entry.last_key < *key);
+        if block < self.index.len() {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
     pub fn get(&mut self, key: &KeyT) -> Option<ValueT> {
-        //bloom_filter
-        let read_lock = self.read_write_lock.read().unwrap().clone();
-        if self.bloom_filter.check(key) {
-            //it is very likely that this Run contains target entry. False positives may occur.
-            if *key < self.fence_pointers[0] || *key > self.max_key {
-                return None;
-            }
-            let page_index: usize;
+        self.try_get(key)
+            .unwrap_or_else(|e| panic!("Reading run failed because {}", e))
+    }
 
-            match self.fence_pointers.binary_search(key) {
-                Ok(find) => {
-                    page_index = find;
-                }
-                Err(not) => {
-                    page_index = not - 1;
-                }
-            }
-            assert!(page_index >= 0);
-
-            self.map_read(page_size::get(), page_index * page_size::get());
-
-            let mut val: ValueT = vec![];
-            for i in 0..page_size::get() / ENTRY_SIZE {
-                let offset = ENTRY_SIZE * i as usize;
-                if self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE].to_vec()
-                    == *key
-                {
-                    val = self.mapping.as_ref().unwrap().as_ref()
-                        [offset + KEY_SIZE..offset + KEY_SIZE + VALUE_SIZE]
-                        .to_vec();
+    /// Like `get`, but returns `Error::Corruption` instead of panicking on a damaged block.
+    pub fn try_get(&mut self, key: &KeyT) -> error::Result<Option<ValueT>> {
+        match self.probe(key) {
+            Some(read) => {
+                let res = read.read()?.get(key);
+                if res.is_none() {
+                    //a bloom filter false positive
+                    self.filter_false_positives += 1;
                 }
+                Ok(res)
             }
+            None => Ok(None),
+        }
+    }
 
-            self.unmap();
+    /// Share of the lookups of keys not in the run that the bloom filter let through, as
+    /// measured on the lookups so far.
+    pub fn false_positive_rate(&self) -> f64 {
+        let absent = self.filter_negatives + self.filter_false_positives;
+        if absent == 0 {
+            return 0.0;
+        }
+        self.filter_false_positives as f64 / absent as f64
+    }
 
-            Some(val)
+    /// Returns the read of the only data block that may hold `key`, or None if the bloom
+    /// filter or the key range of the run rules the key out.
+    pub fn probe(&mut self, key: &KeyT) -> Option<BlockRead> {
+        //filter
+        let read_lock = self.read_write_lock.read().unwrap().clone();
+        if self.filter.check(key) {
+            //it is very likely that this Run contains target entry. False positives may occur.
+            let block = match self.block_for(key) {
+                Some(block) if *key >= self.min_key && *key <= self.max_key => block,
+                _ => {
+                    self.filter_false_positives += 1;
+                    return None;
+                }
+            };
+            self.access_count += 1;
+            Some(self.block_read(&self.index[block].handle))
         } else {
             //not in this run according to bloom filter
             //println!("not in this Run according to bloom filter");
+            self.filter_negatives += 1;
             None
         }
     }
 
-    pub fn get_keys(&mut self) -> Vec<KeyT> {
-        self.map_read_default();
-        let mut res: Vec<KeyT> = Vec::new();
-        for i in 0..self.max_size {
-            let offset = ENTRY_SIZE * i as usize;
-            let key = self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE].to_vec();
-            if key[KEY_SIZE - 1] == 32 {
-                break;
-            }
-            res.push(key);
+    /// Looks up sorted `keys`, reading each data block that may hold some of them only once.
+    /// With a `reader` the blocks are read in one batch.
+    pub fn try_multi_get(
+        &mut self,
+        keys: &[KeyT],
+        reader: Option<&mut uring::BatchReader>,
+    ) -> error::Result<Vec<Option<ValueT>>> {
+        let plan = self.plan_multi_get(keys);
+        if plan.handles.is_empty() {
+            return Ok(vec![None; keys.len()]);
         }
-        res
+        let read = match reader {
+            Some(reader) => {
+                let reads: Vec<(&Run, table::BlockHandle)> = plan
+                    .handles
+                    .iter()
+                    .map(|handle| (&*self, *handle))
+                    .collect();
+                read_data_blocks(&reads, reader)?
+            }
+            None => plan
+                .handles
+                .iter()
+                .map(|handle| self.read_data_block(handle))
+                .collect::<error::Result<Vec<Arc<block::Block>>>>()?,
+        };
+        Ok(self.resolve_multi_get(keys, &plan, &read))
     }
 
-    pub fn range(&mut self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
-        let read_lock = self.read_write_lock.read().unwrap().clone();
-        let mut res: Vec<EntryT> = Vec::new();
-
-        let page_start: usize;
-        let page_end: usize;
-
-        if *start > self.max_key || self.fence_pointers[0] > *end {
-            return res;
-        }
-
-        if *start < self.fence_pointers[0] {
-            page_start = 0;
-        } else {
-            match self.fence_pointers.binary_search(start) {
-                Ok(find) => {
-                    page_start = find;
-                }
-                Err(not) => {
-                    page_start = not - 1;
+    /// Checks sorted `keys` against the filter and the key range of the run, and returns the
+    /// data blocks that may hold them, each once. The blocks can be read along with those of
+    /// other runs and handed to `resolve_multi_get`.
+    pub fn plan_multi_get(&mut self, keys: &[KeyT]) -> MultiGetPlan {
+        //keys that pass the bloom filter and the block that may hold each of them
+        let mut wanted: Vec<(usize, usize)> = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if !self.filter.check(key) {
+                self.filter_negatives += 1;
+                continue;
+            }
+            match self.block_for(key) {
+                Some(block) if *key >= self.min_key && *key <= self.max_key => {
+                    wanted.push((i, block))
                 }
+                _ => self.filter_false_positives += 1,
             }
         }
+        //keys are sorted, so the keys of a block are next to each other
+        let mut blocks: Vec<usize> = wanted.iter().map(|(_, block)| *block).collect();
+        blocks.dedup();
+        self.access_count += blocks.len() as u64;
+        MultiGetPlan {
+            wanted: wanted
+                .into_iter()
+                .map(|(i, block)| (i, blocks.binary_search(&block).unwrap()))
+                .collect(),
+            handles: blocks
+                .iter()
+                .map(|block| self.index[*block].handle)
+                .collect(),
+        }
+    }
 
-        if *end > self.max_key {
-            page_end = 0;
-        } else {
-            match self.fence_pointers.binary_search(end) {
-                Ok(find) => {
-                    page_end = find;
-                }
-                Err(not) => {
-                    page_end = not - 1;
-                }
+    /// Looks up the keys planned by `plan_multi_get` in `blocks`, the blocks of
+    /// `plan.handles` in order.
+    pub fn resolve_multi_get(
+        &mut self,
+        keys: &[KeyT],
+        plan: &MultiGetPlan,
+        blocks: &[Arc<block::Block>],
+    ) -> Vec<Option<ValueT>> {
+        let mut res: Vec<Option<ValueT>> = vec![None; keys.len()];
+        for (i, block) in plan.wanted.iter() {
+            res[*i] = blocks[*block].get(&keys[*i]);
+            if res[*i].is_none() {
+                self.filter_false_positives += 1;
             }
         }
+        res
+    }
 
-        assert!(page_start < page_end);
-        let num_pages = page_end - page_start;
-        self.map_read(num_pages * page_size::get(), page_start * page_size::get());
+    pub fn get_keys(&mut self) -> Vec<KeyT> {
+        self.map_read_default()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect()
+    }
 
-        let num_entries = num_pages * page_size::get() / ENTRY_SIZE;
-        res.reserve(num_entries);
+    pub fn range(&mut self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
+        self.try_range(start, end)
+            .unwrap_or_else(|e| panic!("Reading run failed because {}", e))
+    }
 
-        for i in 0..num_entries {
-            let offset = ENTRY_SIZE * i as usize;
-            let entry = EntryT {
-                key: self.mapping.as_ref().unwrap().as_ref()[offset..offset + KEY_SIZE].to_vec(),
-                value: self.mapping.as_ref().unwrap().as_ref()
-                    [offset + KEY_SIZE..offset + KEY_SIZE + VALUE_SIZE]
-                    .to_vec(),
-            };
-            if *start <= entry.key && entry.key <= *end {
-                res.push(entry);
+    /// Returns the handles of the data blocks that may hold keys in `[start, end]`, none if
+    /// the key range or the range filter of the run rules the range out.
+    pub fn range_handles(&self, start: &KeyT, end: &KeyT) -> Vec<table::BlockHandle> {
+        if self.size == 0 || *start > self.max_key || self.min_key > *end {
+            return Vec::new();
+        }
+        if let Some(range_filter) = self.range_filter.as_ref() {
+            if !range_filter.may_contain_range(start, end) {
+                return Vec::new();
             }
         }
+        //blocks from the one that may hold start up to the one that may hold end
+        let block_start = self.block_for(start).unwrap();
+        let block_end = self.block_for(end).unwrap_or(self.index.len() - 1);
+        self.index[block_start..=block_end]
+            .iter()
+            .map(|entry| entry.handle)
+            .collect()
+    }
 
-        self.unmap();
+    pub fn try_range(&mut self, start: &KeyT, end: &KeyT) -> error::Result<Vec<EntryT>> {
+        let handles = self.range_handles(start, end);
+        if handles.is_empty() {
+            return Ok(Vec::new());
+        }
+        let first = handles[0];
+        let last = handles[handles.len() - 1];
+        self.access_count += 1;
+        let mut blocks: Vec<Arc<block::Block>> = Vec::with_capacity(handles.len());
+        if self.block_cache.is_some() {
+            //every block goes through the cache on its own
+            for handle in handles.iter() {
+                blocks.push(self.read_data_block(handle)?);
+            }
+        } else {
+            //one read for all blocks
+            let data = self.map_read(
+                (last.offset + last.size - first.offset) as usize + table::BLOCK_TRAILER_SIZE,
+                first.offset as usize,
+            )?;
+            for handle in handles.iter() {
+                let local = table::BlockHandle {
+                    offset: handle.offset - first.offset,
+                    size: handle.size,
+                };
+                let block =
+                    table::block_data(&data, &local, self.verify_checksums, &*self.compressor)
+                        .and_then(block::Block::new)
+                        .ok_or_else(|| self.corruption(handle.offset))?;
+                blocks.push(Arc::new(block));
+            }
+        }
 
-        res
+        Ok(entries_in_range(&blocks, start, end))
     }
 
     pub fn put(&mut self, entry: &EntryT) {
         assert!(self.size < self.max_size);
         let mut write_lock = self.read_write_lock.write().unwrap();
-        if self.size % (page_size::get() / ENTRY_SIZE) as u64 == 0 {
-            self.fence_pointers.push(entry.key.clone());
+        if self.size == 0 {
+            self.min_key = entry.key.clone();
         }
 
         self.max_key = max(entry.key.clone(), self.max_key.clone());
 
-        let mut entry_data: Vec<u8> = Vec::new();
-        entry_data.extend(entry.key.iter());
-        entry_data.extend(entry.value.iter());
-
-        let mut offset = ENTRY_SIZE * self.size as usize;
-        for byte in entry_data {
-            self.mapping.as_mut().unwrap().as_mut()[offset] = byte;
-            offset += 1;
-        }
+        self.builder
+            .as_mut()
+            .expect("map_write must be called before put")
+            .add(&entry.key, &entry.value);
 
         //set true for this key in this Run. For later more efficient search and avoid unnecessary file I/O operations.
-        self.bloom_filter.set(&entry.key);
+        self.filter.set(&entry.key);
+        if let Some(range_filter) = self.range_filter.as_mut() {
+            range_filter.set(&entry.key);
+        }
         self.size += 1;
     }
 
@@ -322,8 +618,123 @@ impl Run {
     // }
 }
 
+impl Drop for Run {
+    fn drop(&mut self) {
+        //close the mapping so the file can go away once it is removed
+        self.table_cache.evict(self.cache_id);
+    }
+}
+
+/// The data blocks a multi get reads from a run, see `Run::plan_multi_get`.
+pub struct MultiGetPlan {
+    //every key that passed the filter and the position of its block in handles
+    wanted: Vec<(usize, usize)>,
+    pub handles: Vec<table::BlockHandle>,
+}
+
+/// The read of one data block of a run. It holds everything the read needs, so it can be done
+/// away from the run, e.g. on a thread of the worker pool.
+#[derive(Clone)]
+pub struct BlockRead {
+    pub handle: table::BlockHandle,
+    cache_id: u64,
+    path: PathBuf,
+    io_mode: file_io::IoMode,
+    table_cache: Arc<cache::TableCache>,
+    block_cache: Option<Arc<cache::BlockCache>>,
+    verify_checksums: bool,
+    compressor: Arc<dyn compress::Compressor>,
+}
+
+impl BlockRead {
+    /// Reads the block through the block cache, if there is one.
+    pub fn read(&self) -> error::Result<Arc<block::Block>> {
+        let key = cache::CacheKey {
+            run: self.cache_id,
+            offset: self.handle.offset,
+        };
+        if let Some(block) = self.block_cache.as_ref().and_then(|cache| cache.get(&key)) {
+            return Ok(block);
+        }
+        let corruption = || error::Error::Corruption {
+            file: self.path.clone(),
+            offset: self.handle.offset,
+        };
+        let file = self
+            .table_cache
+            .get_or_open(self.cache_id, &self.path, self.io_mode)?;
+        let len = self.handle.size as usize + table::BLOCK_TRAILER_SIZE;
+        if self.handle.offset as usize + len > file.len()? {
+            return Err(corruption());
+        }
+        let raw = file.read(len, self.handle.offset as usize)?;
+        let local = table::BlockHandle {
+            offset: 0,
+            size: self.handle.size,
+        };
+        let block = table::block_data(&raw, &local, self.verify_checksums, &*self.compressor)
+            .and_then(block::Block::new)
+            .ok_or_else(corruption)?;
+        let block = Arc::new(block);
+        if let Some(cache) = self.block_cache.as_ref() {
+            cache.insert(key, block.clone());
+        }
+        Ok(block)
+    }
+}
+
+/// Returns the entries of `blocks` with keys in `[start, end]`.
+pub fn entries_in_range(blocks: &[Arc<block::Block>], start: &KeyT, end: &KeyT) -> Vec<EntryT> {
+    let mut res: Vec<EntryT> = Vec::new();
+    for block in blocks {
+        res.extend(
+            block
+                .entries()
+                .into_iter()
+                .filter(|entry| *start <= entry.key && entry.key <= *end),
+        );
+    }
+    res
+}
+
+/// Reads the data block at the handle of every `(run, handle)` pair. The blocks that are not
+/// in the block cache are read in one batch by `reader`, across all runs, and are added to
+/// the cache. Blocks are returned in the order of `reads`.
+pub fn read_data_blocks(
+    reads: &[(&Run, table::BlockHandle)],
+    reader: &mut uring::BatchReader,
+) -> error::Result<Vec<Arc<block::Block>>> {
+    let mut blocks: Vec<Option<Arc<block::Block>>> = reads
+        .iter()
+        .map(|(run, handle)| run.cached_block(handle))
+        .collect();
+    let mut files: Vec<(Arc<file_io::RunFile>, usize, usize)> = Vec::new();
+    let mut missing: Vec<usize> = Vec::new();
+    for (i, (run, handle)) in reads.iter().enumerate() {
+        if blocks[i].is_none() {
+            let file = run.file()?;
+            let len = handle.size as usize + table::BLOCK_TRAILER_SIZE;
+            if handle.offset as usize + len > file.len()? {
+                return Err(run.corruption(handle.offset));
+            }
+            files.push((file, len, handle.offset as usize));
+            missing.push(i);
+        }
+    }
+    let requests: Vec<(&file_io::RunFile, usize, usize)> = files
+        .iter()
+        .map(|(file, len, offset)| (&**file, *len, *offset))
+        .collect();
+    for (i, raw) in missing.into_iter().zip(reader.read_files(&requests)) {
+        let (run, handle) = &reads[i];
+        blocks[i] = Some(run.decode_data_block(handle, &raw?)?);
+    }
+    Ok(blocks.into_iter().map(Option::unwrap).collect())
+}
+
 #[test]
 fn test_run() {
+    use crate::data_type::{KEY_SIZE, VALUE_SIZE};
     use crate::run;
     use std::fs;
     fs::create_dir("/tmp/unit_test");
@@ -348,6 +759,98 @@ fn test_run() {
     println!("{:?}", run.get_keys());
 }
 
+#[test]
+fn test_run_open() {
+    use crate::data_type::VALUE_SIZE;
+    let _ = fs::create_dir_all("/tmp/unit_test/1");
+    let mut run = Run::new(1000, 8.0, "unit_test", 1, 0);
+    let key = |i: u32| format!("{:08}", i).into_bytes();
+    run.map_write();
+    for i in 0..1000 {
+        run.put(&EntryT {
+            key: key(i * 2),
+            value: vec![33; VALUE_SIZE],
+        });
+    }
+    run.unmap();
+    assert!(run.index.len() > 1);
+
+    let mut reopened = Run::open(1, run.tmp_file.clone()).unwrap();
+    assert_eq!(run.index, reopened.index);
+    assert_eq!(run.properties, reopened.properties);
+    assert_eq!(1000, reopened.size);
+    assert_eq!(key(0), reopened.min_key);
+    assert_eq!(key(1998), reopened.max_key);
+    for i in 0..1000 {
+        assert_eq!(Some(vec![33; VALUE_SIZE]), reopened.get(&key(i * 2)));
+        assert_eq!(None, reopened.get(&key(i * 2 + 1)));
+    }
+    //the filter was restored, so it rules out most of the absent odd keys
+    assert_eq!(
+        1000,
+        reopened.filter_negatives + reopened.filter_false_positives
+    );
+    assert!(reopened.false_positive_rate() < 0.1);
+    let entries = reopened.range(&key(101), &key(1500));
+    assert_eq!(700, entries.len());
+    assert_eq!(key(102), entries[0].key);
+    assert_eq!(1000, reopened.map_read_default().len());
+    let keys: Vec<KeyT> = (0..20).map(|i| key(i * 3)).collect();
+    let mut reader = uring::BatchReader::new(8);
+    for values in vec![
+        reopened.try_multi_get(&keys, None).unwrap(),
+        reopened.try_multi_get(&keys, Some(&mut reader)).unwrap(),
+    ] {
+        for (i, value) in values.into_iter().enumerate() {
+            assert_eq!(i % 2 == 0, value.is_some());
+        }
+    }
+    fs::remove_file(&run.tmp_file).unwrap();
+
+    //anything without a valid footer is rejected
+    fs::write("/tmp/unit_test/1/not_a_run", vec![32; 100]).unwrap();
+    assert!(Run::open(1, PathBuf::from("/tmp/unit_test/1/not_a_run")).is_err());
+}
+
+#[test]
+fn test_corruption() {
+    use crate::data_type::VALUE_SIZE;
+    let _ = fs::create_dir_all("/tmp/unit_test/2");
+    let mut run = Run::new(1000, 8.0, "unit_test", 2, 0);
+    let key = |i: u32| format!("{:08}", i).into_bytes();
+    run.map_write();
+    for i in 0..1000 {
+        run.put(&EntryT {
+            key: key(i),
+            value: vec![33; VALUE_SIZE],
+        });
+    }
+    run.unmap();
+
+    //flip a byte in the middle of the second data block
+    let damaged = run.index[1].handle;
+    let mut data = fs::read(&run.tmp_file).unwrap();
+    data[(damaged.offset + damaged.size / 2) as usize] ^= 0xff;
+    fs::write(&run.tmp_file, &data).unwrap();
+
+    let target = run.index[1].last_key.clone();
+    match run.try_get(&target) {
+        Err(error::Error::Corruption { file, offset }) => {
+            assert_eq!(run.tmp_file, file);
+            assert_eq!(damaged.offset, offset);
+        }
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert!(run.try_range(&key(0), &key(999)).is_err());
+    assert!(run.try_map_read_default().is_err());
+    //other blocks are still readable
+    assert_eq!(Some(vec![33; VALUE_SIZE]), run.try_get(&key(0)).unwrap());
+
+    run.verify_checksums = false;
+    assert!(run.try_get(&key(0)).is_ok());
+    fs::remove_file(&run.tmp_file).unwrap();
+}
+
 #[test]
 fn test_multithreading() {
     println!("test multi threading!");
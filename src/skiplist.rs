@@ -0,0 +1,347 @@
+//Concurrent skiplist memtable. Nodes are linked in with compare-and-swap, so writers insert
+//from several threads at once through &self while readers walk the list without locks.
+//A node is linked into level 0 first and into the levels above afterwards, so a node that
+//can be reached at all is always in the sorted level 0 list.
+//Nodes are never unlinked: a put of a key already in the list swaps the value of its node,
+//and the old value is kept until the list is cleared, as a reader may still be reading it.
+use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::memtable::{entry_charge, ConcurrentMemTable, MemTable};
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+pub const MAX_HEIGHT: usize = 12;
+
+struct Node {
+    key: KeyT,
+    value: AtomicPtr<ValueT>,
+    //successor of the node on every level it is linked into
+    next: Box<[AtomicPtr<Node>]>,
+}
+
+impl Node {
+    fn new(key: KeyT, value: Option<ValueT>, height: usize) -> *mut Node {
+        let value = match value {
+            Some(value) => Box::into_raw(Box::new(value)),
+            None => ptr::null_mut(),
+        };
+        Box::into_raw(Box::new(Node {
+            key,
+            value: AtomicPtr::new(value),
+            next: (0..height)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+        }))
+    }
+
+    fn next(&self, level: usize) -> *mut Node {
+        self.next[level].load(Ordering::Acquire)
+    }
+}
+
+pub struct SkipList {
+    //sentinel before the first node, linked into every level
+    head: *mut Node,
+    len: AtomicUsize,
+    //approximate memory of the nodes and values in bytes
+    size: AtomicUsize,
+    //values replaced by later puts of their key, freed by empty. They stay boxed where
+    //readers may still find them.
+    #[allow(clippy::vec_box)]
+    replaced: Mutex<Vec<Box<ValueT>>>,
+}
+
+//nodes are only freed through &mut self, and all shared state is atomic
+unsafe impl Send for SkipList {}
+unsafe impl Sync for SkipList {}
+
+//height of a new node, every level is linked with probability 1/4 of the one below
+fn random_height() -> usize {
+    let mut height = 1;
+    while height < MAX_HEIGHT && rand::random::<u8>() < 64 {
+        height += 1;
+    }
+    height
+}
+
+impl SkipList {
+    pub fn new() -> SkipList {
+        SkipList {
+            head: Node::new(KeyT::new(), None, MAX_HEIGHT),
+            len: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+            replaced: Mutex::new(Vec::new()),
+        }
+    }
+
+    //the last node on level before key, starting at pred, and its successor
+    fn find_splice(&self, key: &KeyT, level: usize, mut pred: *mut Node) -> (*mut Node, *mut Node) {
+        loop {
+            let next = unsafe { (*pred).next(level) };
+            if next.is_null() || unsafe { &(*next).key } >= key {
+                return (pred, next);
+            }
+            pred = next;
+        }
+    }
+
+    //the first node with a key of at least key, null if there is none
+    fn seek(&self, key: &KeyT) -> *mut Node {
+        let mut pred = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            pred = self.find_splice(key, level, pred).0;
+        }
+        unsafe { (*pred).next(0) }
+    }
+
+    fn replace_value(&self, node: *mut Node, value: ValueT) {
+        //the old value stays until the list is emptied
+        self.size
+            .fetch_add(entry_charge(&[], &value), Ordering::Relaxed);
+        let old = unsafe {
+            (*node)
+                .value
+                .swap(Box::into_raw(Box::new(value)), Ordering::AcqRel)
+        };
+        self.replaced
+            .lock()
+            .unwrap()
+            .push(unsafe { Box::from_raw(old) });
+    }
+
+    /// Inserts `key` or replaces its value. Safe to call from several threads at once.
+    pub fn insert(&self, key: KeyT, value: ValueT) {
+        let mut preds = [self.head; MAX_HEIGHT];
+        let mut succs = [ptr::null_mut(); MAX_HEIGHT];
+        let mut pred = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            let splice = self.find_splice(&key, level, pred);
+            pred = splice.0;
+            preds[level] = splice.0;
+            succs[level] = splice.1;
+        }
+        if !succs[0].is_null() && unsafe { (*succs[0]).key == key } {
+            self.replace_value(succs[0], value);
+            return;
+        }
+
+        let height = random_height();
+        let charge = entry_charge(&key, &value) + height * std::mem::size_of::<usize>();
+        let node = Node::new(key, Some(value), height);
+        for level in 0..height {
+            loop {
+                unsafe { (*node).next[level].store(succs[level], Ordering::Relaxed) };
+                let linked = unsafe {
+                    (*preds[level]).next[level].compare_exchange(
+                        succs[level],
+                        node,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    )
+                };
+                if linked.is_ok() {
+                    break;
+                }
+                //another writer linked a node after pred, look again from there
+                let (pred, succ) = self.find_splice(unsafe { &(*node).key }, level, preds[level]);
+                preds[level] = pred;
+                succs[level] = succ;
+                if level == 0 && !succ.is_null() && unsafe { (*succ).key == (*node).key } {
+                    //the same key was inserted meanwhile, our node was never reachable
+                    let node = unsafe { Box::from_raw(node) };
+                    let value = unsafe { Box::from_raw(node.value.load(Ordering::Relaxed)) };
+                    self.replace_value(succ, *value);
+                    return;
+                }
+            }
+        }
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.size.fetch_add(charge, Ordering::Relaxed);
+    }
+
+    /// Returns the entries in key order. Entries inserted while iterating may be missed.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            node: unsafe { (*self.head).next(0) },
+            list: PhantomData,
+        }
+    }
+
+    /// Returns the entries with keys of at least `start` in key order.
+    pub fn iter_from(&self, start: &KeyT) -> Iter<'_> {
+        Iter {
+            node: self.seek(start),
+            list: PhantomData,
+        }
+    }
+
+    fn free_nodes(&mut self) {
+        let mut node = unsafe { (*self.head).next(0) };
+        while !node.is_null() {
+            let owned = unsafe { Box::from_raw(node) };
+            unsafe { drop(Box::from_raw(owned.value.load(Ordering::Relaxed))) };
+            node = owned.next(0);
+        }
+        for link in unsafe { (*self.head).next.iter() } {
+            link.store(ptr::null_mut(), Ordering::Relaxed);
+        }
+        self.replaced.lock().unwrap().clear();
+        self.len.store(0, Ordering::Relaxed);
+        self.size.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+impl Drop for SkipList {
+    fn drop(&mut self) {
+        self.free_nodes();
+        unsafe { drop(Box::from_raw(self.head)) };
+    }
+}
+
+//nodes live as long as the list, which the iterator borrows
+pub struct Iter<'a> {
+    node: *mut Node,
+    list: PhantomData<&'a SkipList>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = EntryT;
+
+    fn next(&mut self) -> Option<EntryT> {
+        if self.node.is_null() {
+            return None;
+        }
+        let node = unsafe { &*self.node };
+        self.node = node.next(0);
+        Some(EntryT {
+            key: node.key.clone(),
+            value: unsafe { (*node.value.load(Ordering::Acquire)).clone() },
+        })
+    }
+}
+
+impl MemTable for SkipList {
+    fn put(&mut self, key: KeyT, value: ValueT) {
+        self.insert(key, value);
+    }
+
+    fn get(&self, key: &KeyT) -> Option<ValueT> {
+        let node = self.seek(key);
+        if !node.is_null() && unsafe { (*node).key == *key } {
+            Some(unsafe { (*(*node).value.load(Ordering::Acquire)).clone() })
+        } else {
+            None
+        }
+    }
+
+    fn range(&self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
+        self.iter_from(start)
+            .take_while(|entry| entry.key <= *end)
+            .collect()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = EntryT> + '_> {
+        Box::new(SkipList::iter(self))
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    fn empty(&mut self) {
+        self.free_nodes();
+    }
+
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentMemTable> {
+        Some(self)
+    }
+}
+
+impl ConcurrentMemTable for SkipList {
+    fn insert(&self, key: KeyT, value: ValueT) {
+        SkipList::insert(self, key, value);
+    }
+}
+
+#[test]
+fn test_skiplist() {
+    let mut list = SkipList::new();
+    let key = |i: u32| format!("{:04}", i).into_bytes();
+    for i in (0..100).rev() {
+        list.put(key(i), key(i));
+    }
+    list.put(key(7), b"seven".to_vec());
+    assert_eq!(100, list.len());
+    assert!(list.approximate_size() > 100 * entry_charge(&key(0), &key(0)));
+    assert_eq!(Some(b"seven".to_vec()), list.get(&key(7)));
+    assert_eq!(None, list.get(&key(100)));
+    let entries: Vec<EntryT> = MemTable::iter(&list).collect();
+    assert!(entries.windows(2).all(|pair| pair[0].key < pair[1].key));
+    assert_eq!(
+        (10..=20).map(key).collect::<Vec<KeyT>>(),
+        list.range(&key(10), &key(20))
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<KeyT>>()
+    );
+    list.empty();
+    assert_eq!(0, list.len());
+    assert_eq!(0, list.approximate_size());
+    assert_eq!(None, list.get(&key(7)));
+    list.put(key(1), key(1));
+    assert_eq!(Some(key(1)), list.get(&key(1)));
+}
+
+#[test]
+fn test_concurrent_skiplist() {
+    use std::sync::Arc;
+    use std::thread;
+    let list = Arc::new(SkipList::new());
+    let key = |i: u32| format!("{:06}", i).into_bytes();
+    let writers: Vec<thread::JoinHandle<()>> = (0..4u32)
+        .map(|t| {
+            let list = list.clone();
+            thread::spawn(move || {
+                for i in 0..5000 {
+                    //every key is written by two threads
+                    list.insert(key(i * 2 + t % 2), t.to_be_bytes().to_vec());
+                }
+            })
+        })
+        .collect();
+    //readers see sorted, unique keys while the writers insert
+    let reader = {
+        let list = list.clone();
+        thread::spawn(move || {
+            for _ in 0..20 {
+                let keys: Vec<KeyT> = list.iter().map(|entry| entry.key).collect();
+                assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            }
+        })
+    };
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    reader.join().unwrap();
+    assert_eq!(10000, list.len());
+    let entries: Vec<EntryT> = list.iter().collect();
+    assert_eq!(
+        (0..10000).map(key).collect::<Vec<KeyT>>(),
+        entries.into_iter().map(|e| e.key).collect::<Vec<KeyT>>()
+    );
+    for i in 0..10000 {
+        let value = list.get(&key(i)).unwrap();
+        assert!(value == (i % 2).to_be_bytes() || value == (i % 2 + 2).to_be_bytes());
+    }
+}
@@ -0,0 +1,30 @@
+//Statistics of a tree, returned by LSMTree::stats.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LevelStats {
+    //runs of the level, including the fragments of guards
+    pub num_runs: usize,
+    pub num_entries: u64,
+    //bits per entry the filters of new runs of the level get
+    pub filter_bits_per_entry: f32,
+    //memory of the filters of the runs of the level
+    pub filter_bits: u64,
+    //share of lookups of keys not in a run that its filter let through, measured so far
+    pub false_positive_rate: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    pub levels: Vec<LevelStats>,
+}
+
+impl TreeStats {
+    /// Memory of all filters in bits.
+    pub fn filter_bits(&self) -> u64 {
+        self.levels.iter().map(|level| level.filter_bits).sum()
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.levels.iter().map(|level| level.num_entries).sum()
+    }
+}
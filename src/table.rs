@@ -0,0 +1,424 @@
+//On-disk layout of a run file:
+//  [data block 0]...[data block n-1]
+//  [filter blocks][properties block]
+//  [metaindex block]   name of every meta block -> handle
+//  [index block]       last key of every data block -> handle
+//  [footer]            metaindex handle, index handle, format version, magic number
+//Every block is followed by a trailer, which a handle's size does not include: the id of
+//the codec the block is compressed with and the checksum of the stored block and that id.
+//Only data blocks are compressed.
+//Data, index, metaindex and properties blocks use the block format of block.rs.
+use crate::block::{get_varint, put_varint, Block, BlockBuilder};
+use crate::compress;
+use crate::compress::Compressor;
+use crate::crc32c;
+use crate::data_type::KeyT;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+pub static TABLE_MAGIC: u64 = 0x6273_6d74_5f6b_7673;
+//no run file of another version has been released, the next change to the layout bumps it
+pub static FORMAT_VERSION: u32 = 1;
+//two fixed width handles, the version and the magic number
+pub static FOOTER_SIZE: usize = 44;
+//codec id and masked crc32c
+pub static BLOCK_TRAILER_SIZE: usize = 5;
+//data blocks are cut once they reach this size
+pub static BLOCK_SIZE: usize = 4096;
+pub static FILTER_BLOCK_NAME: &str = "filter.bloom";
+pub static XOR_FILTER_BLOCK_NAME: &str = "filter.xor";
+pub static RANGE_FILTER_BLOCK_NAME: &str = "filter.range";
+pub static PROPERTIES_BLOCK_NAME: &str = "properties";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlockHandle {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::new();
+        put_varint(&mut res, self.offset);
+        put_varint(&mut res, self.size);
+        res
+    }
+
+    pub fn decode(src: &[u8]) -> Option<BlockHandle> {
+        let (offset, n) = get_varint(src)?;
+        let (size, _) = get_varint(&src[n..])?;
+        Some(BlockHandle { offset, size })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    pub metaindex: BlockHandle,
+    pub index: BlockHandle,
+    pub version: u32,
+}
+
+impl Footer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res: Vec<u8> = Vec::with_capacity(FOOTER_SIZE);
+        for handle in [self.metaindex, self.index].iter() {
+            res.extend_from_slice(&handle.offset.to_le_bytes());
+            res.extend_from_slice(&handle.size.to_le_bytes());
+        }
+        res.extend_from_slice(&self.version.to_le_bytes());
+        res.extend_from_slice(&TABLE_MAGIC.to_le_bytes());
+        res
+    }
+
+    /// Returns None if `src` is not the footer of a run file of a known format version.
+    pub fn decode(src: &[u8]) -> Option<Footer> {
+        if src.len() != FOOTER_SIZE {
+            return None;
+        }
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(src[offset..offset + 8].try_into().unwrap());
+        let version = u32::from_le_bytes(src[32..36].try_into().unwrap());
+        if u64_at(36) != TABLE_MAGIC || version != FORMAT_VERSION {
+            return None;
+        }
+        Some(Footer {
+            metaindex: BlockHandle {
+                offset: u64_at(0),
+                size: u64_at(8),
+            },
+            index: BlockHandle {
+                offset: u64_at(16),
+                size: u64_at(24),
+            },
+            version,
+        })
+    }
+}
+
+/// Summary of a run file, stored in its properties block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    pub num_entries: u64,
+    //capacity the run was created with
+    pub max_entries: u64,
+    pub num_data_blocks: u64,
+    //size of the data blocks on disk and before compression
+    pub data_size: u64,
+    pub raw_data_size: u64,
+    //name of the codec of the data blocks
+    pub compression: String,
+    pub index_size: u64,
+    pub filter_size: u64,
+    pub min_key: KeyT,
+    pub max_key: KeyT,
+}
+
+impl TableProperties {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut block = BlockBuilder::new();
+        let add_u64 = |block: &mut BlockBuilder, name: &str, value: u64| {
+            let mut encoded: Vec<u8> = Vec::new();
+            put_varint(&mut encoded, value);
+            block.add(name.as_bytes(), &encoded);
+        };
+        //names must be added in sorted order
+        block.add(b"compression", self.compression.as_bytes());
+        add_u64(&mut block, "data_size", self.data_size);
+        add_u64(&mut block, "filter_size", self.filter_size);
+        add_u64(&mut block, "index_size", self.index_size);
+        add_u64(&mut block, "max_entries", self.max_entries);
+        block.add(b"max_key", &self.max_key);
+        block.add(b"min_key", &self.min_key);
+        add_u64(&mut block, "num_data_blocks", self.num_data_blocks);
+        add_u64(&mut block, "num_entries", self.num_entries);
+        add_u64(&mut block, "raw_data_size", self.raw_data_size);
+        block.finish()
+    }
+
+    pub fn decode(block: Block) -> TableProperties {
+        let mut res = TableProperties::default();
+        for entry in block.entries() {
+            let value = get_varint(&entry.value)
+                .map(|(value, _)| value)
+                .unwrap_or(0);
+            match entry.key.as_slice() {
+                b"compression" => {
+                    res.compression = String::from_utf8_lossy(&entry.value).into_owned()
+                }
+                b"data_size" => res.data_size = value,
+                b"filter_size" => res.filter_size = value,
+                b"index_size" => res.index_size = value,
+                b"max_entries" => res.max_entries = value,
+                b"max_key" => res.max_key = entry.value,
+                b"min_key" => res.min_key = entry.value,
+                b"num_data_blocks" => res.num_data_blocks = value,
+                b"num_entries" => res.num_entries = value,
+                b"raw_data_size" => res.raw_data_size = value,
+                //written by a newer version, ignore
+                _ => {}
+            }
+        }
+        res
+    }
+
+    /// Size of the data blocks before compression divided by their size on disk.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.data_size == 0 {
+            1.0
+        } else {
+            self.raw_data_size as f64 / self.data_size as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub last_key: KeyT,
+    pub handle: BlockHandle,
+}
+
+/// Decodes the index block, or any other block whose values are block handles. Returns None
+/// if a value is not a block handle.
+pub fn decode_index(block: Block) -> Option<Vec<IndexEntry>> {
+    block
+        .entries()
+        .into_iter()
+        .map(|entry| {
+            Some(IndexEntry {
+                handle: BlockHandle::decode(&entry.value)?,
+                last_key: entry.key,
+            })
+        })
+        .collect()
+}
+
+/// Builds a run file in memory. Entries are added in key order, then `finish` lays out the
+/// meta blocks, index and footer behind the data blocks.
+pub struct TableBuilder {
+    data: Vec<u8>,
+    block: BlockBuilder,
+    compressor: Arc<dyn Compressor>,
+    pub index: Vec<IndexEntry>,
+    pub properties: TableProperties,
+}
+
+impl TableBuilder {
+    pub fn new(max_entries: u64, compressor: Arc<dyn Compressor>) -> TableBuilder {
+        TableBuilder {
+            data: Vec::new(),
+            block: BlockBuilder::new(),
+            index: Vec::new(),
+            properties: TableProperties {
+                max_entries,
+                compression: compressor.name().to_string(),
+                ..TableProperties::default()
+            },
+            compressor,
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        if self.properties.num_entries == 0 {
+            self.properties.min_key = key.to_vec();
+        }
+        self.properties.max_key = key.to_vec();
+        self.properties.num_entries += 1;
+        self.block.add(key, value);
+        if self.block.size_estimate() >= BLOCK_SIZE {
+            self.flush_block();
+        }
+    }
+
+    fn flush_block(&mut self) {
+        if self.block.is_empty() {
+            return;
+        }
+        let last_key = self.block.last_key().clone();
+        let block = self.block.finish();
+        self.properties.raw_data_size += block.len() as u64;
+        let compressed = self.compressor.compress(&block);
+        //keep the block uncompressed unless that saves at least 1/8 of it, or if it is too
+        //large to be decompressed
+        let handle = if compressed.len() < block.len() - block.len() / 8
+            && block.len() <= compress::MAX_BLOCK_SIZE
+        {
+            let id = self.compressor.id();
+            self.write_block(compressed, id)
+        } else {
+            self.write_block(block, compress::NO_COMPRESSION)
+        };
+        self.index.push(IndexEntry { last_key, handle });
+        self.properties.num_data_blocks += 1;
+    }
+
+    fn write_block(&mut self, block: Vec<u8>, codec: u8) -> BlockHandle {
+        let handle = BlockHandle {
+            offset: self.data.len() as u64,
+            size: block.len() as u64,
+        };
+        self.data.extend_from_slice(&block);
+        self.data.push(codec);
+        let crc = crc32c::mask(crc32c::extend(crc32c::value(&block), &[codec]));
+        self.data.extend_from_slice(&crc.to_le_bytes());
+        handle
+    }
+
+    /// Returns the complete file, with every filter stored as a meta block under its name.
+    pub fn finish(&mut self, filters: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        self.flush_block();
+        self.properties.data_size = self.data.len() as u64;
+        self.properties.filter_size = filters.iter().map(|(_, f)| f.len() as u64).sum();
+        //the metaindex is a block, its names must be added in order
+        let mut filter_handles: Vec<(&str, BlockHandle)> = filters
+            .iter()
+            .map(|(name, f)| (*name, self.write_block(f.clone(), compress::NO_COMPRESSION)))
+            .collect();
+        filter_handles.sort_by_key(|(name, _)| *name);
+
+        let mut index_block = BlockBuilder::new();
+        for entry in self.index.iter() {
+            index_block.add(&entry.last_key, &entry.handle.encode());
+        }
+        let index_block = index_block.finish();
+        self.properties.index_size = index_block.len() as u64;
+        let properties_handle =
+            self.write_block(self.properties.encode(), compress::NO_COMPRESSION);
+
+        let mut metaindex = BlockBuilder::new();
+        for (name, handle) in filter_handles.iter() {
+            metaindex.add(name.as_bytes(), &handle.encode());
+        }
+        metaindex.add(
+            PROPERTIES_BLOCK_NAME.as_bytes(),
+            &properties_handle.encode(),
+        );
+        let metaindex_handle = self.write_block(metaindex.finish(), compress::NO_COMPRESSION);
+        let index_handle = self.write_block(index_block, compress::NO_COMPRESSION);
+
+        let footer = Footer {
+            metaindex: metaindex_handle,
+            index: index_handle,
+            version: FORMAT_VERSION,
+        };
+        self.data.extend_from_slice(&footer.encode());
+        std::mem::take(&mut self.data)
+    }
+}
+
+/// Returns the uncompressed contents of the block at `handle` within `data`, or None if the
+/// block is out of bounds, cannot be decompressed or, when `verify` is set, does not match
+/// its checksum. Blocks written by `codec` are decompressed by it, blocks of any other codec
+/// by the built-in codec with the id in the trailer.
+pub fn block_data(
+    data: &[u8],
+    handle: &BlockHandle,
+    verify: bool,
+    codec: &dyn Compressor,
+) -> Option<Vec<u8>> {
+    let start = handle.offset as usize;
+    let end = start.checked_add(handle.size as usize)?;
+    if end + BLOCK_TRAILER_SIZE > data.len() {
+        return None;
+    }
+    let contents = &data[start..end];
+    let id = data[end];
+    if verify {
+        let stored =
+            u32::from_le_bytes(data[end + 1..end + BLOCK_TRAILER_SIZE].try_into().unwrap());
+        if crc32c::unmask(stored) != crc32c::extend(crc32c::value(contents), &[id]) {
+            return None;
+        }
+    }
+    if id == compress::NO_COMPRESSION {
+        Some(contents.to_vec())
+    } else if id == codec.id() {
+        codec.decompress(contents)
+    } else {
+        compress::by_id(id)?.decompress(contents)
+    }
+}
+
+#[test]
+fn test_footer() {
+    let footer = Footer {
+        metaindex: BlockHandle {
+            offset: 4096,
+            size: 40,
+        },
+        index: BlockHandle {
+            offset: 4136,
+            size: 100,
+        },
+        version: FORMAT_VERSION,
+    };
+    let encoded = footer.encode();
+    assert_eq!(FOOTER_SIZE, encoded.len());
+    assert_eq!(Some(footer), Footer::decode(&encoded));
+    assert_eq!(None, Footer::decode(&vec![0; FOOTER_SIZE]));
+    let newer = Footer {
+        version: FORMAT_VERSION + 1,
+        ..footer
+    };
+    assert_eq!(None, Footer::decode(&newer.encode()));
+}
+
+#[test]
+fn test_table_builder() {
+    let mut builder = TableBuilder::new(1000, Arc::new(compress::NoCompression));
+    for i in 0..1000u32 {
+        builder.add(&i.to_be_bytes(), &[b'v'; 24]);
+    }
+    let file = builder.finish(&[(FILTER_BLOCK_NAME, b"filter".to_vec())]);
+    let footer = Footer::decode(&file[file.len() - FOOTER_SIZE..]).unwrap();
+    let index = decode_index(
+        Block::new(block_data(&file, &footer.index, true, &compress::NoCompression).unwrap())
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(builder.index, index);
+    assert!(index.len() > 1);
+    assert_eq!(
+        999u32.to_be_bytes().to_vec(),
+        index[index.len() - 1].last_key
+    );
+
+    let meta = decode_index(
+        Block::new(block_data(&file, &footer.metaindex, true, &compress::NoCompression).unwrap())
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(FILTER_BLOCK_NAME.as_bytes(), meta[0].last_key.as_slice());
+    assert_eq!(
+        b"filter".to_vec(),
+        block_data(&file, &meta[0].handle, true, &compress::NoCompression).unwrap()
+    );
+    let properties = TableProperties::decode(
+        Block::new(block_data(&file, &meta[1].handle, true, &compress::NoCompression).unwrap())
+            .unwrap(),
+    );
+    assert_eq!(builder.properties, properties);
+    assert_eq!(1000, properties.num_entries);
+    assert_eq!(index.len() as u64, properties.num_data_blocks);
+}
+
+#[test]
+fn test_compressed_table() {
+    let mut builder = TableBuilder::new(1000, Arc::new(compress::Lz::high()));
+    for i in 0..1000u32 {
+        let value = format!("{{\"id\":{},\"ok\":true}}", i);
+        builder.add(&i.to_be_bytes(), value.as_bytes());
+    }
+    let file = builder.finish(&[(FILTER_BLOCK_NAME, Vec::new())]);
+    let properties = builder.properties.clone();
+    assert_eq!("lz-high", properties.compression);
+    assert!(properties.compression_ratio() > 1.5);
+    assert!(properties.data_size < properties.raw_data_size);
+
+    //the blocks are read back by the built-in codec with the id in their trailer
+    let mut entries = 0;
+    for entry in builder.index.iter() {
+        let contents = block_data(&file, &entry.handle, true, &compress::NoCompression).unwrap();
+        entries += Block::new(contents).unwrap().entries().len();
+    }
+    assert_eq!(1000, entries);
+}
@@ -0,0 +1,574 @@
+//LSM-trie (see papers/atc15-paper-wu.pdf): a table type for point lookups only.
+//Keys are hashed and tables are organized into a trie of hash prefixes. Compacting a node
+//splits its tables by the next bits of the hash into its children, and every table is split
+//into buckets by the low bits of the hash with one bloom filter per bucket kept in memory.
+//A get checks the filters of one bucket along the trie path and reads about one bucket.
+use crate::bloom_filter::BloomFilter;
+use crate::buffer;
+use crate::data_type;
+use crate::data_type::{EntryT, KeyT, ValueT, ENTRY_SIZE, KEY_SIZE, TOMBSTONE, VALUE_SIZE};
+use crate::memtable::MemTable;
+use crate::merge;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+//every node has 8 children, chosen by the next 3 bits of the hash
+pub static TRIE_FANOUT: usize = 8;
+static TRIE_BITS: u32 = 3;
+
+//tables are placed by the hash, so it must not change between builds
+pub fn hash_key(key: &KeyT) -> u64 {
+    BloomFilter::hash_key(key)
+}
+
+//child of the root path of hash at the given depth
+fn prefix(hash: u64, depth: usize) -> u64 {
+    if depth == 0 {
+        0
+    } else {
+        hash >> (64 - TRIE_BITS * depth as u32)
+    }
+}
+
+/// A hash-partitioned table. The file holds the buckets back to back followed by the
+/// bucket offsets, so reading one bucket is a single read.
+pub struct HTable {
+    pub file: PathBuf,
+    //bucket i is in [bucket_offsets[i], bucket_offsets[i + 1])
+    pub bucket_offsets: Vec<u64>,
+    pub size: u64,
+}
+
+impl HTable {
+    /// Writes `entries` into a new table file and returns it with one bloom filter per bucket.
+    pub fn create(
+        file: PathBuf,
+        entries: &[EntryT],
+        num_buckets: usize,
+        bf_bits_per_entry: f32,
+    ) -> io::Result<(HTable, Vec<BloomFilter>)> {
+        let mut buckets: Vec<Vec<&EntryT>> = vec![Vec::new(); num_buckets];
+        for entry in entries {
+            buckets[bucket_index(hash_key(&entry.key), num_buckets)].push(entry);
+        }
+        let mut data: Vec<u8> = Vec::with_capacity(entries.len() * ENTRY_SIZE);
+        let mut bucket_offsets: Vec<u64> = Vec::with_capacity(num_buckets + 1);
+        let mut blooms: Vec<BloomFilter> = Vec::with_capacity(num_buckets);
+        for bucket in buckets.iter() {
+            bucket_offsets.push(data.len() as u64);
+            let mut bloom = new_bloom(bucket.len(), bf_bits_per_entry);
+            for entry in bucket.iter() {
+                data.extend(entry.key.iter());
+                data.extend(entry.value.iter());
+                bloom.set(&entry.key);
+            }
+            blooms.push(bloom);
+        }
+        bucket_offsets.push(data.len() as u64);
+        for offset in bucket_offsets.iter() {
+            data.extend_from_slice(&offset.to_le_bytes());
+        }
+        data.extend_from_slice(&(num_buckets as u64).to_le_bytes());
+        fs::write(&file, data)?;
+        Ok((
+            HTable {
+                file,
+                bucket_offsets,
+                size: entries.len() as u64,
+            },
+            blooms,
+        ))
+    }
+
+    /// Opens an existing table file and rebuilds the bloom filters of its buckets.
+    pub fn open(file: PathBuf, bf_bits_per_entry: f32) -> io::Result<(HTable, Vec<BloomFilter>)> {
+        let data = fs::read(&file)?;
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a trie table", file),
+            )
+        };
+        let word_at = |offset: usize| {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(&data[offset..offset + 8]);
+            u64::from_le_bytes(word)
+        };
+        if data.len() < 8 {
+            return Err(invalid());
+        }
+        let num_buckets = word_at(data.len() - 8) as usize;
+        if num_buckets == 0 {
+            return Err(invalid());
+        }
+        //the bucket offsets and their count end the file
+        let offsets_start = num_buckets
+            .checked_add(2)
+            .and_then(|words| words.checked_mul(8))
+            .and_then(|trailer| data.len().checked_sub(trailer))
+            .ok_or_else(invalid)?;
+        let bucket_offsets: Vec<u64> = (0..num_buckets + 1)
+            .map(|i| word_at(offsets_start + 8 * i))
+            .collect();
+        //buckets of whole entries back to back, up to the offsets
+        if bucket_offsets[0] != 0
+            || bucket_offsets[num_buckets] != offsets_start as u64
+            || bucket_offsets
+                .windows(2)
+                .any(|pair| pair[0] > pair[1] || (pair[1] - pair[0]) % ENTRY_SIZE as u64 != 0)
+        {
+            return Err(invalid());
+        }
+        let mut table = HTable {
+            file,
+            bucket_offsets,
+            size: 0,
+        };
+        let mut blooms: Vec<BloomFilter> = Vec::with_capacity(num_buckets);
+        for bucket in 0..num_buckets {
+            let start = table.bucket_offsets[bucket] as usize;
+            let end = table.bucket_offsets[bucket + 1] as usize;
+            let entries = decode_entries(&data[start..end]);
+            let mut bloom = new_bloom(entries.len(), bf_bits_per_entry);
+            for entry in entries.iter() {
+                bloom.set(&entry.key);
+            }
+            table.size += entries.len() as u64;
+            blooms.push(bloom);
+        }
+        Ok((table, blooms))
+    }
+
+    pub fn num_buckets(&self) -> usize {
+        self.bucket_offsets.len() - 1
+    }
+
+    pub fn read_bucket(&self, bucket: usize) -> io::Result<Vec<EntryT>> {
+        let start = self.bucket_offsets[bucket];
+        let mut data: Vec<u8> = vec![0; (self.bucket_offsets[bucket + 1] - start) as usize];
+        File::open(&self.file)?.read_exact_at(&mut data, start)?;
+        Ok(decode_entries(&data))
+    }
+
+    //every entry of the table sorted by key, used by compactions
+    pub fn read_all(&self) -> io::Result<Vec<EntryT>> {
+        let mut res: Vec<EntryT> = Vec::with_capacity(self.size as usize);
+        for bucket in 0..self.num_buckets() {
+            res.extend(self.read_bucket(bucket)?);
+        }
+        res.sort();
+        Ok(res)
+    }
+}
+
+fn bucket_index(hash: u64, num_buckets: usize) -> usize {
+    //the high bits pick the trie path, so buckets use the low ones
+    ((hash & 0xffff_ffff) % num_buckets as u64) as usize
+}
+
+fn new_bloom(entries: usize, bf_bits_per_entry: f32) -> BloomFilter {
+    BloomFilter::with_bits_per_entry(entries.max(1) as u64, bf_bits_per_entry)
+}
+
+fn decode_entries(data: &[u8]) -> Vec<EntryT> {
+    data.chunks(ENTRY_SIZE)
+        .map(|chunk| EntryT {
+            key: chunk[..KEY_SIZE].to_vec(),
+            value: chunk[KEY_SIZE..KEY_SIZE + VALUE_SIZE].to_vec(),
+        })
+        .collect()
+}
+
+/// A trie node. The bloom filters of all its tables are clustered by bucket, so a lookup
+/// walks one contiguous list of filters per node.
+pub struct TrieNode {
+    //newest first
+    pub tables: VecDeque<HTable>,
+    //filters[bucket][table], tables in the same order as above
+    pub filters: Vec<Vec<BloomFilter>>,
+}
+
+impl TrieNode {
+    pub fn new(num_buckets: usize) -> TrieNode {
+        TrieNode {
+            tables: VecDeque::new(),
+            filters: (0..num_buckets).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn push_front(&mut self, table: HTable, blooms: Vec<BloomFilter>) {
+        for (bucket, bloom) in blooms.into_iter().enumerate() {
+            self.filters[bucket].insert(0, bloom);
+        }
+        self.tables.push_front(table);
+    }
+
+    pub fn push_back(&mut self, table: HTable, blooms: Vec<BloomFilter>) {
+        for (bucket, bloom) in blooms.into_iter().enumerate() {
+            self.filters[bucket].push(bloom);
+        }
+        self.tables.push_back(table);
+    }
+
+    pub fn get(&self, key: &KeyT, hash: u64) -> io::Result<Option<ValueT>> {
+        let bucket = bucket_index(hash, self.filters.len());
+        for (table, bloom) in self.filters[bucket].iter().enumerate() {
+            if !bloom.check(key) {
+                continue;
+            }
+            for entry in self.tables[table].read_bucket(bucket)? {
+                if entry.key == *key {
+                    return Ok(Some(entry.value));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn take_tables(&mut self) -> Vec<HTable> {
+        for filters in self.filters.iter_mut() {
+            filters.clear();
+        }
+        self.tables.drain(..).collect()
+    }
+}
+
+pub struct LSMTrie {
+    buffer: buffer::Buffer,
+    //nodes[depth] maps a hash prefix to its node
+    nodes: Vec<HashMap<u64, TrieNode>>,
+    num_buckets: usize,
+    bf_bits_per_entry: f32,
+    tree_name: String,
+    next_table_id: u64,
+}
+
+impl LSMTrie {
+    /// Returns a hash-based LSM-trie for point lookups
+    ///
+    /// # Arguments
+    ///
+    /// * `buf_max_entries` - Max number of entries in memory buffer, also the size of every table
+    /// * `dep` - depth of the trie
+    /// * `bf_bits_per_entry` - Used for bloom filter size initialization
+    ///
+    /// # Example
+    ///
+    /// ```
+    ///
+    /// use lsm_kv::trie;
+    /// let mut trie = trie::LSMTrie::new(100, 3, 0.5, "trie_doc_test".to_string());
+    /// trie.put("hello", "world");
+    /// assert_eq!(trie.get("hello"), Some("world".to_string()));
+    /// assert!(trie.range("a", "z").is_err());
+    /// trie.clear();
+    ///
+    /// ```
+    pub fn new(
+        buf_max_entries: u64,
+        dep: u64,
+        bf_bits_per_entry: f32,
+        tree_name: String,
+    ) -> LSMTrie {
+        assert!(dep > 0 && TRIE_BITS as u64 * dep <= 64);
+        //a table fills roughly one page per bucket
+        let entries_per_page = (page_size::get() / ENTRY_SIZE) as u64;
+        let num_buckets = std::cmp::max(1, buf_max_entries / entries_per_page) as usize;
+        let _ = fs::create_dir_all(format!("/tmp/{}/", tree_name));
+        LSMTrie {
+            buffer: buffer::Buffer::new(buf_max_entries as usize),
+            nodes: (0..dep).map(|_| HashMap::new()).collect(),
+            num_buckets,
+            bf_bits_per_entry,
+            tree_name,
+            next_table_id: 0,
+        }
+    }
+
+    fn table_file(&mut self) -> PathBuf {
+        let id = self.next_table_id;
+        self.next_table_id += 1;
+        PathBuf::from(format!("/tmp/{}/htable-{}.txt", self.tree_name, id))
+    }
+
+    pub fn put(&mut self, key_str: &str, value_str: &str) -> bool {
+        let key = data_type::fill_str_with_witespace(key_str, KEY_SIZE);
+        let value = data_type::fill_str_with_witespace(value_str, VALUE_SIZE);
+        if self.buffer.full() {
+            if let Err(e) = self.flush() {
+                panic!("could not flush trie buffer due to {}", e);
+            }
+        }
+        self.buffer.put(key, value);
+        true
+    }
+
+    pub fn get(&mut self, key_str: &str) -> Option<String> {
+        let key = data_type::fill_str_with_witespace(key_str, KEY_SIZE);
+        let value = match self.buffer.get(&key) {
+            Some(v) => Some(v),
+            None => {
+                let hash = hash_key(&key);
+                let mut found: Option<ValueT> = None;
+                //at most one node per depth can hold the key
+                for depth in 0..self.nodes.len() {
+                    if let Some(node) = self.nodes[depth].get(&prefix(hash, depth)) {
+                        match node.get(&key, hash) {
+                            Ok(Some(v)) => {
+                                found = Some(v);
+                                break;
+                            }
+                            Ok(None) => {}
+                            Err(e) => panic!("could not read trie table due to {}", e),
+                        }
+                    }
+                }
+                found
+            }
+        };
+        match value {
+            Some(v) => {
+                let res = data_type::vec_u8_to_str(&v);
+                if res != TOMBSTONE {
+                    Some(res)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        }
+    }
+
+    pub fn del(&mut self, key_str: &str) {
+        self.put(key_str, TOMBSTONE);
+    }
+
+    /// Hashing destroys the key order, so range queries are not supported by this table type.
+    pub fn range(&mut self, _start_str: &str, _end_str: &str) -> io::Result<Vec<String>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "range is not supported by LSM-trie tables",
+        ))
+    }
+
+    //writes the buffer as a new table of the root
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.entries.is_empty() {
+            return Ok(());
+        }
+        self.compact(0, 0)?;
+        let entries: Vec<EntryT> = self.buffer.entries.iter().cloned().collect();
+        let file = self.table_file();
+        let (table, blooms) =
+            HTable::create(file, &entries, self.num_buckets, self.bf_bits_per_entry)?;
+        let num_buckets = self.num_buckets;
+        self.nodes[0]
+            .entry(0)
+            .or_insert_with(|| TrieNode::new(num_buckets))
+            .push_front(table, blooms);
+        self.buffer.empty();
+        Ok(())
+    }
+
+    //makes room in a full node by moving its tables down to its children
+    fn compact(&mut self, depth: usize, node_prefix: u64) -> io::Result<()> {
+        let full = match self.nodes[depth].get(&node_prefix) {
+            Some(node) => node.tables.len() >= TRIE_FANOUT,
+            None => false,
+        };
+        if !full {
+            return Ok(());
+        }
+        let last = depth == self.nodes.len() - 1;
+        if !last {
+            for child in 0..TRIE_FANOUT as u64 {
+                self.compact(depth + 1, (node_prefix << TRIE_BITS) | child)?;
+            }
+        }
+
+        let tables = self.nodes[depth]
+            .get_mut(&node_prefix)
+            .unwrap()
+            .take_tables();
+        let mut merge_ctx = merge::MergeContextT::new();
+        for table in tables.iter() {
+            let entries = table.read_all()?;
+            let len = entries.len();
+            merge_ctx.add(entries, len);
+        }
+        let mut children: Vec<Vec<EntryT>> = vec![Vec::new(); TRIE_FANOUT];
+        while !merge_ctx.done() {
+            let entry = merge_ctx.next();
+            if last {
+                //there is nothing older below, tombstones can go
+                if entry.value != TOMBSTONE.as_bytes() {
+                    children[0].push(entry);
+                }
+            } else {
+                let child = prefix(hash_key(&entry.key), depth + 1) as usize % TRIE_FANOUT;
+                children[child].push(entry);
+            }
+        }
+        for (child, entries) in children.into_iter().enumerate() {
+            if entries.is_empty() {
+                continue;
+            }
+            let file = self.table_file();
+            let (table, blooms) =
+                HTable::create(file, &entries, self.num_buckets, self.bf_bits_per_entry)?;
+            let (target_depth, target_prefix) = if last {
+                (depth, node_prefix)
+            } else {
+                (depth + 1, (node_prefix << TRIE_BITS) | child as u64)
+            };
+            let num_buckets = self.num_buckets;
+            self.nodes[target_depth]
+                .entry(target_prefix)
+                .or_insert_with(|| TrieNode::new(num_buckets))
+                .push_front(table, blooms);
+        }
+        for table in tables {
+            fs::remove_file(&table.file)?;
+        }
+        Ok(())
+    }
+
+    //one line per node: "<depth> <prefix> <table files, newest first>"
+    fn manifest(&self) -> PathBuf {
+        PathBuf::from(format!("/tmp/{}/trie_manifest", self.tree_name))
+    }
+
+    pub fn close(&mut self) {
+        if let Err(e) = self.flush() {
+            panic!("could not flush trie buffer due to {}", e);
+        }
+        let mut manifest = String::new();
+        for (depth, nodes) in self.nodes.iter().enumerate() {
+            for (node_prefix, node) in nodes.iter() {
+                manifest.push_str(&format!("{} {}", depth, node_prefix));
+                for table in node.tables.iter() {
+                    manifest.push_str(&format!(" {}", table.file.display()));
+                }
+                manifest.push('\n');
+            }
+        }
+        if let Err(e) = fs::write(self.manifest(), manifest) {
+            panic!("could not save trie manifest due to {}", e);
+        }
+    }
+
+    pub fn load(&mut self) -> io::Result<()> {
+        let manifest = fs::read_to_string(self.manifest())?;
+        for line in manifest.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let depth: usize = tokens[0].parse().unwrap();
+            let node_prefix: u64 = tokens[1].parse().unwrap();
+            let mut node = TrieNode::new(self.num_buckets);
+            for file in tokens[2..].iter() {
+                let (table, blooms) = HTable::open(PathBuf::from(file), self.bf_bits_per_entry)?;
+                assert_eq!(self.num_buckets, table.num_buckets());
+                if let Ok(id) = file
+                    .trim_start_matches(&format!("/tmp/{}/htable-", self.tree_name))
+                    .trim_end_matches(".txt")
+                    .parse::<u64>()
+                {
+                    self.next_table_id = std::cmp::max(self.next_table_id, id + 1);
+                }
+                node.push_back(table, blooms);
+            }
+            self.nodes[depth].insert(node_prefix, node);
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        let _ = fs::remove_dir_all(format!("/tmp/{}/", self.tree_name));
+        let _ = fs::create_dir_all(format!("/tmp/{}/", self.tree_name));
+        for nodes in self.nodes.iter_mut() {
+            nodes.clear();
+        }
+        self.buffer.empty();
+        self.next_table_id = 0;
+    }
+}
+
+#[test]
+fn test_htable() {
+    let _ = fs::create_dir_all("/tmp/htable_test");
+    let entries: Vec<EntryT> = (0..300u32)
+        .map(|i| EntryT {
+            key: data_type::fill_str_with_witespace(&i.to_string(), KEY_SIZE),
+            value: data_type::fill_str_with_witespace(&i.to_string(), VALUE_SIZE),
+        })
+        .collect();
+    let file = PathBuf::from("/tmp/htable_test/htable-0.txt");
+    let (table, blooms) = HTable::create(file.clone(), &entries, 4, 1.0).unwrap();
+    let (reopened, _) = HTable::open(file.clone(), 1.0).unwrap();
+    assert_eq!(300, reopened.size);
+    assert_eq!(table.bucket_offsets, reopened.bucket_offsets);
+    let mut sorted = entries.clone();
+    sorted.sort();
+    assert_eq!(sorted, reopened.read_all().unwrap());
+    for entry in entries.iter() {
+        let bucket = bucket_index(hash_key(&entry.key), 4);
+        assert!(blooms[bucket].check(&entry.key));
+        assert!(table.read_bucket(bucket).unwrap().contains(entry));
+    }
+    //the same key lands in the same bucket in every build
+    assert_eq!(0xaf63_dc4c_8601_ec8c, hash_key(&b"a".to_vec()));
+
+    //truncated and damaged tables are rejected instead of read out of bounds
+    let data = fs::read(&file).unwrap();
+    let damaged = PathBuf::from("/tmp/htable_test/htable-1.txt");
+    let mut huge_count = data.clone();
+    let len = huge_count.len();
+    huge_count[len - 8..].copy_from_slice(&u64::MAX.to_le_bytes());
+    for bad in [data[..4].to_vec(), data[8..].to_vec(), huge_count].iter() {
+        fs::write(&damaged, bad).unwrap();
+        let err = HTable::open(damaged.clone(), 1.0).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
+
+#[test]
+fn test_trie() {
+    let test_size = 3000;
+    let mut trie = LSMTrie::new(16, 3, 0.5, "trie_test".to_string());
+    trie.clear();
+    for i in 0..test_size {
+        trie.put(&i.to_string(), &i.to_string());
+    }
+    for i in (0..test_size).step_by(4) {
+        trie.del(&i.to_string());
+    }
+    assert!(!trie.nodes[2].is_empty());
+    for j in 0..test_size {
+        let expected = if j % 4 == 0 {
+            None
+        } else {
+            Some(j.to_string())
+        };
+        assert_eq!(expected, trie.get(&j.to_string()));
+    }
+    assert_eq!(
+        io::ErrorKind::Unsupported,
+        trie.range("1", "2").unwrap_err().kind()
+    );
+    trie.close();
+
+    let mut trie2 = LSMTrie::new(16, 3, 0.5, "trie_test".to_string());
+    trie2.load().unwrap();
+    for j in 0..test_size {
+        let expected = if j % 4 == 0 {
+            None
+        } else {
+            Some(j.to_string())
+        };
+        assert_eq!(expected, trie2.get(&j.to_string()));
+    }
+    trie2.clear();
+}
@@ -0,0 +1,474 @@
+//Batched reads. A batch of reads, possibly from many run files, is submitted to io_uring with
+//one io_uring_enter call so the device serves them concurrently. On kernels without io_uring
+//(before 5.1, or with it disabled by sysctl or seccomp) the same batch is served with pread.
+//The ring is set up with the raw system calls, which is all the read path needs.
+use crate::file_io::{align_down, align_up, AlignedBuffer, RunFile};
+use std::fs::File;
+use std::io;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub static DEFAULT_RING_ENTRIES: u32 = 64;
+
+static IORING_OP_READV: u8 = 1;
+static IORING_ENTER_GETEVENTS: u32 = 1;
+static IORING_OFF_SQ_RING: i64 = 0;
+static IORING_OFF_CQ_RING: i64 = 0x800_0000;
+static IORING_OFF_SQES: i64 = 0x1000_0000;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+//submission queue entry
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+//completion queue entry
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: i64) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut c_void, self.len);
+        }
+    }
+}
+
+/// One read of `len` bytes at `offset` of the open file `fd`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadRequest {
+    pub fd: RawFd,
+    pub offset: u64,
+    pub len: usize,
+}
+
+pub struct IoUring {
+    //the mappings are declared first so they are unmapped before the ring is closed
+    sq_ring: Mapping,
+    cq_ring: Mapping,
+    sqes: Mapping,
+    ring: File,
+    entries: u32,
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+//the ring is only used through &mut self, the kernel is the only other party touching it
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Sets up a ring for `entries` reads in flight. Fails if the kernel has no io_uring.
+    pub fn new(entries: u32) -> io::Result<IoUring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ring = unsafe { File::from_raw_fd(fd as RawFd) };
+        let fd = ring.as_raw_fd();
+        let sq_len = (params.sq_off.array + params.sq_entries * 4) as usize;
+        let cq_len = (params.cq_off.cqes as usize) + params.cq_entries as usize * 16;
+        Ok(IoUring {
+            sq_ring: Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+            cq_ring: Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+            sqes: Mapping::new(fd, params.sq_entries as usize * 64, IORING_OFF_SQES)?,
+            ring,
+            entries: params.sq_entries,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+        })
+    }
+
+    fn enter(&self, to_submit: u32, min_complete: u32) -> io::Result<u32> {
+        loop {
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.ring.as_raw_fd(),
+                    to_submit,
+                    min_complete,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<c_void>(),
+                    0,
+                )
+            };
+            if ret >= 0 {
+                return Ok(ret as u32);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Reads every request into its buffer and returns the number of bytes each read. Fails
+    /// as a whole only if the ring itself stops working, and then only once every read the
+    /// kernel took is done with the buffers.
+    pub fn read(
+        &mut self,
+        requests: &[ReadRequest],
+        buffers: &mut [AlignedBuffer],
+    ) -> io::Result<Vec<io::Result<usize>>> {
+        let mut res: Vec<io::Result<usize>> = Vec::with_capacity(requests.len());
+        let mut start = 0;
+        while start < requests.len() {
+            //the ring is empty between chunks, so a chunk of up to entries reads always fits
+            let end = requests.len().min(start + self.entries as usize);
+            let iovecs: Vec<libc::iovec> = requests[start..end]
+                .iter()
+                .zip(buffers[start..end].iter_mut())
+                .map(|(request, buffer)| libc::iovec {
+                    iov_base: buffer.as_mut_ptr() as *mut c_void,
+                    iov_len: request.len,
+                })
+                .collect();
+            let count = (end - start) as u32;
+            let mut chunk: Vec<Option<io::Result<usize>>> = (start..end).map(|_| None).collect();
+            unsafe {
+                let sq_tail = &*self.sq_ring.at::<AtomicU32>(self.sq_off.tail);
+                let sq_mask = *self.sq_ring.at::<u32>(self.sq_off.ring_mask);
+                let sq_array = self.sq_ring.at::<u32>(self.sq_off.array);
+                let tail = sq_tail.load(Ordering::Relaxed);
+                for (i, (request, iovec)) in requests[start..end].iter().zip(&iovecs).enumerate() {
+                    let index = tail.wrapping_add(i as u32) & sq_mask;
+                    let sqe = self.sqes.at::<Sqe>(index * 64);
+                    ptr::write(
+                        sqe,
+                        Sqe {
+                            opcode: IORING_OP_READV,
+                            flags: 0,
+                            ioprio: 0,
+                            fd: request.fd,
+                            off: request.offset,
+                            addr: iovec as *const libc::iovec as u64,
+                            len: 1,
+                            rw_flags: 0,
+                            user_data: i as u64,
+                            buf_index: 0,
+                            personality: 0,
+                            splice_fd_in: 0,
+                            pad: [0; 2],
+                        },
+                    );
+                    *sq_array.add(index as usize) = index;
+                }
+                sq_tail.store(tail.wrapping_add(count), Ordering::Release);
+
+                let cq_head = &*self.cq_ring.at::<AtomicU32>(self.cq_off.head);
+                let cq_tail = &*self.cq_ring.at::<AtomicU32>(self.cq_off.tail);
+                let cq_mask = *self.cq_ring.at::<u32>(self.cq_off.ring_mask);
+                let cqes = self.cq_ring.at::<Cqe>(self.cq_off.cqes);
+                let sq_head = &*self.sq_ring.at::<AtomicU32>(self.sq_off.head);
+                let mut submitted = 0;
+                let mut completed = 0;
+                let mut error: Option<io::Error> = None;
+                //the kernel writes through iovecs into buffers until a read completes, so no
+                //submitted read may be left behind, even once the ring fails
+                while completed < count && (error.is_none() || completed < submitted) {
+                    if error.is_none() {
+                        match self.enter(count - submitted, count - completed) {
+                            Ok(n) => submitted += n.min(count - submitted),
+                            Err(e) => {
+                                //reads the kernel did not take yet are taken back
+                                let head = sq_head.load(Ordering::Acquire);
+                                sq_tail.store(head, Ordering::Release);
+                                submitted = head.wrapping_sub(tail);
+                                error = Some(e);
+                            }
+                        }
+                    } else if self.enter(0, submitted - completed).is_err() {
+                        //completions are still posted, e.g. as task work on the next syscall
+                        std::thread::yield_now();
+                    }
+                    let mut head = cq_head.load(Ordering::Relaxed);
+                    while head != cq_tail.load(Ordering::Acquire) {
+                        let cqe = &*cqes.add((head & cq_mask) as usize);
+                        chunk[cqe.user_data as usize] = Some(if cqe.res < 0 {
+                            Err(io::Error::from_raw_os_error(-cqe.res))
+                        } else {
+                            Ok(cqe.res as usize)
+                        });
+                        head = head.wrapping_add(1);
+                        completed += 1;
+                    }
+                    cq_head.store(head, Ordering::Release);
+                }
+                if let Some(e) = error {
+                    return Err(e);
+                }
+            }
+            res.extend(chunk.into_iter().map(Option::unwrap));
+            start = end;
+        }
+        Ok(res)
+    }
+}
+
+//reads from offset until buf is full or the file ends, returns the number of bytes read
+fn pread_all(fd: RawFd, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = unsafe {
+            libc::pread(
+                fd,
+                buf[read..].as_mut_ptr() as *mut c_void,
+                buf.len() - read,
+                (offset + read as u64) as libc::off_t,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        } else if n == 0 {
+            break;
+        }
+        read += n as usize;
+    }
+    Ok(read)
+}
+
+/// Serves batches of reads with io_uring where the kernel has it and with pread otherwise.
+pub enum BatchReader {
+    Uring(IoUring),
+    Pread,
+}
+
+impl BatchReader {
+    /// Uses a ring of `entries` reads if io_uring is available, pread otherwise.
+    pub fn new(entries: u32) -> BatchReader {
+        match IoUring::new(entries) {
+            Ok(ring) => BatchReader::Uring(ring),
+            Err(_) => BatchReader::Pread,
+        }
+    }
+
+    pub fn is_uring(&self) -> bool {
+        matches!(self, BatchReader::Uring(_))
+    }
+
+    /// Performs all `requests` and returns their data, which is shorter than requested only
+    /// if the file ends first.
+    pub fn read(&mut self, requests: &[ReadRequest]) -> Vec<io::Result<Vec<u8>>> {
+        //aligned buffers make the same requests work for files opened with O_DIRECT
+        let mut buffers: Vec<AlignedBuffer> = requests
+            .iter()
+            .map(|request| AlignedBuffer::new(request.len))
+            .collect();
+        let results = match self {
+            BatchReader::Uring(ring) => match ring.read(requests, &mut buffers) {
+                Ok(results) => Some(results),
+                Err(_) => {
+                    //the ring broke, stay with pread from now on
+                    *self = BatchReader::Pread;
+                    None
+                }
+            },
+            BatchReader::Pread => None,
+        };
+        let mut results = results.map(|r| r.into_iter());
+
+        requests
+            .iter()
+            .zip(buffers.iter_mut())
+            .map(|(request, buffer)| {
+                let buffer = &mut buffer[..request.len];
+                let read = match results.as_mut().and_then(|r| r.next()) {
+                    //a short read is finished with pread, which stops at the end of file
+                    Some(Ok(n)) if n == buffer.len() || n == 0 => n,
+                    Some(Ok(n)) => {
+                        n + pread_all(request.fd, &mut buffer[n..], request.offset + n as u64)?
+                    }
+                    //e.g. a kernel too old for the opcode, pread reports real I/O errors
+                    Some(Err(_)) | None => pread_all(request.fd, buffer, request.offset)?,
+                };
+                Ok(buffer[..read].to_vec())
+            })
+            .collect()
+    }
+
+    /// Reads `len` bytes at `offset` of every file in one batch. Mapped files are copied
+    /// from memory, the others are read with the requests of a single batch.
+    pub fn read_files(&mut self, reads: &[(&RunFile, usize, usize)]) -> Vec<io::Result<Vec<u8>>> {
+        let mut res: Vec<Option<io::Result<Vec<u8>>>> = reads.iter().map(|_| None).collect();
+        let mut requests: Vec<ReadRequest> = Vec::new();
+        //read of every request, and where the wanted bytes start in its data
+        let mut targets: Vec<(usize, usize)> = Vec::new();
+        for (i, (file, len, offset)) in reads.iter().enumerate() {
+            let (fd, start, end) = match file {
+                RunFile::Mapped(mmap) => {
+                    res[i] = Some(if *offset + *len <= mmap.len() {
+                        file.read(*len, *offset)
+                    } else {
+                        Err(io::ErrorKind::UnexpectedEof.into())
+                    });
+                    continue;
+                }
+                RunFile::Pread(f) | RunFile::Direct(f, false) => {
+                    (f.as_raw_fd(), *offset, *offset + *len)
+                }
+                RunFile::Direct(f, true) => {
+                    (f.as_raw_fd(), align_down(*offset), align_up(*offset + *len))
+                }
+            };
+            requests.push(ReadRequest {
+                fd,
+                offset: start as u64,
+                len: end - start,
+            });
+            targets.push((i, *offset - start));
+        }
+        for ((i, skip), data) in targets.into_iter().zip(self.read(&requests)) {
+            let len = reads[i].1;
+            res[i] = Some(data.and_then(|data| {
+                if data.len() < skip + len {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                } else {
+                    Ok(data[skip..skip + len].to_vec())
+                }
+            }));
+        }
+        res.into_iter().map(Option::unwrap).collect()
+    }
+}
+
+#[test]
+fn test_batch_reader() {
+    use crate::file_io::IoMode;
+    use std::path::Path;
+    let path = Path::new("/tmp/batch_reader_test");
+    let data: Vec<u8> = (0..20000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(path, &data).unwrap();
+    let file = File::open(path).unwrap();
+    //more reads than the ring holds, and one running past the end of file
+    let requests: Vec<ReadRequest> = (0..40)
+        .map(|i| ReadRequest {
+            fd: file.as_raw_fd(),
+            offset: i * 500,
+            len: 1000,
+        })
+        .collect();
+    for mut reader in vec![BatchReader::new(16), BatchReader::Pread] {
+        let results = reader.read(&requests);
+        for (request, result) in requests.iter().zip(results) {
+            let start = request.offset as usize;
+            let end = data.len().min(start + request.len);
+            assert_eq!(&data[start..end], result.unwrap().as_slice());
+        }
+        for mode in [IoMode::Mmap, IoMode::Pread, IoMode::Direct].iter() {
+            let run_file = RunFile::open(path, *mode).unwrap();
+            let reads = vec![
+                (&run_file, 100, 4090),
+                (&run_file, 10, 0),
+                (&run_file, 1, 19999),
+            ];
+            let results = reader.read_files(&reads);
+            assert_eq!(&data[4090..4190], results[0].as_ref().unwrap().as_slice());
+            assert_eq!(&data[..10], results[1].as_ref().unwrap().as_slice());
+            assert_eq!(&data[19999..], results[2].as_ref().unwrap().as_slice());
+            assert!(reader.read_files(&[(&run_file, 10, 19995)])[0].is_err());
+        }
+    }
+    std::fs::remove_file(path).unwrap();
+}
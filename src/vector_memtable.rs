@@ -0,0 +1,142 @@
+//Append-only memtable for bulk loads. A put pushes the entry onto a vector without looking
+//for its key, and the entries are only sorted when they are read in order, e.g. by the
+//flush. Lookups scan the vector from the back, as the latest put of a key wins, so a tree
+//with many reads of its recent writes is better served by the other memtables.
+use crate::data_type::{EntryT, KeyT, ValueT};
+use crate::memtable::{entry_charge, MemTable};
+
+#[derive(Default)]
+pub struct VectorMemTable {
+    entries: Vec<EntryT>,
+    //approximate memory of the entries in bytes, overwritten ones included
+    size: usize,
+}
+
+impl VectorMemTable {
+    pub fn new() -> VectorMemTable {
+        VectorMemTable::default()
+    }
+
+    //positions of the latest entry of every key in key order
+    fn sorted(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.entries.len()).collect();
+        //stable, so the puts of a key stay in order and the last one is kept
+        order.sort_by(|a, b| self.entries[*a].key.cmp(&self.entries[*b].key));
+        let mut res: Vec<usize> = Vec::with_capacity(order.len());
+        for i in order {
+            match res.last_mut() {
+                Some(last) if self.entries[*last].key == self.entries[i].key => *last = i,
+                _ => res.push(i),
+            }
+        }
+        res
+    }
+}
+
+impl MemTable for VectorMemTable {
+    fn put(&mut self, key: KeyT, value: ValueT) {
+        self.size += entry_charge(&key, &value);
+        self.entries.push(EntryT { key, value });
+    }
+
+    fn get(&self, key: &KeyT) -> Option<ValueT> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.key == *key)
+            .map(|entry| entry.value.clone())
+    }
+
+    /// Looks up `keys` in one pass over the entries.
+    fn multi_get(&self, keys: &[KeyT]) -> Vec<Option<ValueT>> {
+        let mut res: Vec<Option<ValueT>> = vec![None; keys.len()];
+        let mut found = 0;
+        for entry in self.entries.iter().rev() {
+            if found == keys.len() {
+                break;
+            }
+            if let Ok(i) = keys.binary_search(&entry.key) {
+                if res[i].is_none() {
+                    res[i] = Some(entry.value.clone());
+                    found += 1;
+                }
+            }
+        }
+        res
+    }
+
+    fn range(&self, start: &KeyT, end: &KeyT) -> Vec<EntryT> {
+        let mut order: Vec<usize> = (0..self.entries.len())
+            .filter(|i| self.entries[*i].key >= *start && self.entries[*i].key <= *end)
+            .collect();
+        order.sort_by(|a, b| self.entries[*a].key.cmp(&self.entries[*b].key));
+        let mut res: Vec<EntryT> = Vec::with_capacity(order.len());
+        for i in order {
+            match res.last_mut() {
+                Some(last) if last.key == self.entries[i].key => {
+                    last.value = self.entries[i].value.clone()
+                }
+                _ => res.push(self.entries[i].clone()),
+            }
+        }
+        res
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = EntryT> + '_> {
+        Box::new(
+            self.sorted()
+                .into_iter()
+                .map(move |i| self.entries[i].clone()),
+        )
+    }
+
+    /// Number of puts since the table was emptied, a key put twice counts twice.
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.size
+    }
+
+    fn empty(&mut self) {
+        self.entries.clear();
+        self.size = 0;
+    }
+}
+
+#[test]
+fn test_vector_memtable() {
+    let mut table = VectorMemTable::new();
+    let key = |i: u32| format!("{:04}", i).into_bytes();
+    for i in (0..100).rev() {
+        table.put(key(i), key(i));
+    }
+    table.put(key(7), b"seven".to_vec());
+    table.put(key(7), b"siete".to_vec());
+    assert_eq!(102, table.len());
+    assert_eq!(
+        100 * entry_charge(&key(0), &key(0))
+            + entry_charge(&key(7), b"seven")
+            + entry_charge(&key(7), b"siete"),
+        table.approximate_size()
+    );
+    assert_eq!(Some(b"siete".to_vec()), table.get(&key(7)));
+    assert_eq!(None, table.get(&key(100)));
+    assert_eq!(
+        vec![Some(key(0)), Some(b"siete".to_vec()), None],
+        table.multi_get(&[key(0), key(7), key(100)])
+    );
+    let entries: Vec<EntryT> = table.iter().collect();
+    assert_eq!(
+        (0..100).map(key).collect::<Vec<KeyT>>(),
+        entries.iter().map(|e| e.key.clone()).collect::<Vec<KeyT>>()
+    );
+    assert_eq!(b"siete".to_vec(), entries[7].value);
+    let range = table.range(&key(5), &key(8));
+    assert_eq!(4, range.len());
+    assert_eq!(b"siete".to_vec(), range[2].value);
+    table.empty();
+    assert!(table.is_empty());
+    assert_eq!(0, table.approximate_size());
+}
@@ -0,0 +1,220 @@
+//WiscKey-style value log. Large values are appended to log files and the tree only keeps
+//a small pointer to them, so compaction no longer rewrites the values themselves.
+use crate::data_type::{KeyT, ValueT, VALUE_SIZE};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+//first byte of a value slot holding a pointer. Inline values are padded with spaces.
+pub static VLOG_POINTER_TAG: u8 = 0;
+pub static DEFAULT_VLOG_FILE_SIZE: u64 = 64 * 1024 * 1024;
+//key length and value length in front of every record
+static RECORD_HEADER_SIZE: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValuePointer {
+    pub file: u32,
+    //offset of the value itself, not of its record
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl ValuePointer {
+    /// Encodes the pointer into a value slot of `VALUE_SIZE` bytes.
+    pub fn encode(&self) -> ValueT {
+        let mut res: ValueT = Vec::with_capacity(VALUE_SIZE);
+        res.push(VLOG_POINTER_TAG);
+        res.extend_from_slice(&self.file.to_le_bytes());
+        res.extend_from_slice(&self.offset.to_le_bytes());
+        res.extend_from_slice(&self.len.to_le_bytes());
+        res.resize(VALUE_SIZE, 0);
+        res
+    }
+
+    /// Returns the pointer stored in a value slot, or None for an inline value.
+    pub fn decode(value: &[u8]) -> Option<ValuePointer> {
+        if value.len() != VALUE_SIZE || value[0] != VLOG_POINTER_TAG {
+            return None;
+        }
+        let mut file = [0u8; 4];
+        let mut offset = [0u8; 8];
+        let mut len = [0u8; 4];
+        file.copy_from_slice(&value[1..5]);
+        offset.copy_from_slice(&value[5..13]);
+        len.copy_from_slice(&value[13..17]);
+        Some(ValuePointer {
+            file: u32::from_le_bytes(file),
+            offset: u64::from_le_bytes(offset),
+            len: u32::from_le_bytes(len),
+        })
+    }
+}
+
+pub struct ValueLog {
+    pub dir: PathBuf,
+    //values longer than this are moved to the log
+    pub threshold: usize,
+    //the head file is sealed and a new one started once it grows past this
+    pub max_file_size: u64,
+    head: u32,
+    head_file: File,
+    head_size: u64,
+}
+
+impl ValueLog {
+    /// Opens the value log in `dir`, appending to the newest log file found there.
+    pub fn open(dir: PathBuf, threshold: usize) -> io::Result<ValueLog> {
+        fs::create_dir_all(&dir)?;
+        let head = ValueLog::list_files(&dir)?.last().cloned().unwrap_or(0);
+        let head_file = ValueLog::open_for_append(&dir, head)?;
+        let head_size = head_file.metadata()?.len();
+        Ok(ValueLog {
+            dir,
+            threshold,
+            max_file_size: DEFAULT_VLOG_FILE_SIZE,
+            head,
+            head_file,
+            head_size,
+        })
+    }
+
+    fn file_path(dir: &Path, id: u32) -> PathBuf {
+        dir.join(format!("vlog-{}.log", id))
+    }
+
+    fn open_for_append(dir: &Path, id: u32) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(ValueLog::file_path(dir, id))
+    }
+
+    //ids of every log file in dir, oldest first
+    fn list_files(dir: &Path) -> io::Result<Vec<u32>> {
+        let mut ids: Vec<u32> = fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .filter_map(|e| {
+                e.file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_prefix("vlog-"))
+                    .and_then(|name| name.strip_suffix(".log"))
+                    .and_then(|id| id.parse::<u32>().ok())
+            })
+            .collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Ids of the log files that no longer receive appends, oldest first.
+    pub fn sealed_files(&self) -> io::Result<Vec<u32>> {
+        let mut ids = ValueLog::list_files(&self.dir)?;
+        ids.retain(|id| *id != self.head);
+        Ok(ids)
+    }
+
+    pub fn append(&mut self, key: &KeyT, value: &[u8]) -> io::Result<ValuePointer> {
+        if self.head_size >= self.max_file_size {
+            self.head += 1;
+            self.head_file = ValueLog::open_for_append(&self.dir, self.head)?;
+            self.head_size = 0;
+        }
+        let mut record: Vec<u8> =
+            Vec::with_capacity(RECORD_HEADER_SIZE as usize + key.len() + value.len());
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(key);
+        record.extend_from_slice(value);
+        self.head_file.write_all(&record)?;
+        let pointer = ValuePointer {
+            file: self.head,
+            offset: self.head_size + RECORD_HEADER_SIZE + key.len() as u64,
+            len: value.len() as u32,
+        };
+        self.head_size += record.len() as u64;
+        Ok(pointer)
+    }
+
+    pub fn read(&self, pointer: &ValuePointer) -> io::Result<ValueT> {
+        let file = File::open(ValueLog::file_path(&self.dir, pointer.file))?;
+        let mut value: ValueT = vec![0; pointer.len as usize];
+        file.read_exact_at(&mut value, pointer.offset)?;
+        Ok(value)
+    }
+
+    /// Returns every record of a log file with the pointer that refers to its value.
+    pub fn records(&self, id: u32) -> io::Result<Vec<(KeyT, ValuePointer)>> {
+        let data = fs::read(ValueLog::file_path(&self.dir, id))?;
+        let mut res: Vec<(KeyT, ValuePointer)> = Vec::new();
+        let mut offset: usize = 0;
+        while offset + RECORD_HEADER_SIZE as usize <= data.len() {
+            let mut len = [0u8; 4];
+            len.copy_from_slice(&data[offset..offset + 4]);
+            let key_len = u32::from_le_bytes(len) as usize;
+            len.copy_from_slice(&data[offset + 4..offset + 8]);
+            let value_len = u32::from_le_bytes(len);
+            let key_start = offset + RECORD_HEADER_SIZE as usize;
+            let value_start = key_start + key_len;
+            if value_start + value_len as usize > data.len() {
+                //torn write at the end of the file
+                break;
+            }
+            res.push((
+                data[key_start..value_start].to_vec(),
+                ValuePointer {
+                    file: id,
+                    offset: value_start as u64,
+                    len: value_len,
+                },
+            ));
+            offset = value_start + value_len as usize;
+        }
+        Ok(res)
+    }
+
+    /// Makes the appends to the head file durable.
+    pub fn sync(&self) -> io::Result<()> {
+        self.head_file.sync_all()
+    }
+
+    pub fn remove_file(&self, id: u32) -> io::Result<()> {
+        assert!(id != self.head);
+        fs::remove_file(ValueLog::file_path(&self.dir, id))
+    }
+}
+
+#[test]
+fn test_pointer_encode() {
+    let pointer = ValuePointer {
+        file: 3,
+        offset: 1 << 40,
+        len: 4096,
+    };
+    let slot = pointer.encode();
+    assert_eq!(VALUE_SIZE, slot.len());
+    assert_eq!(Some(pointer), ValuePointer::decode(&slot));
+    assert_eq!(None, ValuePointer::decode(&vec![32; VALUE_SIZE]));
+}
+
+#[test]
+fn test_append_read() {
+    let dir = PathBuf::from("/tmp/vlog_unit_test");
+    let _ = fs::remove_dir_all(&dir);
+    let mut vlog = ValueLog::open(dir.clone(), 8).unwrap();
+    vlog.max_file_size = 64;
+    let mut pointers: Vec<ValuePointer> = Vec::new();
+    for i in 0..10u8 {
+        pointers.push(vlog.append(&vec![i; 8], &vec![i; 40]).unwrap());
+    }
+    for i in 0..10u8 {
+        assert_eq!(vec![i; 40], vlog.read(&pointers[i as usize]).unwrap());
+    }
+    let sealed = vlog.sealed_files().unwrap();
+    assert!(!sealed.is_empty());
+    let records = vlog.records(sealed[0]).unwrap();
+    assert_eq!(vec![0u8; 8], records[0].0);
+    assert_eq!(pointers[0], records[0].1);
+    fs::remove_dir_all(&dir).unwrap();
+}
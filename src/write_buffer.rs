@@ -0,0 +1,87 @@
+//Caps the memory of the memtables of all trees that share a WriteBufferManager. Every tree
+//reports the size of its memtable, and once the total reaches the capacity, a tree whose
+//memtable holds at least its share of the capacity flushes it on its next put. The total
+//stays below the capacity unless the trees over their share stop writing.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct WriteBufferManager {
+    capacity: usize,
+    usage: AtomicUsize,
+    //trees using the manager, the capacity is shared among them
+    num_trees: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    /// Creates a manager capping the memtables of the trees using it at `capacity` bytes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::lsm::LSMTree;
+    /// use lsm_kv::write_buffer::WriteBufferManager;
+    /// use std::sync::Arc;
+    /// let manager = Arc::new(WriteBufferManager::new(64 * 1024 * 1024));
+    /// let mut lsm = LSMTree::new(100, 5, 10, 0.5, 4, "write_buffer_doc".to_string());
+    /// lsm.set_write_buffer_manager(manager.clone());
+    /// lsm.put("hello", "world");
+    /// assert!(manager.usage() > 0);
+    /// ```
+    pub fn new(capacity: usize) -> WriteBufferManager {
+        WriteBufferManager {
+            capacity,
+            usage: AtomicUsize::new(0),
+            num_trees: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn register(&self) {
+        self.num_trees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn unregister(&self) {
+        self.num_trees.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn reserve(&self, bytes: usize) {
+        self.usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn free(&self, bytes: usize) {
+        self.usage.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Total memory of the memtables in bytes.
+    pub fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Whether a tree whose memtable takes `memtable_size` bytes should flush it.
+    pub fn should_flush(&self, memtable_size: usize) -> bool {
+        let share = self.capacity / self.num_trees.load(Ordering::Relaxed).max(1);
+        self.usage() >= self.capacity && memtable_size >= share
+    }
+}
+
+#[test]
+fn test_write_buffer_manager() {
+    let manager = WriteBufferManager::new(1000);
+    manager.register();
+    manager.register();
+    manager.reserve(600);
+    assert!(!manager.should_flush(600));
+    manager.reserve(400);
+    assert_eq!(1000, manager.usage());
+    //only the tree holding at least half of the capacity flushes
+    assert!(manager.should_flush(600));
+    assert!(!manager.should_flush(400));
+    manager.free(600);
+    assert!(!manager.should_flush(400));
+    manager.unregister();
+    manager.reserve(600);
+    assert!(!manager.should_flush(400));
+    assert!(manager.should_flush(1000));
+}
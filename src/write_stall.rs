@@ -0,0 +1,279 @@
+//Write stalls keep writers from outrunning flushes and compactions. Every put checks the
+//number of level 0 runs, the bytes waiting to be compacted and the number of immutable
+//buffers against a soft and a hard limit. Past a soft limit puts are delayed to a write rate
+//that falls as the value nears its hard limit, past a hard limit a put is stopped until the
+//tree has flushed or compacted enough.
+use std::collections::HashMap;
+use std::time::Duration;
+
+//delays shorter than this are added up and slept at once
+static MIN_SLEEP: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StallReason {
+    Level0Runs,
+    PendingCompactionBytes,
+    ImmutableBuffers,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteStallCondition {
+    Normal,
+    /// Writes are slowed down to `rate` bytes per second.
+    Delayed {
+        reason: StallReason,
+        rate: u64,
+    },
+    Stopped(StallReason),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteStallOptions {
+    pub level0_slowdown_runs: usize,
+    pub level0_stop_runs: usize,
+    //bytes of the runs in full levels, which the next flush reaching them merges down
+    pub soft_pending_compaction_bytes: u64,
+    pub hard_pending_compaction_bytes: u64,
+    pub immutable_buffers_slowdown: usize,
+    pub immutable_buffers_stop: usize,
+    //bytes per second writes are delayed to at a soft limit
+    pub delayed_write_rate: u64,
+}
+
+impl Default for WriteStallOptions {
+    fn default() -> Self {
+        WriteStallOptions {
+            level0_slowdown_runs: 20,
+            level0_stop_runs: 36,
+            soft_pending_compaction_bytes: 64 << 30,
+            hard_pending_compaction_bytes: 256 << 30,
+            immutable_buffers_slowdown: 2,
+            immutable_buffers_stop: 4,
+            delayed_write_rate: 16 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StallStats {
+    pub count: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteStallStats {
+    //delayed and stopped puts for every reason, and the time they waited
+    pub delays: HashMap<StallReason, StallStats>,
+    pub stops: HashMap<StallReason, StallStats>,
+}
+
+impl WriteStallStats {
+    pub fn total_delay(&self) -> Duration {
+        self.delays.values().map(|stats| stats.duration).sum()
+    }
+
+    pub fn total_stop(&self) -> Duration {
+        self.stops.values().map(|stats| stats.duration).sum()
+    }
+}
+
+pub struct WriteController {
+    pub options: WriteStallOptions,
+    stats: WriteStallStats,
+    //delay owed by the writes delayed for every reason since its last sleep
+    debt: HashMap<StallReason, Duration>,
+}
+
+impl WriteController {
+    pub fn new(options: WriteStallOptions) -> WriteController {
+        assert!(options.level0_slowdown_runs <= options.level0_stop_runs);
+        assert!(options.soft_pending_compaction_bytes <= options.hard_pending_compaction_bytes);
+        assert!(options.immutable_buffers_slowdown <= options.immutable_buffers_stop);
+        assert!(options.delayed_write_rate > 0);
+        WriteController {
+            options,
+            stats: WriteStallStats::default(),
+            debt: HashMap::new(),
+        }
+    }
+
+    /// Whether writes to a tree in the given state run, are delayed or are stopped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use lsm_kv::write_stall::*;
+    /// let controller = WriteController::new(WriteStallOptions {
+    ///     level0_slowdown_runs: 4,
+    ///     level0_stop_runs: 8,
+    ///     ..WriteStallOptions::default()
+    /// });
+    /// assert_eq!(WriteStallCondition::Normal, controller.condition(3, 0, 0));
+    /// assert_eq!(
+    ///     WriteStallCondition::Stopped(StallReason::Level0Runs),
+    ///     controller.condition(8, 0, 0)
+    /// );
+    /// ```
+    pub fn condition(
+        &self,
+        level0_runs: usize,
+        pending_compaction_bytes: u64,
+        immutable_buffers: usize,
+    ) -> WriteStallCondition {
+        let options = &self.options;
+        let limits = [
+            (
+                StallReason::ImmutableBuffers,
+                immutable_buffers as u64,
+                options.immutable_buffers_slowdown as u64,
+                options.immutable_buffers_stop as u64,
+            ),
+            (
+                StallReason::Level0Runs,
+                level0_runs as u64,
+                options.level0_slowdown_runs as u64,
+                options.level0_stop_runs as u64,
+            ),
+            (
+                StallReason::PendingCompactionBytes,
+                pending_compaction_bytes,
+                options.soft_pending_compaction_bytes,
+                options.hard_pending_compaction_bytes,
+            ),
+        ];
+        if let Some((reason, _, _, _)) = limits.iter().find(|(_, value, _, hard)| value >= hard) {
+            return WriteStallCondition::Stopped(*reason);
+        }
+        //the slowest rate of all limits past their soft limit
+        limits
+            .iter()
+            .filter(|(_, value, soft, _)| value >= soft)
+            .map(|(reason, value, soft, hard)| WriteStallCondition::Delayed {
+                reason: *reason,
+                rate: self.delayed_rate(*value, *soft, *hard),
+            })
+            .min_by_key(|condition| match condition {
+                WriteStallCondition::Delayed { rate, .. } => *rate,
+                _ => u64::MAX,
+            })
+            .unwrap_or(WriteStallCondition::Normal)
+    }
+
+    //the delayed write rate at the soft limit, falling linearly to a sixteenth of it at the
+    //hard limit
+    fn delayed_rate(&self, value: u64, soft: u64, hard: u64) -> u64 {
+        let rate = self.options.delayed_write_rate;
+        //stopped writes never get here, so value is below hard
+        let severity = (value - soft) as f64 / (hard - soft) as f64;
+        ((rate as f64 * (1.0 - severity)) as u64)
+            .max(rate / 16)
+            .max(1)
+    }
+
+    /// Adds the time a write of `bytes` takes at `rate` to the time owed for `reason`, and
+    /// returns the time to sleep, which is zero until the time owed for `reason` adds up to a
+    /// millisecond.
+    pub fn delay(&mut self, reason: StallReason, rate: u64, bytes: usize) -> Duration {
+        let debt = self.debt.entry(reason).or_default();
+        *debt += Duration::from_nanos(bytes as u64 * 1_000_000_000 / rate);
+        let stats = self.stats.delays.entry(reason).or_default();
+        stats.count += 1;
+        if *debt < MIN_SLEEP {
+            return Duration::from_secs(0);
+        }
+        stats.duration += *debt;
+        std::mem::replace(debt, Duration::from_secs(0))
+    }
+
+    /// Records a put stopped for `reason` for `duration`.
+    pub fn record_stop(&mut self, reason: StallReason, duration: Duration) {
+        let stats = self.stats.stops.entry(reason).or_default();
+        stats.count += 1;
+        stats.duration += duration;
+    }
+
+    pub fn stats(&self) -> &WriteStallStats {
+        &self.stats
+    }
+}
+
+#[test]
+fn test_write_stall_condition() {
+    let controller = WriteController::new(WriteStallOptions {
+        level0_slowdown_runs: 4,
+        level0_stop_runs: 8,
+        soft_pending_compaction_bytes: 1000,
+        hard_pending_compaction_bytes: 2000,
+        immutable_buffers_slowdown: 2,
+        immutable_buffers_stop: 3,
+        delayed_write_rate: 1600,
+    });
+    assert_eq!(WriteStallCondition::Normal, controller.condition(3, 999, 1));
+    assert_eq!(
+        WriteStallCondition::Delayed {
+            reason: StallReason::Level0Runs,
+            rate: 1600
+        },
+        controller.condition(4, 0, 0)
+    );
+    //the rate falls towards the hard limit, and the slowest limit wins
+    assert_eq!(
+        WriteStallCondition::Delayed {
+            reason: StallReason::PendingCompactionBytes,
+            rate: 400
+        },
+        controller.condition(4, 1750, 0)
+    );
+    assert_eq!(
+        WriteStallCondition::Delayed {
+            reason: StallReason::Level0Runs,
+            rate: 400
+        },
+        controller.condition(7, 1000, 0)
+    );
+    assert_eq!(
+        WriteStallCondition::Delayed {
+            reason: StallReason::PendingCompactionBytes,
+            rate: 100
+        },
+        controller.condition(0, 1990, 0)
+    );
+    assert_eq!(
+        WriteStallCondition::Stopped(StallReason::ImmutableBuffers),
+        controller.condition(8, 2000, 3)
+    );
+    assert_eq!(
+        WriteStallCondition::Stopped(StallReason::PendingCompactionBytes),
+        controller.condition(0, 2000, 0)
+    );
+}
+
+#[test]
+fn test_write_stall_delay() {
+    let mut controller = WriteController::new(WriteStallOptions::default());
+    //a thousandth of a millisecond per write at 1 MB per second
+    for _ in 0..999 {
+        let slept = controller.delay(StallReason::Level0Runs, 1_000_000, 1);
+        assert_eq!(Duration::from_secs(0), slept);
+    }
+    //the delays for another reason are owed and recorded apart
+    let other = controller.delay(StallReason::PendingCompactionBytes, 1_000_000, 500);
+    assert_eq!(Duration::from_secs(0), other);
+    let slept = controller.delay(StallReason::Level0Runs, 1_000_000, 1);
+    assert_eq!(MIN_SLEEP, slept);
+    assert_eq!(
+        Duration::from_millis(1),
+        controller.delay(StallReason::PendingCompactionBytes, 1_000_000, 500)
+    );
+    controller.record_stop(StallReason::ImmutableBuffers, Duration::from_millis(5));
+    let stats = controller.stats();
+    assert_eq!(1000, stats.delays[&StallReason::Level0Runs].count);
+    assert_eq!(slept, stats.delays[&StallReason::Level0Runs].duration);
+    assert_eq!(
+        Duration::from_millis(1),
+        stats.delays[&StallReason::PendingCompactionBytes].duration
+    );
+    assert_eq!(Duration::from_millis(2), stats.total_delay());
+    assert_eq!(Duration::from_millis(5), stats.total_stop());
+    assert!(stats.stops.get(&StallReason::Level0Runs).is_none());
+}
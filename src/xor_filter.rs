@@ -0,0 +1,301 @@
+//Xor filter (Graf and Lemire, 2020) of the runs. Every key hash picks one slot in each third
+//of a table of 1.23 * n + 32 fingerprints, and the filter is built so the fingerprints of
+//the three slots xor to the fingerprint of the key. A key is then checked with three reads,
+//and a key not in the filter passes with probability 2^-f for f bit fingerprints, using
+//1.23 * f bits per key instead of the 1.44 * f of a bloom filter.
+//The table can only be built once all keys are known: keys are collected by set and the
+//table is built by build, after which no keys can be added.
+use crate::bloom_filter::BloomFilter;
+use std::convert::TryInto;
+
+//encoded filter: [seed u64][block length u64][fingerprint bits u64][number of keys u64]
+//followed by the packed fingerprints, every word little endian
+static HEADER_SIZE: usize = 32;
+//building fails for an unlucky seed with a small probability, it is retried with another
+static MAX_ATTEMPTS: u64 = 100;
+
+#[derive(Debug, Clone)]
+pub struct XorFilter {
+    seed: u64,
+    //slots in each of the three parts of the table
+    block_length: u64,
+    fingerprint_bits: u32,
+    num_keys: u64,
+    //fingerprints packed fingerprint_bits apart
+    table: Vec<u64>,
+    //hashes of the keys added since the filter was created, until it is built
+    keys: Vec<u64>,
+    built: bool,
+}
+
+//murmur3 finalizer of the key hash and the seed
+fn mix(hash: u64, seed: u64) -> u64 {
+    let mut h = hash.wrapping_add(seed);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+impl XorFilter {
+    /// Returns an empty filter with fingerprints of about `bits_per_entry / 1.23` bits.
+    pub fn with_bits_per_entry(bits_per_entry: f32) -> XorFilter {
+        let fingerprint_bits = (bits_per_entry as f64 / 1.23).round() as u32;
+        XorFilter {
+            seed: 0,
+            block_length: 0,
+            fingerprint_bits: fingerprint_bits.clamp(1, 32),
+            num_keys: 0,
+            table: Vec::new(),
+            keys: Vec::new(),
+            built: false,
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8]) {
+        assert!(!self.built, "keys cannot be added to a built xor filter");
+        self.keys.push(BloomFilter::hash_key(key));
+    }
+
+    fn slots(&self, h: u64) -> [usize; 3] {
+        let mut res = [0; 3];
+        for (i, slot) in res.iter_mut().enumerate() {
+            //maps 32 bits of the hash onto the part of the table without a division
+            let r = h.rotate_left(21 * i as u32) as u32 as u64;
+            *slot = (((r * self.block_length) >> 32) + i as u64 * self.block_length) as usize;
+        }
+        res
+    }
+
+    fn fingerprint(&self, h: u64) -> u64 {
+        (h ^ (h >> 32)) & ((1u64 << self.fingerprint_bits) - 1)
+    }
+
+    fn get_slot(&self, slot: usize) -> u64 {
+        let bits = self.fingerprint_bits as usize;
+        let (word, shift) = (slot * bits / 64, slot * bits % 64);
+        let mut value = self.table[word] >> shift;
+        if shift + bits > 64 {
+            value |= self.table[word + 1] << (64 - shift);
+        }
+        value & ((1u64 << bits) - 1)
+    }
+
+    fn set_slot(&mut self, slot: usize, value: u64) {
+        let bits = self.fingerprint_bits as usize;
+        let (word, shift) = (slot * bits / 64, slot * bits % 64);
+        let mask = (1u64 << bits) - 1;
+        self.table[word] &= !(mask << shift);
+        self.table[word] |= value << shift;
+        if shift + bits > 64 {
+            self.table[word + 1] &= !(mask >> (64 - shift));
+            self.table[word + 1] |= value >> (64 - shift);
+        }
+    }
+
+    /// Builds the table from the keys set so far. Keys cannot be added afterwards.
+    pub fn build(&mut self) {
+        if self.built {
+            return;
+        }
+        let mut keys = std::mem::take(&mut self.keys);
+        //two equal hashes would never peel
+        keys.sort_unstable();
+        keys.dedup();
+        self.num_keys = keys.len() as u64;
+        self.block_length = (32 + (1.23 * keys.len() as f64).ceil() as u64) / 3;
+        let capacity = 3 * self.block_length as usize;
+
+        let mut order: Vec<(u64, usize)> = Vec::with_capacity(keys.len());
+        for attempt in 0..MAX_ATTEMPTS {
+            self.seed = mix(attempt, 0x9e37_79b9_7f4a_7c15);
+            //number of keys in every slot and the xor of their hashes
+            let mut count: Vec<u32> = vec![0; capacity];
+            let mut xor_mask: Vec<u64> = vec![0; capacity];
+            for key in keys.iter() {
+                let h = mix(*key, self.seed);
+                for slot in self.slots(h).iter() {
+                    count[*slot] += 1;
+                    xor_mask[*slot] ^= h;
+                }
+            }
+            //peel off slots holding a single key, which the key is assigned to
+            let mut queue: Vec<usize> = (0..capacity).filter(|i| count[*i] == 1).collect();
+            order.clear();
+            while let Some(i) = queue.pop() {
+                if count[i] != 1 {
+                    continue;
+                }
+                let h = xor_mask[i];
+                order.push((h, i));
+                for slot in self.slots(h).iter() {
+                    count[*slot] -= 1;
+                    xor_mask[*slot] ^= h;
+                    if count[*slot] == 1 {
+                        queue.push(*slot);
+                    }
+                }
+            }
+            if order.len() == keys.len() {
+                break;
+            }
+        }
+        assert_eq!(order.len(), keys.len(), "building the xor filter failed");
+
+        self.table = vec![0; (capacity * self.fingerprint_bits as usize).div_ceil(64)];
+        //in reverse peeling order the slot of a key is the last of its slots to be written
+        for (h, i) in order.iter().rev() {
+            let value = self
+                .slots(*h)
+                .iter()
+                .fold(self.fingerprint(*h), |acc, slot| acc ^ self.get_slot(*slot));
+            self.set_slot(*i, value);
+        }
+        self.built = true;
+    }
+
+    /// Returns false if `key` was not in the filter when it was built, and true if it may
+    /// have been. Before the filter is built every key may be in it.
+    pub fn check(&self, key: &[u8]) -> bool {
+        if !self.built {
+            return true;
+        }
+        let h = mix(BloomFilter::hash_key(key), self.seed);
+        let xor = self
+            .slots(h)
+            .iter()
+            .fold(0, |acc, slot| acc ^ self.get_slot(*slot));
+        xor == self.fingerprint(h)
+    }
+
+    pub fn is_built(&self) -> bool {
+        self.built
+    }
+
+    pub fn num_bits(&self) -> u64 {
+        3 * self.block_length * self.fingerprint_bits as u64
+    }
+
+    pub fn fingerprint_bits(&self) -> u32 {
+        self.fingerprint_bits
+    }
+
+    /// False positive rate given by the size of the fingerprints.
+    pub fn expected_false_positive_rate(&self) -> f64 {
+        0.5f64.powi(self.fingerprint_bits as i32)
+    }
+
+    /// Encodes the built filter.
+    pub fn encode(&self) -> Vec<u8> {
+        assert!(self.built, "only a built xor filter can be encoded");
+        let mut res: Vec<u8> = Vec::with_capacity(HEADER_SIZE + self.table.len() * 8);
+        res.extend_from_slice(&self.seed.to_le_bytes());
+        res.extend_from_slice(&self.block_length.to_le_bytes());
+        res.extend_from_slice(&(self.fingerprint_bits as u64).to_le_bytes());
+        res.extend_from_slice(&self.num_keys.to_le_bytes());
+        for word in self.table.iter() {
+            res.extend_from_slice(&word.to_le_bytes());
+        }
+        res
+    }
+
+    /// Restores a filter written by `encode`, returns None if `src` is not one.
+    pub fn decode(src: &[u8]) -> Option<XorFilter> {
+        if src.len() < HEADER_SIZE {
+            return None;
+        }
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(src[offset..offset + 8].try_into().unwrap());
+        let (seed, block_length, fingerprint_bits, num_keys) =
+            (u64_at(0), u64_at(8), u64_at(16), u64_at(24));
+        //build never makes an empty table, a check would index into one
+        if block_length == 0 || fingerprint_bits == 0 || fingerprint_bits > 32 {
+            return None;
+        }
+        //a damaged block length must not overflow
+        let table_bits = block_length.checked_mul(3)?.checked_mul(fingerprint_bits)?;
+        if (src.len() - HEADER_SIZE) as u64 != table_bits.div_ceil(64) * 8 {
+            return None;
+        }
+        Some(XorFilter {
+            seed,
+            block_length,
+            fingerprint_bits: fingerprint_bits as u32,
+            num_keys,
+            table: src[HEADER_SIZE..]
+                .chunks(8)
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                .collect(),
+            keys: Vec::new(),
+            built: true,
+        })
+    }
+}
+
+#[test]
+fn test_xor_filter() {
+    let key = |i: u32| format!("key{:08}", i).into_bytes();
+    for fingerprint_bits in [1u32, 8, 13, 16].iter() {
+        let mut filter = XorFilter::with_bits_per_entry(*fingerprint_bits as f32 * 1.23);
+        assert_eq!(*fingerprint_bits, filter.fingerprint_bits());
+        for i in 0..10000 {
+            filter.set(&key(i));
+        }
+        assert!(filter.check(&key(20000)));
+        filter.build();
+        assert!((0..10000).all(|i| filter.check(&key(i))));
+        let false_positives = (10000..110000).filter(|i| filter.check(&key(*i))).count();
+        let rate = false_positives as f64 / 100000.0;
+        let expected = filter.expected_false_positive_rate();
+        assert!(
+            rate < expected * 1.5 + 0.0005,
+            "{} bits: {} false positive rate",
+            fingerprint_bits,
+            rate
+        );
+        assert!(filter.num_bits() <= (10000.0 * 1.24 + 32.0) as u64 * *fingerprint_bits as u64);
+
+        let decoded = XorFilter::decode(&filter.encode()).unwrap();
+        assert_eq!(
+            false_positives,
+            (10000..110000).filter(|i| decoded.check(&key(*i))).count()
+        );
+        assert!((0..10000).all(|i| decoded.check(&key(i))));
+    }
+    assert!(XorFilter::decode(&[0; 40]).is_none());
+    let mut overflowing = vec![0xff; 24];
+    overflowing[16..].copy_from_slice(&16u64.to_le_bytes());
+    overflowing.extend_from_slice(&[0; 16]);
+    assert!(XorFilter::decode(&overflowing).is_none());
+    let mut empty = vec![0; 32];
+    empty[16..24].copy_from_slice(&8u64.to_le_bytes());
+    assert!(XorFilter::decode(&empty).is_none());
+
+    //no keys, and the same key twice
+    let mut empty = XorFilter::with_bits_per_entry(10.0);
+    empty.build();
+    assert!(!(0..1000).all(|i| empty.check(&key(i))));
+    let mut twice = XorFilter::with_bits_per_entry(10.0);
+    twice.set(&key(1));
+    twice.set(&key(1));
+    twice.build();
+    assert!(twice.check(&key(1)));
+}
+
+#[test]
+fn test_xor_filter_memory() {
+    //at the same bits per entry the xor filter beats the bloom filter
+    let key = |i: u32| format!("key{:08}", i).into_bytes();
+    let mut bloom = BloomFilter::with_bits_per_entry(50000, 10.0);
+    let mut xor = XorFilter::with_bits_per_entry(10.0);
+    for i in 0..50000 {
+        bloom.set(&key(i));
+        xor.set(&key(i));
+    }
+    xor.build();
+    let bloom_hits = (50000..250000).filter(|i| bloom.check(&key(*i))).count();
+    let xor_hits = (50000..250000).filter(|i| xor.check(&key(*i))).count();
+    assert!(xor.num_bits() <= bloom.num_bits());
+    assert!(xor_hits < bloom_hits);
+}